@@ -1,4 +1,7 @@
 use crate::models::chat::chat_format::ChatFormat;
+use crate::models::chat::context::Truncation;
+use crate::models::chat::{Precision, QuantizationType};
+use crate::models::embedding::{EmbeddingArchitecture, Pooling};
 use serde::Deserialize;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -7,6 +10,7 @@ pub struct Config {
     pub port: Option<u16>,
     pub(crate) chat_configs: Option<Vec<ChatModelConfig>>,
     pub(crate) whisper_configs: Option<Vec<WhisperModelConfig>>,
+    pub(crate) embedding_configs: Option<Vec<EmbeddingModelConfig>>,
 }
 
 impl Config {
@@ -29,6 +33,34 @@ pub(crate) struct ChatModelConfig {
     pub(crate) gqa: usize,
     #[serde(default = "default_chat_format")]
     pub(crate) chat_format: ChatFormat,
+    /// Jinja template of the jinja chat format, or the path of a file with one. Defaults to
+    /// tokenizer.chat_template of the GGUF file.
+    pub(crate) chat_template: Option<String>,
+    /// Maximum number of sequences the decode loop advances together in one batched forward
+    /// pass, further requests wait for a free slot.
+    #[serde(default = "default_max_batch_size")]
+    pub(crate) max_batch_size: usize,
+    /// Memory cap of the prompt prefix cache in megabytes, 0 disables the cache.
+    #[serde(default = "default_prefix_cache_size")]
+    pub(crate) prefix_cache_size: usize,
+    /// Default penalty for repeating recent tokens, 1.0 disables it.
+    #[serde(default = "default_repeat_penalty")]
+    pub(crate) repeat_penalty: f32,
+    /// Default number of recent tokens the repeat penalty applies to.
+    #[serde(default = "default_repeat_last_n")]
+    pub(crate) repeat_last_n: usize,
+    /// Maximum number of tokens of the prompt and the completion together. Defaults to the
+    /// context length in the GGUF metadata, capped at the longest sequence the model supports.
+    pub(crate) context_length: Option<usize>,
+    /// What to do with prompts that do not fit into the context, reject or drop_oldest.
+    #[serde(default)]
+    pub(crate) truncation: Truncation,
+    /// Type of the weights of safetensors models, f32, bf16 or f16. Defaults to f32 on the CPU
+    /// and to the torch_dtype of config.json otherwise.
+    pub(crate) dtype: Option<Precision>,
+    /// Quantize the linear weights of safetensors models on load, to q4_0, q4_1, q5_0, q5_1,
+    /// q8_0, q2_k, q3_k, q4_k, q5_k or q6_k. The result is cached next to the weights.
+    pub(crate) quantize: Option<QuantizationType>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -41,6 +73,28 @@ pub(crate) struct WhisperModelConfig {
     pub(crate) seed: u64,
     #[serde(default = "default_quantized")]
     pub(crate) quantized: bool,
+    /// Number of threads transcribing requests of this model in parallel.
+    #[serde(default = "default_workers")]
+    pub(crate) workers: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct EmbeddingModelConfig {
+    pub(crate) model_id: String,
+    pub(crate) alias: String,
+    #[serde(default = "default_cpu")]
+    pub(crate) cpu: bool,
+    /// Architecture of the model, bert, jina_bert or nomic_bert. Detected from config.json if unset.
+    pub(crate) architecture: Option<EmbeddingArchitecture>,
+    /// How token states are pooled into one embedding, mean or cls.
+    #[serde(default)]
+    pub(crate) pooling: Pooling,
+    /// Whether embeddings are scaled to unit length.
+    #[serde(default = "default_normalize")]
+    pub(crate) normalize: bool,
+    /// Number of threads embedding requests of this model in parallel.
+    #[serde(default = "default_workers")]
+    pub(crate) workers: usize,
 }
 
 fn default_chat_format() -> ChatFormat {
@@ -58,6 +112,30 @@ fn default_quantized() -> bool {
     true
 }
 
+fn default_max_batch_size() -> usize {
+    8
+}
+
+fn default_prefix_cache_size() -> usize {
+    1024
+}
+
+fn default_repeat_penalty() -> f32 {
+    1.1
+}
+
+fn default_repeat_last_n() -> usize {
+    64
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -16,11 +16,14 @@ pub(crate) async fn create_transcription(mut req: Request) -> Result<Response> {
         .ok_or_else(|| {
             SilentError::business_error(StatusCode::BAD_REQUEST, "model not set".to_string())
         })?;
-    let result = whisper_model.handle(transcription_req, None).map_err(|e| {
-        SilentError::business_error(
-            StatusCode::BAD_REQUEST,
-            format!("failed to handle whisper model: {}", e),
-        )
-    })?;
+    let result = whisper_model
+        .handle(transcription_req, None)
+        .await
+        .map_err(|e| {
+            SilentError::business_error(
+                StatusCode::BAD_REQUEST,
+                format!("failed to handle whisper model: {}", e),
+            )
+        })?;
     Ok(result.into())
 }
@@ -1,3 +1,4 @@
+use crate::handlers::error_response;
 use crate::types::chat::completion::ChatResponseFormat;
 use crate::types::chat::ChatCompletionRequest;
 use crate::Models;
@@ -15,27 +16,21 @@ pub(crate) async fn chat_completions(mut req: Request) -> silent::Result<Respons
         })?;
 
     if chat_completion_req.stream.clone().unwrap_or(false) {
-        let stream = chat_model.stream_handle(chat_completion_req).map_err(|e| {
-            SilentError::business_error(
-                StatusCode::BAD_REQUEST,
-                format!("failed to handle chat model: {}", e),
-            )
-        })?;
+        let stream = match chat_model.stream_handle(chat_completion_req) {
+            Ok(stream) => stream,
+            Err(e) => return error_response(e, "messages"),
+        };
         let result = sse_reply(stream);
         Ok(result)
     } else {
-        let result = chat_model
-            .handle(chat_completion_req.clone())
-            .map_err(|e| {
-                SilentError::business_error(
-                    StatusCode::BAD_REQUEST,
-                    format!("failed to handle chat model: {}", e),
-                )
-            })?;
+        let result = match chat_model.handle(chat_completion_req.clone()).await {
+            Ok(result) => result,
+            Err(e) => return error_response(e, "messages"),
+        };
         match chat_completion_req.response_format {
             None => Ok(result.into()),
             Some(format) => {
-                if format.r#type == ChatResponseFormat::Json {
+                if format.r#type != ChatResponseFormat::Text {
                     Ok(result.into())
                 } else {
                     let result = match result.choices.first() {
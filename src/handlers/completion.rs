@@ -0,0 +1,29 @@
+use crate::handlers::error_response;
+use crate::types::completion::CompletionRequest;
+use crate::Models;
+use silent::prelude::sse_reply;
+use silent::{Request, Response, SilentError, StatusCode};
+
+pub(crate) async fn completions(mut req: Request) -> silent::Result<Response> {
+    let completion_req: CompletionRequest = req.json_parse().await?;
+    let model = req.get_config::<Models>()?;
+
+    let chat_model = model
+        .get_chat(completion_req.model.clone())
+        .ok_or_else(|| {
+            SilentError::business_error(StatusCode::BAD_REQUEST, "model not set".to_string())
+        })?;
+
+    if completion_req.stream.unwrap_or(false) {
+        let stream = match chat_model.stream_complete(completion_req) {
+            Ok(stream) => stream,
+            Err(e) => return error_response(e, "prompt"),
+        };
+        Ok(sse_reply(stream))
+    } else {
+        match chat_model.complete(completion_req).await {
+            Ok(result) => Ok(result.into()),
+            Err(e) => error_response(e, "prompt"),
+        }
+    }
+}
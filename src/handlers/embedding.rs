@@ -0,0 +1,20 @@
+use crate::types::embedding::EmbeddingRequest;
+use crate::Models;
+use silent::{Request, Response, Result, SilentError, StatusCode};
+
+pub(crate) async fn embeddings(mut req: Request) -> Result<Response> {
+    let embedding_req: EmbeddingRequest = req.json_parse().await?;
+    let model = req.get_config::<Models>()?;
+    let embedding_model = model
+        .get_embedding(embedding_req.model.clone())
+        .ok_or_else(|| {
+            SilentError::business_error(StatusCode::BAD_REQUEST, "model not set".to_string())
+        })?;
+    let result = embedding_model.handle(embedding_req).await.map_err(|e| {
+        SilentError::business_error(
+            StatusCode::BAD_REQUEST,
+            format!("failed to handle embedding model: {}", e),
+        )
+    })?;
+    Ok(result.into())
+}
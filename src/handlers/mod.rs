@@ -1,13 +1,52 @@
 use crate::handlers::audio::create_transcription;
 use crate::handlers::chat::chat_completions;
+use crate::handlers::completion::completions;
+use crate::handlers::embedding::embeddings;
+use crate::handlers::model::{list_models, retrieve_model};
+use crate::models::chat::context::ContextLengthExceeded;
 use silent::prelude::{HandlerAppend, Route};
+use silent::{Response, SilentError, StatusCode};
 
 mod audio;
 mod chat;
+mod completion;
+mod embedding;
 mod model;
 
 pub fn get_routes() -> Route {
     Route::new("")
         .append(Route::new("/v1/audio/transcriptions").post(create_transcription))
         .append(Route::new("/v1/chat/completions").post(chat_completions))
+        .append(Route::new("/v1/completions").post(completions))
+        .append(Route::new("/v1/embeddings").post(embeddings))
+        .append(
+            Route::new("/v1/models")
+                .get(list_models)
+                // ids of Hugging Face models contain slashes, such as org/name
+                .append(Route::new("<id:**>").get(retrieve_model)),
+        )
+}
+
+/// A 400 response for a failed request, with an OpenAI style error body for the errors that
+/// clients handle programmatically. `param` is the request field holding the prompt.
+fn error_response(e: anyhow::Error, param: &str) -> silent::Result<Response> {
+    match e.downcast_ref::<ContextLengthExceeded>() {
+        Some(exceeded) => {
+            let mut res: Response = serde_json::json!({
+                "error": {
+                    "message": exceeded.to_string(),
+                    "type": "invalid_request_error",
+                    "param": param,
+                    "code": "context_length_exceeded",
+                }
+            })
+            .into();
+            res.set_status(StatusCode::BAD_REQUEST);
+            Ok(res)
+        }
+        None => Err(SilentError::business_error(
+            StatusCode::BAD_REQUEST,
+            format!("failed to handle chat model: {}", e),
+        )),
+    }
 }
@@ -1 +1,17 @@
+use crate::types::model::ModelList;
+use crate::Models;
+use silent::{Request, Response, Result, SilentError, StatusCode};
 
+pub(crate) async fn list_models(req: Request) -> Result<Response> {
+    let models = req.get_config::<Models>()?;
+    Ok(ModelList::new(models.list()).into())
+}
+
+pub(crate) async fn retrieve_model(req: Request) -> Result<Response> {
+    let id: String = req.get_path_params("id")?;
+    let models = req.get_config::<Models>()?;
+    let model = models.get(&id).ok_or_else(|| {
+        SilentError::business_error(StatusCode::NOT_FOUND, format!("model {id} not found"))
+    })?;
+    Ok(model.into())
+}
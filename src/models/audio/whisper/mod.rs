@@ -6,7 +6,7 @@ mod pcm_decode;
 use crate::configs::WhisperModelConfig;
 use crate::models::audio::whisper::decoder::{Decoder, Task};
 use crate::models::audio::whisper::pcm_decode::pcm_decode;
-use crate::models::device::{device, token_id};
+use crate::models::device::{device, device_name, token_id};
 use crate::types::audio::transcription::{CreateTranscriptionRequest, CreateTranscriptionResponse};
 use anyhow::{Error as E, Result};
 use candle_core as candle;
@@ -16,10 +16,62 @@ use candle_transformers::models::whisper::{self as m, audio, Config};
 use model::Model;
 use silent::prelude::info;
 use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use tokenizers::Tokenizer;
+use tokio::sync::oneshot;
 
+struct Job {
+    request: CreateTranscriptionRequest,
+    task: Option<Task>,
+    reply: oneshot::Sender<Result<CreateTranscriptionResponse>>,
+}
+
+/// Handle to the transcription workers of a whisper model.
+///
+/// Transcription runs on a pool of dedicated OS threads, handlers only await the reply.
 #[derive(Clone, Debug)]
 pub(crate) struct Whisper {
+    pub(crate) device: &'static str,
+    sender: Sender<Job>,
+}
+
+impl Whisper {
+    pub(crate) async fn handle(
+        &self,
+        request: CreateTranscriptionRequest,
+        task: Option<Task>,
+    ) -> Result<CreateTranscriptionResponse> {
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .send(Job {
+                request,
+                task,
+                reply,
+            })
+            .map_err(|_| anyhow::anyhow!("whisper workers have stopped"))?;
+        receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("whisper worker dropped the request"))?
+    }
+
+    fn spawn(name: &str, transcriber: Transcriber, workers: usize) -> Result<Self> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let device = device_name(&transcriber.device);
+        for index in 0..workers.max(1) {
+            let transcriber = transcriber.clone();
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("whisper-{name}-{index}"))
+                .spawn(move || transcriber.run(receiver))?;
+        }
+        Ok(Self { device, sender })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Transcriber {
     tokenizer: Tokenizer,
     model: Model,
     config: Config,
@@ -28,8 +80,26 @@ pub(crate) struct Whisper {
     seed: u64,
 }
 
-impl Whisper {
-    pub(crate) fn handle(
+impl Transcriber {
+    fn run(self, receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            let Ok(Job {
+                request,
+                task,
+                reply,
+            }) = job
+            else {
+                return;
+            };
+            let _ = reply.send(self.handle(request, task));
+        }
+    }
+
+    fn handle(
         &self,
         request: CreateTranscriptionRequest,
         task: Option<Task>,
@@ -111,12 +181,13 @@ pub(crate) fn init_model(args: WhisperModelConfig) -> Result<Whisper> {
             unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], m::DTYPE, &device)? };
         Model::Normal(m::model::Whisper::load(&vb, config.clone())?)
     };
-    Ok(Whisper {
+    let transcriber = Transcriber {
         tokenizer,
         model,
         config,
         mel_filters,
         device,
         seed: args.seed,
-    })
+    };
+    Whisper::spawn(&args.alias, transcriber, args.workers)
 }
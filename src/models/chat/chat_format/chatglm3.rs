@@ -1,4 +1,4 @@
-use super::{ChatMessage, ChatMessages};
+use super::{assistant_content, tool_responses, ChatMessage, ChatMessages};
 use anyhow::Result;
 
 pub(crate) fn format_messages(messages: ChatMessages) -> Result<String> {
@@ -16,9 +16,8 @@ fn transform(message: ChatMessage) -> String {
     match message {
         ChatMessage::User(message) => format!("<s>[INST]\n{}\n", message.content),
         ChatMessage::Assistant(message) => {
-            format!("[/INST]\n{}\n", message.content.unwrap_or("".to_string()))
+            format!("[/INST]\n{}\n", assistant_content(message))
         }
-        // todo: implement tool
-        ChatMessage::Tool(_) => !unimplemented!("tool"),
+        ChatMessage::Tool(messages) => format!("<s>[INST]\n{}\n", tool_responses(messages)),
     }
 }
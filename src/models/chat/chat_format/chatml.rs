@@ -1,4 +1,4 @@
-use super::{ChatMessage, ChatMessages};
+use super::{assistant_content, tool_responses, ChatMessage, ChatMessages};
 use anyhow::Result;
 
 pub(crate) fn format_messages(messages: ChatMessages) -> Result<String> {
@@ -26,30 +26,28 @@ fn transform(message: ChatMessage) -> String {
         ChatMessage::Assistant(message) => {
             format!(
                 "\n{}\n",
-                match message.content {
-                    None => {
-                        "".to_string()
-                    }
-                    Some(content) => {
-                        match content.as_str() {
-                            "" => "".to_string(),
-                            _ => {
-                                format!("{}\n<|im_end|>", content)
-                            }
-                        }
+                match assistant_content(message).as_str() {
+                    "" => "".to_string(),
+                    content => {
+                        format!("{}\n<|im_end|>", content)
                     }
                 }
             )
         }
-        // todo: implement tool
-        ChatMessage::Tool(_) => !unimplemented!("tool"),
+        ChatMessage::Tool(messages) => {
+            format!(
+                "<|im_start|>tool\n{}\n<|im_end|>\n<|im_start|>assistant",
+                tool_responses(messages)
+            )
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::format_messages;
-    use crate::types::chat::completion::ChatCompletionMessage;
+    use crate::models::chat::chat_format::ChatFormat;
+    use crate::types::chat::completion::{ChatCompletionMessage, Tool};
     #[test]
     fn prompt_test() {
         let json_str = r#"
@@ -90,4 +88,70 @@ who are you
 "#
         )
     }
+
+    #[test]
+    fn tool_prompt_test() {
+        let json_str = r#"
+        [{
+                "role": "system",
+                "content": "you are helpfull assistant!"
+            },{
+                "role": "user",
+                "content": "weather?"
+            },{
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [
+                    {"id": "a", "type": "function", "function": {"name": "weather", "arguments": "{\"city\": \"Paris\"}"}},
+                    {"id": "b", "type": "function", "function": {"name": "weather", "arguments": "{\"city\": \"Rome\"}"}}
+                ]
+            },{
+                "role": "tool",
+                "content": "sunny",
+                "tool_call_id": "a"
+            },{
+                "role": "tool",
+                "content": "rainy",
+                "tool_call_id": "b"
+            }
+        ]"#;
+        let tools = r#"[{"type": "function", "function": {"name": "weather", "parameters": {"type": "object"}}}]"#;
+        let messages: Vec<ChatCompletionMessage> = serde_json::from_str(json_str).unwrap();
+        let tools = serde_json::from_str::<Vec<Tool>>(tools).unwrap();
+        let prompt = ChatFormat::ChatML
+            .format_messages(messages.clone(), &tools)
+            .unwrap();
+        assert!(prompt.contains(
+            "<tools>\n{\"type\":\"function\",\"function\":{\"name\":\"weather\",\"parameters\":{\"type\":\"object\"}}}\n</tools>"
+        ));
+        assert!(prompt.ends_with(
+            r#"<|im_start|>user
+weather?
+<|im_end|>
+<|im_start|>assistant
+<tool_call>
+{"name":"weather","arguments":{"city":"Paris"}}
+</tool_call>
+<tool_call>
+{"name":"weather","arguments":{"city":"Rome"}}
+</tool_call>
+<|im_end|>
+<|im_start|>tool
+<tool_response>
+sunny
+</tool_response>
+<tool_response>
+rainy
+</tool_response>
+<|im_end|>
+<|im_start|>assistant
+"#
+        ));
+        let mut unanswered = messages;
+        unanswered.pop();
+        unanswered.push(unanswered.last().unwrap().clone());
+        assert!(ChatFormat::ChatML
+            .format_messages(unanswered, &tools)
+            .is_err());
+    }
 }
@@ -0,0 +1,316 @@
+use super::tools_prompt;
+use crate::models::chat::tool_calls::{ToolSyntax, CALL_START};
+use crate::types::chat::completion::{ChatCompletionMessage, Tool};
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file;
+use minijinja::{Environment, Error, ErrorKind};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// Name of the chat template in its environment.
+const TEMPLATE: &str = "chat_template";
+
+/// The chat template and special tokens that a model file or directory ships.
+#[derive(Debug, Default)]
+pub(crate) struct TemplateMetadata {
+    template: Option<String>,
+    bos_token_id: Option<u32>,
+    eos_token_id: Option<u32>,
+}
+
+impl TemplateMetadata {
+    pub(crate) fn from_gguf(content: &gguf_file::Content) -> Self {
+        let value = |key: &str| content.metadata.get(key);
+        Self {
+            template: value("tokenizer.chat_template")
+                .and_then(|value| value.to_string().ok())
+                .cloned(),
+            bos_token_id: value("tokenizer.ggml.bos_token_id")
+                .and_then(|value| value.to_u32().ok()),
+            eos_token_id: value("tokenizer.ggml.eos_token_id")
+                .and_then(|value| value.to_u32().ok()),
+        }
+    }
+
+    /// The `chat_template` of the tokenizer_config.json of a Hugging Face model directory, the
+    /// default one if there are several.
+    pub(crate) fn from_checkpoint(
+        dir: &Path,
+        bos_token_id: Option<u32>,
+        eos_token_id: Option<u32>,
+    ) -> Self {
+        let config: Option<Value> = std::fs::read_to_string(dir.join("tokenizer_config.json"))
+            .ok()
+            .and_then(|config| serde_json::from_str(&config).ok());
+        let template = config.and_then(|config| match &config["chat_template"] {
+            Value::String(template) => Some(template.clone()),
+            Value::Array(templates) => templates
+                .iter()
+                .find(|template| template["name"] == "default")
+                .and_then(|template| template["template"].as_str())
+                .map(str::to_string),
+            _ => None,
+        });
+        Self {
+            template,
+            bos_token_id,
+            eos_token_id,
+        }
+    }
+}
+
+/// A Jinja chat template as Hugging Face tokenizers define them, rendered with the messages,
+/// the tools and `add_generation_prompt`.
+#[derive(Debug, Clone)]
+pub(crate) struct JinjaTemplate {
+    source: String,
+    /// The environment the template is compiled into once, on load.
+    environment: Arc<Environment<'static>>,
+    bos_token: String,
+    eos_token: String,
+    tool_syntax: Option<ToolSyntax>,
+}
+
+impl JinjaTemplate {
+    /// Load the configured template, a file path or the template itself, or else the one in the
+    /// metadata of the model file.
+    pub(crate) fn load(
+        configured: Option<&str>,
+        metadata: TemplateMetadata,
+        tokenizer: &Tokenizer,
+    ) -> Result<Self> {
+        let source = match configured {
+            Some(path) if Path::new(path).is_file() => std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read chat template {path}"))?,
+            Some(template) => template.to_string(),
+            None => metadata.template.context(
+                "the jinja chat format needs chat_template in the config, \
+                tokenizer.chat_template in the GGUF metadata or tokenizer_config.json",
+            )?,
+        };
+        let token = |id: Option<u32>, default: &str| {
+            id.and_then(|id| tokenizer.id_to_token(id))
+                .unwrap_or_else(|| default.to_string())
+        };
+        // syntax errors are reported on startup rather than on the first request
+        Self::new(
+            source,
+            token(metadata.bos_token_id, "<s>"),
+            token(metadata.eos_token_id, "</s>"),
+        )
+        .context("invalid chat template")
+    }
+
+    fn new(source: String, bos_token: String, eos_token: String) -> Result<Self> {
+        let mut environment = environment();
+        environment.add_template_owned(TEMPLATE, source.clone())?;
+        Ok(Self {
+            tool_syntax: tool_syntax(&source),
+            source,
+            environment: Arc::new(environment),
+            bos_token,
+            eos_token,
+        })
+    }
+
+    pub(crate) fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// How the model calls tools: in the syntax that the template describes the tools with, or in
+    /// the Hermes syntax if the template knows nothing of tools. `None` if the template describes
+    /// tools in a syntax that cannot be parsed.
+    pub(crate) fn tool_syntax(&self) -> Option<ToolSyntax> {
+        self.tool_syntax
+    }
+
+    /// The token that ends a turn of the assistant.
+    pub(crate) fn eos_token(&self) -> &str {
+        &self.eos_token
+    }
+
+    pub(crate) fn render(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        tools: &[Tool],
+    ) -> Result<String> {
+        if !tools.is_empty() && self.tool_syntax.is_none() {
+            anyhow::bail!("the chat template calls tools in a syntax that is not supported");
+        }
+        let mut messages: Vec<Value> = messages.into_iter().map(message_value).collect();
+        // templates that know nothing of tools get them described in the system message
+        if !tools.is_empty() && !self.source.contains("tools") {
+            let prompt = tools_prompt(tools)?;
+            match messages.first_mut() {
+                Some(message) if message["role"] == "system" => {
+                    let content = message["content"].as_str().unwrap_or_default();
+                    message["content"] = Value::String(format!("{content}{prompt}"));
+                }
+                _ => messages.insert(0, json!({"role": "system", "content": prompt.trim_start()})),
+            }
+        }
+        let tools = (!tools.is_empty()).then_some(tools);
+        let template = self.environment.get_template(TEMPLATE)?;
+        Ok(template.render(json!({
+            "messages": messages,
+            "tools": tools,
+            "add_generation_prompt": true,
+            "bos_token": self.bos_token,
+            "eos_token": self.eos_token,
+        }))?)
+    }
+}
+
+/// The syntax of the tool calls that a template writes into the history of a chat, which is the
+/// one its model is trained to call tools with.
+fn tool_syntax(source: &str) -> Option<ToolSyntax> {
+    if !source.contains("tools") {
+        Some(ToolSyntax::Hermes)
+    } else if source.contains("[TOOL_CALLS]") {
+        Some(ToolSyntax::Mistral)
+    } else if source.contains(CALL_START) {
+        Some(ToolSyntax::Hermes)
+    } else if source.contains("<|python_tag|>") || source.contains("<|start_header_id|>") {
+        Some(ToolSyntax::Llama3)
+    } else {
+        None
+    }
+}
+
+/// An environment that behaves like the one transformers renders chat templates with.
+fn environment() -> Environment<'static> {
+    let mut environment = Environment::new();
+    environment.set_trim_blocks(true);
+    environment.set_lstrip_blocks(true);
+    environment.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    environment.add_function(
+        "raise_exception",
+        |message: String| -> Result<String, Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, message))
+        },
+    );
+    environment.add_function("strftime_now", |format: String| {
+        chrono::Local::now().format(&format).to_string()
+    });
+    environment
+}
+
+/// A message as templates expect it, with tool call arguments as objects.
+fn message_value(message: ChatCompletionMessage) -> Value {
+    match message {
+        ChatCompletionMessage::System(message) => {
+            json!({"role": "system", "content": message.content})
+        }
+        ChatCompletionMessage::User(message) => {
+            json!({"role": "user", "content": message.content})
+        }
+        ChatCompletionMessage::Assistant(message) => {
+            let tool_calls: Vec<Value> = message
+                .tool_calls
+                .into_iter()
+                .map(|call| {
+                    let arguments = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or(Value::String(call.function.arguments));
+                    json!({
+                        "id": call.id,
+                        "type": call.r#type,
+                        "function": {"name": call.function.name, "arguments": arguments},
+                    })
+                })
+                .collect();
+            let mut value = json!({"role": "assistant", "content": message.content});
+            if !tool_calls.is_empty() {
+                value["tool_calls"] = Value::Array(tool_calls);
+            }
+            value
+        }
+        ChatCompletionMessage::Tool(message) => json!({
+            "role": "tool",
+            "content": message.content,
+            "tool_call_id": message.tool_call_id,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JinjaTemplate;
+    use crate::models::chat::tool_calls::ToolSyntax;
+    use crate::types::chat::completion::{ChatCompletionMessage, Tool};
+
+    const CHATML: &str = "{% for message in messages %}\
+        {{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n' }}\
+        {% endfor %}\
+        {% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+    fn template(source: &str) -> JinjaTemplate {
+        JinjaTemplate::new(
+            source.to_string(),
+            "<s>".to_string(),
+            "<|im_end|>".to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn render_template() {
+        let messages: Vec<ChatCompletionMessage> = serde_json::from_str(
+            r#"[{"role": "system", "content": "be brief"}, {"role": "user", "content": "Hello"}]"#,
+        )
+        .unwrap();
+        let prompt = template(CHATML).render(messages.clone(), &[]).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nbe brief<|im_end|>\n<|im_start|>user\nHello<|im_end|>\n<|im_start|>assistant\n"
+        );
+
+        let tools: Vec<Tool> = serde_json::from_str(
+            r#"[{"type": "function", "function": {"name": "weather", "parameters": {"type": "object"}}}]"#,
+        )
+        .unwrap();
+        let prompt = template(CHATML).render(messages.clone(), &tools).unwrap();
+        assert!(prompt.starts_with("<|im_start|>system\nbe brief\n\nYou may call"));
+        let prompt = template(
+            "{% for tool in tools %}{{ tool.function.name | tojson }}{% endfor %}{# <tool_call> #}",
+        )
+        .render(messages, &tools)
+        .unwrap();
+        assert_eq!(prompt, "\"weather\"");
+    }
+
+    #[test]
+    fn tool_syntax_of_template() {
+        let native =
+            |calls: &str| format!("{{% if tools %}}{{{{ tools | tojson }}}}{{% endif %}}{calls}");
+        assert_eq!(template(CHATML).tool_syntax(), Some(ToolSyntax::Hermes));
+        for (source, syntax) in [
+            (native("<tool_call>"), Some(ToolSyntax::Hermes)),
+            (native("[TOOL_CALLS]"), Some(ToolSyntax::Mistral)),
+            (native("<|start_header_id|>"), Some(ToolSyntax::Llama3)),
+            (native("<|tool_calls|>"), None),
+        ] {
+            assert_eq!(template(&source).tool_syntax(), syntax, "{source}");
+        }
+
+        let messages: Vec<ChatCompletionMessage> =
+            serde_json::from_str(r#"[{"role": "user", "content": "Hello"}]"#).unwrap();
+        let tools: Vec<Tool> =
+            serde_json::from_str(r#"[{"type": "function", "function": {"name": "weather"}}]"#)
+                .unwrap();
+        let unknown = template(&native("<|tool_calls|>"));
+        assert!(unknown.render(messages.clone(), &[]).is_ok());
+        assert!(unknown.render(messages, &tools).is_err());
+    }
+
+    #[test]
+    fn raise_exception() {
+        let messages: Vec<ChatCompletionMessage> =
+            serde_json::from_str(r#"[{"role": "user", "content": "Hello"}]"#).unwrap();
+        let error = template("{{ raise_exception('roles must alternate') }}")
+            .render(messages, &[])
+            .unwrap_err();
+        assert!(error.to_string().contains("roles must alternate"));
+    }
+}
@@ -1,4 +1,4 @@
-use super::{ChatMessage, ChatMessages};
+use super::{assistant_content, tool_responses, ChatMessage, ChatMessages};
 use anyhow::Result;
 
 pub(crate) fn format_messages(messages: ChatMessages) -> Result<String> {
@@ -20,23 +20,17 @@ fn transform(message: ChatMessage) -> String {
         ChatMessage::Assistant(message) => {
             format!(
                 "\n{}",
-                match message.content {
-                    None => {
-                        "".to_string()
-                    }
-                    Some(content) => {
-                        match content.as_str() {
-                            "" => "".to_string(),
-                            _ => {
-                                format!("{}\n</s>\n", content)
-                            }
-                        }
+                match assistant_content(message).as_str() {
+                    "" => "".to_string(),
+                    content => {
+                        format!("{}\n</s>\n", content)
                     }
                 }
             )
         }
-        // todo: implement tool
-        ChatMessage::Tool(_) => !unimplemented!("tool"),
+        ChatMessage::Tool(messages) => {
+            format!("<s>[INST]\n{}\n[/INST]", tool_responses(messages))
+        }
     }
 }
 
@@ -1,16 +1,21 @@
 mod alpaca;
 mod chatglm3;
 mod chatml;
+mod jinja;
 mod llama2;
 mod openchat;
 
+pub(crate) use jinja::{JinjaTemplate, TemplateMetadata};
+
+use crate::models::chat::tool_calls::{ToolSyntax, CALL_END, CALL_START};
 use crate::types::chat::completion::{
-    AssistantMessage, ChatCompletionMessage, SystemMessage, ToolMessage, UserMessage,
+    AssistantMessage, ChatCompletionMessage, SystemMessage, Tool, ToolMessage, UserMessage,
 };
 use anyhow::Result;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) enum ChatFormat {
     #[serde(rename = "llama-2")]
     Llama2,
@@ -22,6 +27,9 @@ pub(crate) enum ChatFormat {
     ChatGLM3,
     #[serde(rename = "openchat")]
     OpenChat,
+    /// The Jinja template of the config or of the model file, rendered by a [`JinjaTemplate`].
+    #[serde(rename = "jinja")]
+    Jinja,
 }
 
 pub enum ChatMessage {
@@ -29,8 +37,8 @@ pub enum ChatMessage {
     User(UserMessage),
     /// A message from the assistant.
     Assistant(AssistantMessage),
-    /// A message from a tool.
-    Tool(ToolMessage),
+    /// The results of the tool calls of the previous assistant message.
+    Tool(Vec<ToolMessage>),
 }
 struct ChatMessages {
     system: SystemMessage,
@@ -43,6 +51,8 @@ impl TryFrom<Vec<ChatCompletionMessage>> for ChatMessages {
     fn try_from(value: Vec<ChatCompletionMessage>) -> Result<Self, Self::Error> {
         let mut system = None;
         let mut chat = vec![];
+        // ids of the tool calls that have not been answered yet
+        let mut tool_calls = HashSet::new();
         for message in value {
             match message {
                 ChatCompletionMessage::System(message) => {
@@ -52,10 +62,24 @@ impl TryFrom<Vec<ChatCompletionMessage>> for ChatMessages {
                     chat.push(ChatMessage::User(message));
                 }
                 ChatCompletionMessage::Assistant(message) => {
+                    tool_calls = message
+                        .tool_calls
+                        .iter()
+                        .map(|call| call.id.clone())
+                        .collect();
                     chat.push(ChatMessage::Assistant(message));
                 }
                 ChatCompletionMessage::Tool(message) => {
-                    chat.push(ChatMessage::Tool(message));
+                    if !tool_calls.remove(&message.tool_call_id) {
+                        anyhow::bail!(
+                            "tool message does not respond to a tool call: {}",
+                            message.tool_call_id
+                        );
+                    }
+                    match chat.last_mut() {
+                        Some(ChatMessage::Tool(messages)) => messages.push(message),
+                        _ => chat.push(ChatMessage::Tool(vec![message])),
+                    }
                 }
             }
         }
@@ -67,14 +91,33 @@ impl TryFrom<Vec<ChatCompletionMessage>> for ChatMessages {
 }
 
 impl ChatFormat {
-    pub(crate) fn format_messages(&self, messages: Vec<ChatCompletionMessage>) -> Result<String> {
-        let messages = messages.try_into()?;
+    pub(crate) fn format_messages(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        tools: &[Tool],
+    ) -> Result<String> {
+        let mut messages: ChatMessages = messages.try_into()?;
+        if !tools.is_empty() {
+            if self.tool_syntax().is_none() {
+                anyhow::bail!("the {self:?} chat format cannot call tools");
+            }
+            messages.system.content.push_str(&tools_prompt(tools)?);
+        }
         match self {
             Self::Llama2 => llama2::format_messages(messages),
             Self::Alpaca => alpaca::format_messages(messages),
             Self::ChatML => chatml::format_messages(messages),
             Self::ChatGLM3 => chatglm3::format_messages(messages),
             Self::OpenChat => openchat::format_messages(messages),
+            Self::Jinja => anyhow::bail!("the jinja chat format is rendered by its template"),
+        }
+    }
+    /// How models of the format call tools, if they can. Jinja templates have their own, see
+    /// [`JinjaTemplate::tool_syntax`].
+    pub(crate) fn tool_syntax(&self) -> Option<ToolSyntax> {
+        match self {
+            Self::ChatML => Some(ToolSyntax::Hermes),
+            Self::Llama2 | Self::Alpaca | Self::ChatGLM3 | Self::OpenChat | Self::Jinja => None,
         }
     }
     pub(crate) fn get_eos_token(&self) -> String {
@@ -85,6 +128,50 @@ impl ChatFormat {
             // Self::ChatML => "<".to_string(),
             Self::ChatGLM3 => "</s>".to_string(),
             Self::OpenChat => "<|end_of_turn|>".to_string(),
+            // the eos token of the model file is used instead, see JinjaTemplate
+            Self::Jinja => "</s>".to_string(),
         }
     }
 }
+
+/// Describes the tools to the model and how to call them, following the Hermes function calling
+/// format that many fine-tuned models are trained on.
+fn tools_prompt(tools: &[Tool]) -> Result<String> {
+    let mut prompt = "\n\nYou may call one or more functions to assist with the user query. \
+        You are provided with function signatures within <tools></tools> XML tags:\n<tools>\n"
+        .to_string();
+    for tool in tools {
+        prompt.push_str(&serde_json::to_string(tool)?);
+        prompt.push('\n');
+    }
+    prompt.push_str(&format!(
+        "</tools>\n\nFor each function call, return a json object with the function name and \
+        arguments within {CALL_START}{CALL_END} XML tags:\n{CALL_START}\n\
+        {{\"name\": <function-name>, \"arguments\": <args-json-object>}}\n{CALL_END}"
+    ));
+    Ok(prompt)
+}
+
+/// The content of an assistant message followed by its tool calls, as the model writes them.
+fn assistant_content(message: AssistantMessage) -> String {
+    let mut content = message.content.unwrap_or_default();
+    for call in message.tool_calls {
+        let arguments = serde_json::from_str(&call.function.arguments)
+            .unwrap_or(serde_json::Value::String(call.function.arguments));
+        let call = serde_json::json!({"name": call.function.name, "arguments": arguments});
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&format!("{CALL_START}\n{call}\n{CALL_END}"));
+    }
+    content
+}
+
+/// The results of tool calls, in the order of the calls.
+fn tool_responses(messages: Vec<ToolMessage>) -> String {
+    messages
+        .into_iter()
+        .map(|message| format!("<tool_response>\n{}\n</tool_response>", message.content))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,79 @@
+use crate::types::chat::completion::ChatCompletionMessage;
+use serde::Deserialize;
+use std::fmt;
+
+/// What to do with prompts that do not fit into the context of the model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Truncation {
+    /// Reject the request with a context_length_exceeded error.
+    #[default]
+    Reject,
+    /// Drop the oldest turns after the system message until the prompt fits, keeping at least
+    /// the last message.
+    DropOldest,
+}
+
+/// The prompt leaves no room for the completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ContextLengthExceeded {
+    pub(crate) prompt_tokens: usize,
+    pub(crate) context_length: usize,
+}
+
+impl fmt::Display for ContextLengthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "This model's maximum context length is {} tokens. However, your prompt resulted in {} tokens. Please reduce the length of the prompt.",
+            self.context_length, self.prompt_tokens
+        )
+    }
+}
+
+impl std::error::Error for ContextLengthExceeded {}
+
+/// Drop the oldest turn after the system message, along with the results of its tool calls.
+///
+/// Returns false if only the last message is left.
+pub(crate) fn drop_oldest_turn(messages: &mut Vec<ChatCompletionMessage>) -> bool {
+    let is_chat =
+        |message: &ChatCompletionMessage| !matches!(message, ChatCompletionMessage::System(_));
+    if messages.iter().filter(|message| is_chat(message)).count() <= 1 {
+        return false;
+    }
+    let Some(oldest) = messages.iter().position(is_chat) else {
+        return false;
+    };
+    messages.remove(oldest);
+    while let Some(ChatCompletionMessage::Tool(_)) = messages.get(oldest) {
+        messages.remove(oldest);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::drop_oldest_turn;
+    use crate::types::chat::completion::ChatCompletionMessage;
+
+    #[test]
+    fn drop_oldest_turns() {
+        let mut messages: Vec<ChatCompletionMessage> = serde_json::from_str(
+            r#"[
+                {"role": "system", "content": "be brief"},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "a", "type": "function", "function": {"name": "f", "arguments": "{}"}}
+                ]},
+                {"role": "tool", "content": "1", "tool_call_id": "a"},
+                {"role": "user", "content": "hi"}
+            ]"#,
+        )
+        .unwrap();
+        assert!(drop_oldest_turn(&mut messages));
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], ChatCompletionMessage::System(_)));
+        assert!(matches!(messages[1], ChatCompletionMessage::User(_)));
+        assert!(!drop_oldest_turn(&mut messages));
+    }
+}
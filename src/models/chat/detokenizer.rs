@@ -0,0 +1,110 @@
+use anyhow::Result;
+
+/// Turns a stream of tokens into text, one token at a time.
+///
+/// Decoding tokens one by one drops the leading space of SentencePiece tokens and splits
+/// multi-byte characters into replacement characters. Instead every new token is decoded along
+/// with the few tokens before it, and only the text that this window adds to the decoded prefix
+/// is released, once it no longer ends in an incomplete UTF-8 sequence.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Detokenizer {
+    tokens: Vec<u32>,
+    /// Start of the window that is decoded to get the context of new tokens.
+    prefix_offset: usize,
+    /// End of the tokens whose text has been released.
+    read_offset: usize,
+}
+
+impl Detokenizer {
+    /// Add `token` and return the text that became stable with it, which may be empty.
+    pub(crate) fn push<F>(&mut self, token: u32, decode: F) -> Result<String>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        self.tokens.push(token);
+        let prefix_text = decode(&self.tokens[self.prefix_offset..self.read_offset])?;
+        let new_text = decode(&self.tokens[self.prefix_offset..])?;
+        if new_text.len() <= prefix_text.len() || new_text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.tokens.len();
+        Ok(suffix(&new_text, prefix_text.len()).to_string())
+    }
+
+    /// Release the text of the remaining tokens, even if it ends in an incomplete character.
+    pub(crate) fn flush<F>(&mut self, decode: F) -> Result<String>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        if self.read_offset == self.tokens.len() {
+            return Ok(String::new());
+        }
+        let prefix_text = decode(&self.tokens[self.prefix_offset..self.read_offset])?;
+        let new_text = decode(&self.tokens[self.prefix_offset..])?;
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.tokens.len();
+        Ok(suffix(&new_text, prefix_text.len()).to_string())
+    }
+}
+
+/// The part of `text` after its first `len` bytes, starting at the next character boundary.
+fn suffix(text: &str, len: usize) -> &str {
+    let start = (len.min(text.len())..=text.len())
+        .find(|start| text.is_char_boundary(*start))
+        .unwrap_or(text.len());
+    &text[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Detokenizer;
+    use anyhow::Result;
+
+    /// Decodes like a SentencePiece tokenizer with byte fallback: `▁` marks a space, which is
+    /// dropped at the start of the decoded text.
+    fn decode(tokens: &[u32]) -> Result<String> {
+        let vocab: [&[u8]; 6] = [
+            b"\xe2\x96\x81Hello",
+            b"\xe2\x96\x81world",
+            b"!",
+            b"\xe4",
+            b"\xbd",
+            b"\xa0",
+        ];
+        let bytes: Vec<u8> = tokens
+            .iter()
+            .flat_map(|token| vocab[*token as usize].to_vec())
+            .collect();
+        let text = String::from_utf8_lossy(&bytes).replace('\u{2581}', " ");
+        Ok(text.strip_prefix(' ').unwrap_or(&text).to_string())
+    }
+
+    fn detokenize(tokens: &[u32]) -> Vec<String> {
+        let mut detokenizer = Detokenizer::default();
+        let mut texts: Vec<String> = tokens
+            .iter()
+            .map(|token| detokenizer.push(*token, decode).unwrap())
+            .collect();
+        texts.push(detokenizer.flush(decode).unwrap());
+        texts
+    }
+
+    #[test]
+    fn keep_leading_spaces() {
+        assert_eq!(detokenize(&[0, 1, 2]), vec!["Hello", " world", "!", ""]);
+    }
+
+    #[test]
+    fn wait_for_complete_characters() {
+        assert_eq!(
+            detokenize(&[0, 3, 4, 5, 2]),
+            vec!["Hello", "", "", "你", "!", ""]
+        );
+    }
+
+    #[test]
+    fn flush_incomplete_characters() {
+        assert_eq!(detokenize(&[0, 3, 4]), vec!["Hello", "", "", "\u{FFFD}"]);
+    }
+}
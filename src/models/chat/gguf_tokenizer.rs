@@ -0,0 +1,250 @@
+//! Tokenizers built from the `tokenizer.ggml.*` metadata of GGUF files, for model files that come
+//! without a tokenizer.json.
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::{Content, Value};
+use std::collections::HashMap;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::byte_level::ByteLevel;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, DecoderWrapper, NormalizerWrapper, Tokenizer};
+
+/// Token types of `tokenizer.ggml.token_type`.
+const CONTROL: i32 = 3;
+const USER_DEFINED: i32 = 4;
+
+/// Control tokens that end a turn in common chat formats, which files do not always list as
+/// eos or eot tokens.
+const END_OF_TURN: &[&str] = &[
+    "<|eot_id|>",
+    "<|eom_id|>",
+    "<|im_end|>",
+    "<|end|>",
+    "<end_of_turn>",
+    "<|endoftext|>",
+    "<|end_of_turn|>",
+    "<EOT>",
+];
+
+/// Build the tokenizer of a llama style SentencePiece (`llama`) or GPT-2 style BPE (`gpt2`)
+/// vocabulary.
+///
+/// BPE vocabularies are split into words with the GPT-2 pattern, the pre-tokenizer variants of
+/// `tokenizer.ggml.pre` are not reproduced.
+pub(crate) fn tokenizer_from_gguf(content: &Content) -> Result<Tokenizer> {
+    let metadata = |key: &str| content.metadata.get(&format!("tokenizer.ggml.{key}"));
+    let model = metadata("model")
+        .context("the GGUF file has no tokenizer, set tokenizer in the config")?
+        .to_string()?;
+    let tokens = strings(metadata("tokens").context("cannot find tokenizer.ggml.tokens")?)?;
+    let token_types = match metadata("token_type") {
+        Some(types) => types
+            .to_vec()?
+            .iter()
+            .map(|value| value.to_i32())
+            .collect::<candle_core::Result<Vec<_>>>()?,
+        None => vec![],
+    };
+    let token_id = |key: &str| metadata(key).and_then(|value| value.to_u32().ok());
+
+    let (mut tokenizer, add_bos) = match model.as_str() {
+        "llama" => {
+            let scores = match metadata("scores") {
+                Some(scores) => scores
+                    .to_vec()?
+                    .iter()
+                    .map(|value| value.to_f32().map(f64::from))
+                    .collect::<candle_core::Result<Vec<_>>>()?,
+                None => vec![0.; tokens.len()],
+            };
+            let vocab = tokens.iter().cloned().zip(scores).collect();
+            let unk_id = token_id("unknown_token_id").map(|id| id as usize);
+            let unigram = Unigram::from(vocab, unk_id, true).map_err(anyhow::Error::msg)?;
+            let mut tokenizer = Tokenizer::new(unigram);
+            tokenizer.with_normalizer(NormalizerWrapper::Sequence(NormalizerSequence::new(vec![
+                Prepend::new("\u{2581}".to_string()).into(),
+                Replace::new(" ", "\u{2581}")
+                    .map_err(anyhow::Error::msg)?
+                    .into(),
+            ])));
+            tokenizer.with_decoder(DecoderWrapper::Sequence(DecoderSequence::new(vec![
+                Replace::new("\u{2581}", " ")
+                    .map_err(anyhow::Error::msg)?
+                    .into(),
+                ByteFallback::new().into(),
+                Fuse::new().into(),
+                Strip::new(' ', 1, 0).into(),
+            ])));
+            (tokenizer, true)
+        }
+        "gpt2" => {
+            let vocab: HashMap<String, u32> = tokens
+                .iter()
+                .enumerate()
+                .map(|(id, token)| (token.clone(), id as u32))
+                .collect();
+            let merges = strings(metadata("merges").context("cannot find tokenizer.ggml.merges")?)?
+                .into_iter()
+                .map(|merge| {
+                    let (left, right) = merge.split_once(' ').context("invalid merge")?;
+                    Ok((left.to_string(), right.to_string()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .build()
+                .map_err(anyhow::Error::msg)?;
+            let mut tokenizer = Tokenizer::new(bpe);
+            tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, true));
+            tokenizer.with_decoder(ByteLevel::default());
+            tokenizer.with_post_processor(ByteLevel::default());
+            (tokenizer, false)
+        }
+        model => anyhow::bail!("unsupported GGUF tokenizer {model}, set tokenizer in the config"),
+    };
+
+    let added = |token_type: i32| -> Vec<AddedToken> {
+        token_types
+            .iter()
+            .zip(&tokens)
+            .filter(|(kind, _)| **kind == token_type)
+            .map(|(_, token)| AddedToken::from(token.clone(), token_type == CONTROL))
+            .collect()
+    };
+    tokenizer.add_special_tokens(&added(CONTROL));
+    tokenizer.add_tokens(&added(USER_DEFINED));
+
+    let add_bos = metadata("add_bos_token")
+        .and_then(|value| value.to_bool().ok())
+        .unwrap_or(add_bos);
+    if let Some(bos_id) = token_id("bos_token_id").filter(|_| add_bos) {
+        let bos = tokens
+            .get(bos_id as usize)
+            .context("bos token out of range")?;
+        let template = TemplateProcessing::builder()
+            .try_single(format!("{bos}:0 $A:0"))
+            .map_err(anyhow::Error::msg)?
+            .special_tokens(vec![(bos.clone(), bos_id)])
+            .build()?;
+        tokenizer.with_post_processor(template);
+    }
+    Ok(tokenizer)
+}
+
+/// The tokens that end a generation: the eos, eot and eom tokens of the metadata and the control
+/// tokens that end a turn.
+pub(crate) fn eos_token_ids(content: &Content, tokenizer: &Tokenizer) -> Vec<u32> {
+    let mut ids: Vec<u32> = ["eos_token_id", "eot_token_id", "eom_token_id"]
+        .iter()
+        .filter_map(|key| content.metadata.get(&format!("tokenizer.ggml.{key}")))
+        .filter_map(|value| value.to_u32().ok())
+        .collect();
+    let special = tokenizer.get_added_tokens_decoder();
+    ids.extend(
+        END_OF_TURN
+            .iter()
+            .filter_map(|token| tokenizer.token_to_id(token))
+            .filter(|id| special.get(id).is_some_and(|token| token.special)),
+    );
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+fn strings(value: &Value) -> Result<Vec<String>> {
+    Ok(value
+        .to_vec()?
+        .iter()
+        .map(|value| value.to_string().cloned())
+        .collect::<candle_core::Result<_>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eos_token_ids, tokenizer_from_gguf};
+    use candle_core::quantized::gguf_file::{Content, Value, VersionedMagic};
+    use std::collections::HashMap;
+
+    fn content(metadata: Vec<(&str, Value)>) -> Content {
+        Content {
+            magic: VersionedMagic::GgufV3,
+            metadata: metadata
+                .into_iter()
+                .map(|(key, value)| (format!("tokenizer.ggml.{key}"), value))
+                .collect(),
+            tensor_infos: HashMap::new(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    fn strings(values: &[&str]) -> Value {
+        Value::Array(
+            values
+                .iter()
+                .map(|value| Value::String(value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn sentencepiece_tokenizer() {
+        let content = content(vec![
+            ("model", Value::String("llama".to_string())),
+            (
+                "tokens",
+                strings(&[
+                    "<unk>",
+                    "<s>",
+                    "</s>",
+                    "\u{2581}Hello",
+                    "\u{2581}world",
+                    "!",
+                ]),
+            ),
+            (
+                "scores",
+                Value::Array([0., 0., 0., -1., -1., -2.].map(Value::F32).to_vec()),
+            ),
+            (
+                "token_type",
+                Value::Array([2, 3, 3, 1, 1, 1].map(Value::I32).to_vec()),
+            ),
+            ("unknown_token_id", Value::U32(0)),
+            ("bos_token_id", Value::U32(1)),
+            ("eos_token_id", Value::U32(2)),
+        ]);
+        let tokenizer = tokenizer_from_gguf(&content).unwrap();
+        let encoding = tokenizer.encode("Hello world!", true).unwrap();
+        assert_eq!(encoding.get_ids(), [1, 3, 4, 5]);
+        assert_eq!(tokenizer.decode(&[3, 4, 5], true).unwrap(), "Hello world!");
+        assert_eq!(eos_token_ids(&content, &tokenizer), [2]);
+    }
+
+    #[test]
+    fn byte_level_tokenizer() {
+        let content = content(vec![
+            ("model", Value::String("gpt2".to_string())),
+            (
+                "tokens",
+                strings(&["a", "b", "\u{120}", "ab", "\u{120}ab", "<|im_end|>"]),
+            ),
+            ("merges", strings(&["a b", "\u{120} ab"])),
+            (
+                "token_type",
+                Value::Array([1, 1, 1, 1, 1, 3].map(Value::I32).to_vec()),
+            ),
+            ("eos_token_id", Value::U32(1)),
+        ]);
+        let tokenizer = tokenizer_from_gguf(&content).unwrap();
+        let encoding = tokenizer.encode("ab ab<|im_end|>", true).unwrap();
+        assert_eq!(encoding.get_ids(), [3, 4, 5]);
+        assert_eq!(tokenizer.decode(&[3, 4], true).unwrap(), "ab ab");
+        assert_eq!(eos_token_ids(&content, &tokenizer), [1, 5]);
+    }
+}
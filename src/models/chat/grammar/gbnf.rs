@@ -0,0 +1,276 @@
+use crate::models::chat::grammar::{Grammar, GrammarBuilder, Symbol};
+use anyhow::{bail, Context, Result};
+
+/// A grammar in the GBNF syntax of llama.cpp, starting at its `root` rule.
+///
+/// Rules are written `name ::= alternatives` and end at the end of the line, unless the line
+/// ends in `|` or inside parentheses. Supported are quoted literals, character classes like
+/// `[a-z]` and `[^"]`, `.` for any character, groups and the repetitions `*`, `+`, `?`, `{m}`,
+/// `{m,}` and `{m,n}`. Comments start with `#`.
+pub(crate) fn gbnf_grammar(source: &str) -> Result<Grammar> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        position: 0,
+        builder: GrammarBuilder::default(),
+    };
+    parser.rules().with_context(|| {
+        let line = parser.chars[..parser.position]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count();
+        format!("invalid grammar at line {}", line + 1)
+    })?;
+    let root = parser.builder.rule("root");
+    parser.builder.build(root)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    builder: GrammarBuilder,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        if eaten {
+            self.position += 1;
+        }
+        eaten
+    }
+
+    fn expect(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            if !self.eat(c) {
+                bail!("expected `{text}`");
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self.peek().context("unexpected end of grammar")?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    /// Skip spaces and comments, and line breaks too if `newlines` is set.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.position += 1,
+                '\r' | '\n' if newlines => self.position += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\r' && c != '\n') {
+                        self.position += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn rules(&mut self) -> Result<()> {
+        self.skip_space(true);
+        while self.peek().is_some() {
+            let name = self.name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            self.skip_space(true);
+            let alternatives = self.alternatives(false)?;
+            let rule = self.builder.rule(&name);
+            self.builder.define(rule, alternatives);
+            if !matches!(self.peek(), None | Some('\r' | '\n')) {
+                bail!("expected the end of rule `{name}`");
+            }
+            self.skip_space(true);
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            self.position += 1;
+        }
+        if self.position == start {
+            bail!("expected a rule name");
+        }
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    fn alternatives(&mut self, nested: bool) -> Result<Vec<Vec<Symbol>>> {
+        let mut alternatives = vec![self.sequence(nested)?];
+        while self.eat('|') {
+            self.skip_space(true);
+            alternatives.push(self.sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<Vec<Symbol>> {
+        let mut sequence = vec![];
+        loop {
+            let symbols = match self.peek() {
+                Some('"') => {
+                    self.position += 1;
+                    let mut text = String::new();
+                    while !self.eat('"') {
+                        text.push(self.char()?);
+                    }
+                    GrammarBuilder::literal(&text)
+                }
+                Some('[') => {
+                    self.position += 1;
+                    let negated = self.eat('^');
+                    let mut ranges = vec![];
+                    while !self.eat(']') {
+                        let start = self.char()?;
+                        let end = if self.peek() == Some('-')
+                            && self.chars.get(self.position + 1) != Some(&']')
+                        {
+                            self.position += 1;
+                            self.char()?
+                        } else {
+                            start
+                        };
+                        ranges.push((start, end));
+                    }
+                    vec![self.builder.char_class(&ranges, negated)]
+                }
+                Some('.') => {
+                    self.position += 1;
+                    vec![self.builder.char_class(&[], true)]
+                }
+                Some('(') => {
+                    self.position += 1;
+                    self.skip_space(true);
+                    let alternatives = self.alternatives(true)?;
+                    self.expect(")")?;
+                    vec![Symbol::Rule(self.builder.fresh("_group", alternatives))]
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' => {
+                    let name = self.name()?;
+                    vec![Symbol::Rule(self.builder.rule(&name))]
+                }
+                _ => break,
+            };
+            self.skip_space(nested);
+            let repetition = match self.peek() {
+                Some('*') => Some((0, None)),
+                Some('+') => Some((1, None)),
+                Some('?') => Some((0, Some(1))),
+                Some('{') => Some(self.bounds()?),
+                _ => None,
+            };
+            let symbols = match repetition {
+                Some((min, max)) => {
+                    self.position += 1;
+                    self.builder.repeat(symbols, min, max)
+                }
+                None => symbols,
+            };
+            self.skip_space(nested);
+            sequence.extend(symbols);
+        }
+        Ok(sequence)
+    }
+
+    /// The bounds of a `{m}`, `{m,}` or `{m,n}` repetition, up to its closing brace.
+    fn bounds(&mut self) -> Result<(usize, Option<usize>)> {
+        self.position += 1;
+        self.skip_space(false);
+        let min = self.number()?;
+        self.skip_space(false);
+        let max = if self.eat(',') {
+            self.skip_space(false);
+            if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                Some(self.number()?)
+            } else {
+                None
+            }
+        } else {
+            Some(min)
+        };
+        self.skip_space(false);
+        if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+            bail!("invalid repetition");
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        let digits: String = self.chars[start..self.position].iter().collect();
+        digits.parse().context("expected a number")
+    }
+
+    /// A character of a literal or a character class, which may be escaped.
+    fn char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let digits = match self.next()? {
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            c @ ('\\' | '"' | '[' | ']' | '-' | '^') => return Ok(c),
+            c => bail!("unknown escape `\\{c}`"),
+        };
+        let mut code = 0;
+        for _ in 0..digits {
+            let digit = self.next()?.to_digit(16).context("expected a hex digit")?;
+            code = code * 16 + digit;
+        }
+        char::from_u32(code).context("invalid character escape")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gbnf_grammar;
+    use crate::models::chat::grammar::GrammarState;
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_gbnf() {
+        let source = r#"
+            # a list of greetings
+            root ::= greeting ("," [ \t]? greeting){0,2} "\x21"?
+            greeting ::= ("hello" |
+                "hi") " " name
+            name ::= [A-Z] [^,!]*
+        "#;
+        let grammar = Arc::new(gbnf_grammar(source).unwrap());
+        let state = GrammarState::new(grammar);
+        for valid in ["hello Ann", "hi Bob, hello Zoë,hi X!"] {
+            let mut state = state.clone();
+            assert!(state.accept(valid.as_bytes()), "{valid}");
+            assert!(state.is_accepting(), "{valid}");
+        }
+        for invalid in ["hey Ann", "hi ann", "hi A, hi B, hi C, hi D", "hi A!!"] {
+            let mut state = state.clone();
+            assert!(
+                !(state.accept(invalid.as_bytes()) && state.is_accepting()),
+                "{invalid}"
+            );
+        }
+        assert!(gbnf_grammar("root ::= item").is_err());
+        assert!(gbnf_grammar("root ::= \"a\" )").is_err());
+        assert!(gbnf_grammar("start ::= \"a\"").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::models::chat::grammar::{Grammar, GrammarBuilder, RuleId, Symbol};
+use anyhow::Result;
+
+/// Longest run of whitespace allowed between tokens, so that a model cannot get stuck
+/// generating nothing but newlines.
+const MAX_WHITESPACE: usize = 32;
+
+/// Rules of the JSON grammar that other grammars build on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct JsonRules {
+    pub(crate) value: RuleId,
+    pub(crate) object: RuleId,
+    pub(crate) string: RuleId,
+    /// A single, possibly escaped, character of a string.
+    pub(crate) character: RuleId,
+    pub(crate) number: RuleId,
+    pub(crate) integer: RuleId,
+    pub(crate) boolean: RuleId,
+    pub(crate) null: RuleId,
+    pub(crate) ws: RuleId,
+}
+
+impl JsonRules {
+    pub(crate) fn new(builder: &mut GrammarBuilder) -> Self {
+        let lit = GrammarBuilder::literal;
+        let rule = Symbol::Rule;
+        let value = builder.rule("value");
+        let object = builder.rule("object");
+        let array = builder.rule("array");
+        let string = builder.rule("string");
+        let number = builder.rule("number");
+
+        let whitespace = builder.char_class(&[(' ', ' '), ('\t', '\t'), ('\n', '\n')], false);
+        let ws = builder.repeat(vec![whitespace], 0, Some(MAX_WHITESPACE));
+        let ws = builder.add("ws", vec![ws]);
+
+        let member = builder.add(
+            "member",
+            vec![vec![
+                rule(string),
+                rule(ws),
+                Symbol::byte(b':'),
+                rule(ws),
+                rule(value),
+                rule(ws),
+            ]],
+        );
+        let mut members = vec![rule(member)];
+        members.extend(builder.repeat(vec![Symbol::byte(b','), rule(ws), rule(member)], 0, None));
+        builder.define(
+            object,
+            vec![
+                [lit("{"), vec![rule(ws)], lit("}")].concat(),
+                [lit("{"), vec![rule(ws)], members, lit("}")].concat(),
+            ],
+        );
+
+        let element = vec![rule(value), rule(ws)];
+        let mut elements = element.clone();
+        elements.extend(builder.repeat([lit(","), vec![rule(ws)], element].concat(), 0, None));
+        builder.define(
+            array,
+            vec![
+                [lit("["), vec![rule(ws)], lit("]")].concat(),
+                [lit("["), vec![rule(ws)], elements, lit("]")].concat(),
+            ],
+        );
+
+        let unescaped = builder.char_class(&[('"', '"'), ('\\', '\\'), ('\0', '\x1f')], true);
+        let hex = builder.char_class(&[('0', '9'), ('a', 'f'), ('A', 'F')], false);
+        let escaped = builder.char_class(
+            &[
+                ('"', '"'),
+                ('\\', '\\'),
+                ('/', '/'),
+                ('b', 'b'),
+                ('f', 'f'),
+                ('n', 'n'),
+                ('r', 'r'),
+                ('t', 't'),
+            ],
+            false,
+        );
+        let character = builder.add(
+            "character",
+            vec![
+                vec![unescaped],
+                vec![Symbol::byte(b'\\'), escaped],
+                [lit("\\u"), vec![hex.clone(); 4]].concat(),
+            ],
+        );
+        let characters = builder.repeat(vec![rule(character)], 0, None);
+        builder.define(string, vec![[lit("\""), characters, lit("\"")].concat()]);
+
+        let digit = builder.char_class(&[('0', '9')], false);
+        let non_zero = builder.char_class(&[('1', '9')], false);
+        let mut digits = vec![non_zero];
+        digits.extend(builder.repeat(vec![digit.clone()], 0, None));
+        let unsigned = builder.add("unsigned", vec![lit("0"), digits]);
+        let sign = builder.repeat(lit("-"), 0, Some(1));
+        let integer = builder.add("integer", vec![[sign, vec![rule(unsigned)]].concat()]);
+        let fraction_digits = builder.repeat(vec![digit.clone()], 1, None);
+        let fraction = builder.repeat([lit("."), fraction_digits].concat(), 0, Some(1));
+        let e = builder.char_class(&[('e', 'e'), ('E', 'E')], false);
+        let exponent_sign = builder.char_class(&[('+', '+'), ('-', '-')], false);
+        let exponent_sign = builder.repeat(vec![exponent_sign], 0, Some(1));
+        let exponent_digits = builder.repeat(vec![digit], 1, None);
+        let exponent = [vec![e], exponent_sign, exponent_digits].concat();
+        let exponent = builder.repeat(exponent, 0, Some(1));
+        builder.define(
+            number,
+            vec![[vec![rule(integer)], fraction, exponent].concat()],
+        );
+
+        let boolean = builder.add("boolean", vec![lit("true"), lit("false")]);
+        let null = builder.add("null", vec![lit("null")]);
+        builder.define(
+            value,
+            vec![
+                vec![rule(object)],
+                vec![rule(array)],
+                vec![rule(string)],
+                vec![rule(number)],
+                vec![rule(boolean)],
+                vec![rule(null)],
+            ],
+        );
+        Self {
+            value,
+            object,
+            string,
+            character,
+            number,
+            integer,
+            boolean,
+            null,
+            ws,
+        }
+    }
+}
+
+/// Any JSON object, optionally preceded by whitespace, and nothing after it.
+pub(crate) fn json_object_grammar() -> Result<Grammar> {
+    let mut builder = GrammarBuilder::default();
+    let json = JsonRules::new(&mut builder);
+    let root = builder.add(
+        "root",
+        vec![vec![Symbol::Rule(json.ws), Symbol::Rule(json.object)]],
+    );
+    builder.build(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_object_grammar;
+    use crate::models::chat::grammar::GrammarState;
+    use std::sync::Arc;
+
+    #[test]
+    fn json_object() {
+        let grammar = Arc::new(json_object_grammar().unwrap());
+        let state = GrammarState::new(grammar.clone());
+        for valid in [
+            "{}",
+            " {\"a\": [1, -2.5e3, true, null, {\"b\": \"\\u00e9\\n\"}], \"c\": \"好\"}",
+        ] {
+            let mut state = state.clone();
+            assert!(state.accept(valid.as_bytes()), "{valid}");
+            assert!(state.is_finished(), "{valid}");
+        }
+        for invalid in [
+            "[]",
+            "{\"a\" 1}",
+            "{\"a\": 01}",
+            "{\"a\": tru}",
+            "{}}",
+            "{\"\n\"}",
+        ] {
+            let mut state = state.clone();
+            assert!(
+                !(state.accept(invalid.as_bytes()) && state.is_accepting()),
+                "{invalid}"
+            );
+        }
+        let mut state = state.clone();
+        assert!(state.accept(b"{\"a\": 1"));
+        assert!(!state.is_accepting());
+        assert!(state.accepts(b"0}"));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::models::chat::grammar::json::JsonRules;
+use crate::models::chat::grammar::{Grammar, GrammarBuilder, RuleId, Symbol};
+use anyhow::Result;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Keywords that constrain the output and are compiled into the grammar.
+const KEYWORDS: &[&str] = &[
+    "type",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "enum",
+    "const",
+    "$ref",
+    "anyOf",
+    "oneOf",
+    "allOf",
+];
+
+/// Keywords that only describe or organize the schema.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "$defs",
+    "definitions",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+/// Compile a JSON schema into a grammar of the JSON documents that validate against it.
+///
+/// Objects are generated with their properties in the order the schema lists them, optional
+/// properties may be left out and no other properties are generated. Keywords that cannot be
+/// enforced during generation, such as `pattern` or `minimum`, are rejected rather than ignored.
+pub(crate) fn json_schema_grammar(schema: &Value) -> Result<Grammar> {
+    let mut builder = GrammarBuilder::default();
+    let json = JsonRules::new(&mut builder);
+    let value = compile_schema(&mut builder, json, schema, "schema")?;
+    let root = builder.add("root", vec![vec![Symbol::Rule(json.ws), value]]);
+    builder.build(root)
+}
+
+/// A symbol for the JSON values that validate against `schema`, added to an existing grammar.
+pub(crate) fn compile_schema(
+    builder: &mut GrammarBuilder,
+    json: JsonRules,
+    schema: &Value,
+    name: &str,
+) -> Result<Symbol> {
+    let mut compiler = SchemaCompiler {
+        builder,
+        json,
+        root: schema,
+        refs: HashMap::new(),
+    };
+    compiler.compile(schema, name)
+}
+
+struct SchemaCompiler<'a> {
+    builder: &'a mut GrammarBuilder,
+    json: JsonRules,
+    root: &'a Value,
+    /// Rules of the schemas referenced so far, by reference.
+    refs: HashMap<String, RuleId>,
+}
+
+impl SchemaCompiler<'_> {
+    /// A symbol for the values that validate against `schema`.
+    fn compile(&mut self, schema: &Value, name: &str) -> Result<Symbol> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(Symbol::Rule(self.json.value)),
+            Value::Object(schema) => schema,
+            _ => anyhow::bail!("invalid schema at {name}: {schema}"),
+        };
+        if let Some(keyword) = schema
+            .keys()
+            .find(|key| !KEYWORDS.contains(&key.as_str()) && !ANNOTATIONS.contains(&key.as_str()))
+        {
+            anyhow::bail!("unsupported JSON schema keyword at {name}: {keyword}");
+        }
+        if let Some(reference) = schema.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("$ref must be a string at {name}"))?;
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.literal(value, name));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("enum must be an array at {name}"))?;
+            let alternatives = values
+                .iter()
+                .map(|value| GrammarBuilder::literal(&value.to_string()))
+                .collect();
+            return Ok(Symbol::Rule(self.builder.fresh(name, alternatives)));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("{keyword} must be an array at {name}"))?;
+                let alternatives = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(index, schema)| {
+                        Ok(vec![self.compile(schema, &format!("{name}-{index}"))?])
+                    })
+                    .collect::<Result<_>>()?;
+                return Ok(Symbol::Rule(self.builder.fresh(name, alternatives)));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => return self.compile(schema, name),
+                _ => anyhow::bail!("allOf is only supported with a single schema at {name}"),
+            }
+        }
+        let types = match schema.get("type") {
+            Some(Value::String(kind)) => vec![kind.as_str()],
+            Some(Value::Array(kinds)) => kinds
+                .iter()
+                .map(|kind| {
+                    kind.as_str()
+                        .ok_or_else(|| anyhow::anyhow!("invalid type at {name}: {kind}"))
+                })
+                .collect::<Result<_>>()?,
+            Some(kind) => anyhow::bail!("invalid type at {name}: {kind}"),
+            None if schema.contains_key("properties") => vec!["object"],
+            None if schema.contains_key("items") => vec!["array"],
+            None => return Ok(Symbol::Rule(self.json.value)),
+        };
+        let alternatives = types
+            .into_iter()
+            .map(|kind| Ok(vec![self.compile_type(kind, schema, name)?]))
+            .collect::<Result<Vec<_>>>()?;
+        match <[_; 1]>::try_from(alternatives) {
+            Ok([mut alternative]) => Ok(alternative.remove(0)),
+            Err(alternatives) => Ok(Symbol::Rule(self.builder.fresh(name, alternatives))),
+        }
+    }
+
+    fn compile_type(
+        &mut self,
+        kind: &str,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<Symbol> {
+        let json = self.json;
+        match kind {
+            "object" => self.object(schema, name),
+            "array" => self.array(schema, name),
+            "string" => {
+                let min = usize_keyword(schema, "minLength", name)?;
+                let max = usize_keyword(schema, "maxLength", name)?;
+                if min.is_none() && max.is_none() {
+                    return Ok(Symbol::Rule(json.string));
+                }
+                let characters =
+                    self.builder
+                        .repeat(vec![Symbol::Rule(json.character)], min.unwrap_or(0), max);
+                let string = [
+                    vec![Symbol::byte(b'"')],
+                    characters,
+                    vec![Symbol::byte(b'"')],
+                ]
+                .concat();
+                Ok(Symbol::Rule(self.builder.fresh(name, vec![string])))
+            }
+            "number" => Ok(Symbol::Rule(json.number)),
+            "integer" => Ok(Symbol::Rule(json.integer)),
+            "boolean" => Ok(Symbol::Rule(json.boolean)),
+            "null" => Ok(Symbol::Rule(json.null)),
+            kind => anyhow::bail!("unsupported type at {name}: {kind}"),
+        }
+    }
+
+    fn object(&mut self, schema: &Map<String, Value>, name: &str) -> Result<Symbol> {
+        let json = self.json;
+        match schema.get("additionalProperties") {
+            None | Some(Value::Bool(_)) => {}
+            Some(_) => {
+                anyhow::bail!("additionalProperties is only supported as a boolean at {name}")
+            }
+        }
+        let Some(properties) = schema.get("properties") else {
+            if schema.get("additionalProperties") == Some(&Value::Bool(false)) {
+                let empty = [
+                    GrammarBuilder::literal("{"),
+                    vec![Symbol::Rule(json.ws)],
+                    GrammarBuilder::literal("}"),
+                ]
+                .concat();
+                return Ok(Symbol::Rule(self.builder.fresh(name, vec![empty])));
+            }
+            return Ok(Symbol::Rule(json.object));
+        };
+        let properties = properties
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("properties must be an object at {name}"))?;
+        let required: Vec<&str> = match schema.get("required") {
+            None => vec![],
+            Some(Value::Array(required)) => required
+                .iter()
+                .map(|property| {
+                    property
+                        .as_str()
+                        .ok_or_else(|| anyhow::anyhow!("invalid required property at {name}"))
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => anyhow::bail!("required must be an array at {name}"),
+        };
+        if let Some(property) = required
+            .iter()
+            .find(|property| !properties.contains_key(**property))
+        {
+            anyhow::bail!("required property {property} is not defined at {name}");
+        }
+        // "key": value, one sequence per property
+        let mut members = vec![];
+        for (key, property) in properties {
+            let value = self.compile(property, &format!("{name}-{key}"))?;
+            let member = [
+                GrammarBuilder::literal(&Value::String(key.clone()).to_string()),
+                vec![
+                    Symbol::Rule(json.ws),
+                    Symbol::byte(b':'),
+                    Symbol::Rule(json.ws),
+                ],
+                vec![value, Symbol::Rule(json.ws)],
+            ]
+            .concat();
+            members.push((member, required.contains(&key.as_str())));
+        }
+        // rest[i] lists the members from i on; the first member listed has no leading comma
+        let separator = [GrammarBuilder::literal(","), vec![Symbol::Rule(json.ws)]].concat();
+        let mut first_rest = self.builder.fresh(&format!("{name}-end"), vec![vec![]]);
+        let mut later_rest = first_rest;
+        for (member, required) in members.into_iter().rev() {
+            let first = [member.clone(), vec![Symbol::Rule(later_rest)]].concat();
+            let later = [separator.clone(), member, vec![Symbol::Rule(later_rest)]].concat();
+            let (first, later) = if required {
+                (vec![first], vec![later])
+            } else {
+                (
+                    vec![first, vec![Symbol::Rule(first_rest)]],
+                    vec![later, vec![Symbol::Rule(later_rest)]],
+                )
+            };
+            first_rest = self.builder.fresh(&format!("{name}-members"), first);
+            later_rest = self.builder.fresh(&format!("{name}-members"), later);
+        }
+        let object = [
+            GrammarBuilder::literal("{"),
+            vec![Symbol::Rule(json.ws), Symbol::Rule(first_rest)],
+            GrammarBuilder::literal("}"),
+        ]
+        .concat();
+        Ok(Symbol::Rule(self.builder.fresh(name, vec![object])))
+    }
+
+    fn array(&mut self, schema: &Map<String, Value>, name: &str) -> Result<Symbol> {
+        let json = self.json;
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items, &format!("{name}-item"))?,
+            None => Symbol::Rule(json.value),
+        };
+        let min = usize_keyword(schema, "minItems", name)?.unwrap_or(0);
+        let max = usize_keyword(schema, "maxItems", name)?;
+        let open = [GrammarBuilder::literal("["), vec![Symbol::Rule(json.ws)]].concat();
+        let close = GrammarBuilder::literal("]");
+        let mut alternatives = vec![];
+        if min == 0 {
+            alternatives.push([open.clone(), close.clone()].concat());
+        }
+        if max != Some(0) {
+            let element = vec![item, Symbol::Rule(json.ws)];
+            let next = [
+                GrammarBuilder::literal(","),
+                vec![Symbol::Rule(json.ws)],
+                element.clone(),
+            ]
+            .concat();
+            let rest = self
+                .builder
+                .repeat(next, min.saturating_sub(1), max.map(|max| max - 1));
+            alternatives.push([open, element, rest, close].concat());
+        }
+        Ok(Symbol::Rule(self.builder.fresh(name, alternatives)))
+    }
+
+    /// Resolve a reference within the schema, such as `#/$defs/address`.
+    fn reference(&mut self, reference: &str) -> Result<Symbol> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(Symbol::Rule(*rule));
+        }
+        let schema = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| anyhow::anyhow!("cannot resolve $ref: {reference}"))?;
+        let name = reference.rsplit('/').next().unwrap_or("ref").to_string();
+        let rule = self.builder.reserve(&name);
+        self.refs.insert(reference.to_string(), rule);
+        let value = self.compile(schema, &name)?;
+        self.builder.define(rule, vec![vec![value]]);
+        Ok(Symbol::Rule(rule))
+    }
+
+    fn literal(&mut self, value: &Value, name: &str) -> Symbol {
+        let literal = GrammarBuilder::literal(&value.to_string());
+        Symbol::Rule(self.builder.fresh(name, vec![literal]))
+    }
+}
+
+fn usize_keyword(schema: &Map<String, Value>, keyword: &str, name: &str) -> Result<Option<usize>> {
+    schema
+        .get(keyword)
+        .map(|value| {
+            value.as_u64().map(|value| value as usize).ok_or_else(|| {
+                anyhow::anyhow!("{keyword} must be a non-negative integer at {name}")
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_schema_grammar;
+    use crate::models::chat::grammar::GrammarState;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn accepts(state: &GrammarState, document: &str) -> bool {
+        let mut state = state.clone();
+        state.accept(document.as_bytes()) && state.is_accepting()
+    }
+
+    #[test]
+    fn object_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "maxLength": 8},
+                "age": {"type": "integer"},
+                "role": {"enum": ["admin", "user"]},
+                "address": {"$ref": "#/$defs/address"},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2}
+            },
+            "required": ["name", "role"],
+            "$defs": {
+                "address": {
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}, "next": {"$ref": "#/$defs/address"}},
+                    "required": ["city"]
+                }
+            }
+        });
+        let state = GrammarState::new(Arc::new(json_schema_grammar(&schema).unwrap()));
+        assert!(accepts(&state, r#"{"name": "Ann", "role": "user"}"#));
+        assert!(accepts(
+            &state,
+            r#"{"name": "Ann", "age": 30, "role": "admin", "address": {"city": "A", "next": {"city": "B"}}, "tags": ["a", "b"]}"#
+        ));
+        // missing required property, wrong order, unknown enum value, too long, too many items
+        assert!(!accepts(&state, r#"{"name": "Ann"}"#));
+        assert!(!accepts(&state, r#"{"role": "user", "name": "Ann"}"#));
+        assert!(!accepts(&state, r#"{"name": "Ann", "role": "root"}"#));
+        assert!(!accepts(&state, r#"{"name": "Ann Smith", "role": "user"}"#));
+        assert!(!accepts(
+            &state,
+            r#"{"name": "Ann", "role": "user", "tags": ["a", "b", "c"]}"#
+        ));
+        assert!(!accepts(
+            &state,
+            r#"{"name": "Ann", "role": "user", "address": {}}"#
+        ));
+    }
+
+    #[test]
+    fn optional_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"a": {"type": "number"}, "b": {"type": "boolean"}, "c": {"type": "null"}}
+        });
+        let state = GrammarState::new(Arc::new(json_schema_grammar(&schema).unwrap()));
+        for document in [
+            "{}",
+            r#"{"b": true}"#,
+            r#"{"a": 1.5, "c": null}"#,
+            r#"{"c": null}"#,
+        ] {
+            assert!(accepts(&state, document), "{document}");
+        }
+        for document in [r#"{, "b": true}"#, r#"{"a": 1,}"#, r#"{"d": 1}"#] {
+            assert!(!accepts(&state, document), "{document}");
+        }
+    }
+
+    #[test]
+    fn unsupported_keywords() {
+        let schema = json!({"type": "string", "pattern": "^a+$"});
+        assert!(json_schema_grammar(&schema).is_err());
+        let schema =
+            json!({"type": "object", "properties": {"n": {"type": "integer", "minimum": 1}}});
+        assert!(json_schema_grammar(&schema).is_err());
+    }
+}
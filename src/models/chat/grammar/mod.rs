@@ -0,0 +1,526 @@
+mod gbnf;
+mod json;
+mod json_schema;
+mod regex;
+mod tool_call;
+mod trie;
+
+pub(crate) use gbnf::gbnf_grammar;
+pub(crate) use json::json_object_grammar;
+pub(crate) use json_schema::json_schema_grammar;
+pub(crate) use regex::regex_grammar;
+pub(crate) use tool_call::tool_call_grammar;
+pub(crate) use trie::TokenTrie;
+
+use anyhow::Result;
+use regex_syntax::utf8::Utf8Sequences;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub(crate) type RuleId = usize;
+
+/// A symbol on the right hand side of a grammar rule.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Symbol {
+    /// A single byte within one of the inclusive ranges.
+    Bytes(Vec<(u8, u8)>),
+    Rule(RuleId),
+}
+
+impl Symbol {
+    pub(crate) fn byte(byte: u8) -> Self {
+        Self::Bytes(vec![(byte, byte)])
+    }
+}
+
+/// Builds a context free grammar over bytes, rule by rule.
+///
+/// Every rule is a list of alternatives, every alternative a sequence of symbols. Rules are
+/// referenced by id, so they can be used before they are defined, which recursive grammars need.
+#[derive(Debug, Default)]
+pub(crate) struct GrammarBuilder {
+    rules: Vec<Option<Vec<Vec<Symbol>>>>,
+    names: Vec<String>,
+    ids: HashMap<String, RuleId>,
+    char_classes: HashMap<(Vec<(char, char)>, bool), RuleId>,
+}
+
+impl GrammarBuilder {
+    /// The id of the rule called `name`, reserving it if it does not exist yet.
+    pub(crate) fn rule(&mut self, name: &str) -> RuleId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.rules.len();
+        self.rules.push(None);
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    pub(crate) fn define(&mut self, rule: RuleId, alternatives: Vec<Vec<Symbol>>) {
+        self.rules[rule] = Some(alternatives);
+    }
+
+    /// Define the rule called `name` and return its id.
+    pub(crate) fn add(&mut self, name: &str, alternatives: Vec<Vec<Symbol>>) -> RuleId {
+        let rule = self.rule(name);
+        self.define(rule, alternatives);
+        rule
+    }
+
+    /// Define a helper rule under a name derived from `name` that is not taken yet.
+    pub(crate) fn fresh(&mut self, name: &str, alternatives: Vec<Vec<Symbol>>) -> RuleId {
+        let rule = self.reserve(name);
+        self.define(rule, alternatives);
+        rule
+    }
+
+    /// Reserve a helper rule under a name derived from `name` that is not taken yet.
+    pub(crate) fn reserve(&mut self, name: &str) -> RuleId {
+        let mut unique = name.to_string();
+        let mut suffix = 1;
+        while self.ids.contains_key(&unique) {
+            unique = format!("{name}-{suffix}");
+            suffix += 1;
+        }
+        self.rule(&unique)
+    }
+
+    /// The UTF-8 bytes of `text`, one symbol per byte.
+    pub(crate) fn literal(text: &str) -> Vec<Symbol> {
+        text.bytes().map(Symbol::byte).collect()
+    }
+
+    /// A single character within (or, if `negated`, outside of) the inclusive ranges.
+    pub(crate) fn char_class(&mut self, ranges: &[(char, char)], negated: bool) -> Symbol {
+        let key = (ranges.to_vec(), negated);
+        if let Some(rule) = self.char_classes.get(&key) {
+            return Symbol::Rule(*rule);
+        }
+        let ranges = if negated {
+            negate(ranges)
+        } else {
+            ranges.to_vec()
+        };
+        let mut ascii = vec![];
+        let mut alternatives = vec![];
+        for (start, end) in ranges {
+            for sequence in Utf8Sequences::new(start, end) {
+                match sequence.as_slice() {
+                    [range] => ascii.push((range.start, range.end)),
+                    ranges => alternatives.push(
+                        ranges
+                            .iter()
+                            .map(|range| Symbol::Bytes(vec![(range.start, range.end)]))
+                            .collect(),
+                    ),
+                }
+            }
+        }
+        if !ascii.is_empty() {
+            alternatives.insert(0, vec![Symbol::Bytes(ascii)]);
+        }
+        let rule = self.fresh("_char", alternatives);
+        self.char_classes.insert(key, rule);
+        Symbol::Rule(rule)
+    }
+
+    /// `symbols` repeated between `min` and `max` times, or any number of times above `min`.
+    pub(crate) fn repeat(
+        &mut self,
+        symbols: Vec<Symbol>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Vec<Symbol> {
+        let mut sequence: Vec<Symbol> = (0..min).flat_map(|_| symbols.clone()).collect();
+        match max {
+            None => {
+                let rule = self.reserve("_repeat");
+                let mut repeated = symbols;
+                repeated.push(Symbol::Rule(rule));
+                self.define(rule, vec![repeated, vec![]]);
+                sequence.push(Symbol::Rule(rule));
+            }
+            Some(max) if max > min => {
+                let mut optional = self.fresh("_optional", vec![symbols.clone(), vec![]]);
+                for _ in min + 1..max {
+                    let mut repeated = symbols.clone();
+                    repeated.push(Symbol::Rule(optional));
+                    optional = self.fresh("_optional", vec![repeated, vec![]]);
+                }
+                sequence.push(Symbol::Rule(optional));
+            }
+            Some(_) => {}
+        }
+        sequence
+    }
+
+    /// Check and flatten the grammar, starting at `root`.
+    pub(crate) fn build(self, root: RuleId) -> Result<Grammar> {
+        let rules = self
+            .rules
+            .into_iter()
+            .zip(&self.names)
+            .map(|(rule, name)| rule.ok_or_else(|| anyhow::anyhow!("undefined rule: {name}")))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(rule) = left_recursive_rule(&rules) {
+            anyhow::bail!("left recursion in rule: {}", self.names[rule]);
+        }
+        let mut elements = vec![];
+        let mut starts = vec![];
+        for alternatives in &rules {
+            let mut rule_starts = vec![];
+            for alternative in alternatives {
+                rule_starts.push(elements.len() as u32);
+                for symbol in alternative {
+                    elements.push(match symbol {
+                        Symbol::Bytes(ranges) => Element::Bytes(ByteSet::new(ranges)),
+                        Symbol::Rule(rule) => Element::Rule(*rule as u32),
+                    });
+                }
+                elements.push(Element::End);
+            }
+            starts.push(rule_starts);
+        }
+        Ok(Grammar {
+            elements,
+            rules: starts,
+            root,
+        })
+    }
+}
+
+/// Exactly one of the `choices`.
+pub(crate) fn choice_grammar(choices: &[String]) -> Result<Grammar> {
+    if choices.is_empty() {
+        anyhow::bail!("no choices given");
+    }
+    let mut builder = GrammarBuilder::default();
+    let alternatives = choices
+        .iter()
+        .map(|choice| GrammarBuilder::literal(choice))
+        .collect();
+    let root = builder.add("root", alternatives);
+    builder.build(root)
+}
+
+/// The complement of the character ranges, skipping surrogates.
+fn negate(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort();
+    let mut negated = vec![];
+    let mut next = 0u32;
+    for (start, end) in sorted {
+        if (start as u32) > next {
+            negated.push((next, start as u32 - 1));
+        }
+        next = next.max(end as u32 + 1);
+    }
+    negated.push((next, char::MAX as u32));
+    negated
+        .into_iter()
+        .flat_map(|(start, end)| {
+            // char::from_u32 rejects surrogates, so split ranges around them
+            [(start, end.min(0xD7FF)), (start.max(0xE000), end)]
+        })
+        .filter_map(|(start, end)| Some((char::from_u32(start)?, char::from_u32(end)?)))
+        .filter(|(start, end)| start <= end)
+        .collect()
+}
+
+/// Find a rule that can reach itself without consuming any input, with symbols left to match
+/// after it.
+///
+/// A rule that only comes back to itself at the end of its alternatives, like the repetition of
+/// something that may be empty, returns to the same position, where expanding stops.
+fn left_recursive_rule(rules: &[Vec<Vec<Symbol>>]) -> Option<RuleId> {
+    let mut nullable = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (rule, alternatives) in rules.iter().enumerate() {
+            if !nullable[rule]
+                && alternatives.iter().any(|alternative| {
+                    alternative
+                        .iter()
+                        .all(|symbol| matches!(symbol, Symbol::Rule(rule) if nullable[*rule]))
+                })
+            {
+                nullable[rule] = true;
+                changed = true;
+            }
+        }
+    }
+    // rules each rule can start with, and whether symbols follow them
+    let leading: Vec<Vec<(RuleId, bool)>> = rules
+        .iter()
+        .map(|alternatives| {
+            let mut leading = vec![];
+            for alternative in alternatives {
+                for (index, symbol) in alternative.iter().enumerate() {
+                    match symbol {
+                        Symbol::Rule(rule) => {
+                            leading.push((*rule, index + 1 < alternative.len()));
+                            if !nullable[*rule] {
+                                break;
+                            }
+                        }
+                        Symbol::Bytes(_) => break,
+                    }
+                }
+            }
+            leading
+        })
+        .collect();
+    (0..rules.len()).find(|start| {
+        // rules reached, and whether any symbols were left behind on the way
+        let mut seen = vec![[false; 2]; rules.len()];
+        let mut pending = leading[*start].clone();
+        while let Some((rule, followed)) = pending.pop() {
+            if rule == *start && followed {
+                return true;
+            }
+            if !std::mem::replace(&mut seen[rule][followed as usize], true) {
+                pending.extend(
+                    leading[rule]
+                        .iter()
+                        .map(|(next, next_followed)| (*next, followed || *next_followed)),
+                );
+            }
+        }
+        false
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn new(ranges: &[(u8, u8)]) -> Self {
+        let mut set = [0u64; 4];
+        for (start, end) in ranges {
+            for byte in *start..=*end {
+                set[byte as usize / 64] |= 1 << (byte % 64);
+            }
+        }
+        Self(set)
+    }
+
+    fn contains(&self, byte: u8) -> bool {
+        self.0[byte as usize / 64] & (1 << (byte % 64)) != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Element {
+    Bytes(ByteSet),
+    Rule(u32),
+    /// End of an alternative.
+    End,
+}
+
+/// A compiled grammar, the alternatives of all rules laid out in a single element array.
+#[derive(Debug)]
+pub(crate) struct Grammar {
+    elements: Vec<Element>,
+    /// Start of every alternative of every rule.
+    rules: Vec<Vec<u32>>,
+    root: RuleId,
+}
+
+/// Positions in the grammar, the innermost one last.
+type Stack = Vec<u32>;
+
+impl Grammar {
+    /// Push the alternatives of rule references on top of `stack` until every resulting stack
+    /// expects a byte or is empty, which means the input so far is complete.
+    ///
+    /// `expanded` holds the stacks whose rule was expanded already, coming back to one of them
+    /// without consuming input, such as an empty iteration of a repetition, adds nothing new.
+    fn expand(&self, mut stack: Stack, stacks: &mut Vec<Stack>, expanded: &mut HashSet<Stack>) {
+        loop {
+            let Some(position) = stack.last().copied() else {
+                stacks.push(stack);
+                return;
+            };
+            match self.elements[position as usize] {
+                Element::Bytes(_) => {
+                    stacks.push(stack);
+                    return;
+                }
+                Element::End => {
+                    stack.pop();
+                }
+                Element::Rule(rule) => {
+                    if !expanded.insert(stack.clone()) {
+                        return;
+                    }
+                    stack.pop();
+                    if self.elements[position as usize + 1] != Element::End {
+                        stack.push(position + 1);
+                    }
+                    for start in &self.rules[rule as usize] {
+                        let mut alternative = stack.clone();
+                        alternative.push(*start);
+                        self.expand(alternative, stacks, expanded);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn advance(&self, stacks: &[Stack], byte: u8) -> Vec<Stack> {
+        let mut advanced = vec![];
+        let mut expanded = HashSet::new();
+        for stack in stacks {
+            let Some(position) = stack.last().copied() else {
+                continue;
+            };
+            let Element::Bytes(set) = self.elements[position as usize] else {
+                continue;
+            };
+            if set.contains(byte) {
+                let mut stack = stack.clone();
+                stack.pop();
+                stack.push(position + 1);
+                self.expand(stack, &mut advanced, &mut expanded);
+            }
+        }
+        advanced.sort_unstable();
+        advanced.dedup();
+        advanced
+    }
+}
+
+/// The position of a generation within a grammar.
+#[derive(Debug, Clone)]
+pub(crate) struct GrammarState {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Stack>,
+}
+
+impl GrammarState {
+    pub(crate) fn new(grammar: Arc<Grammar>) -> Self {
+        let mut stacks = vec![];
+        let mut expanded = HashSet::new();
+        for start in &grammar.rules[grammar.root] {
+            grammar.expand(vec![*start], &mut stacks, &mut expanded);
+        }
+        stacks.sort_unstable();
+        stacks.dedup();
+        Self { grammar, stacks }
+    }
+
+    fn consume(&self, bytes: &[u8]) -> Option<Vec<Stack>> {
+        let mut stacks = self.stacks.clone();
+        for byte in bytes {
+            stacks = self.grammar.advance(&stacks, *byte);
+            if stacks.is_empty() {
+                return None;
+            }
+        }
+        Some(stacks)
+    }
+
+    /// Whether the grammar allows `bytes` to follow the input so far.
+    pub(crate) fn accepts(&self, bytes: &[u8]) -> bool {
+        self.consume(bytes).is_some()
+    }
+
+    /// Consume `bytes`, returns `false` and leaves the state unchanged if they are not allowed.
+    pub(crate) fn accept(&mut self, bytes: &[u8]) -> bool {
+        match self.consume(bytes) {
+            Some(stacks) => {
+                self.stacks = stacks;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the input so far is a complete sentence of the grammar.
+    pub(crate) fn is_accepting(&self) -> bool {
+        self.stacks.iter().any(Vec::is_empty)
+    }
+
+    /// Whether the input is complete and nothing may follow it.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.stacks.iter().all(Vec::is_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choice_grammar, GrammarBuilder, GrammarState, Symbol};
+    use std::sync::Arc;
+
+    #[test]
+    fn match_bytes() {
+        // root ::= "a" [^!a]* "!"?
+        let mut builder = GrammarBuilder::default();
+        let mut root = GrammarBuilder::literal("a");
+        let other = builder.char_class(&[('!', '!'), ('a', 'a')], true);
+        root.extend(builder.repeat(vec![other], 0, None));
+        root.extend(builder.repeat(vec![Symbol::byte(b'!')], 0, Some(1)));
+        let root = builder.add("root", vec![root]);
+        let grammar = Arc::new(builder.build(root).unwrap());
+        let mut state = GrammarState::new(grammar);
+        assert!(!state.is_accepting());
+        assert!(!state.accepts(b"b"));
+        assert!(state.accept("ab好".as_bytes()));
+        assert!(state.is_accepting());
+        assert!(!state.is_finished());
+        assert!(!state.accepts(b"a"));
+        assert!(state.accepts(&"好".as_bytes()[..1]));
+        assert!(state.accept(b"!"));
+        assert!(state.is_finished());
+        assert!(!state.accepts(b"b"));
+    }
+
+    #[test]
+    fn reject_left_recursion() {
+        // root ::= root "a" | "a"
+        let mut builder = GrammarBuilder::default();
+        let root = builder.rule("root");
+        builder.define(
+            root,
+            vec![
+                vec![Symbol::Rule(root), Symbol::byte(b'a')],
+                GrammarBuilder::literal("a"),
+            ],
+        );
+        assert!(builder.build(root).is_err());
+    }
+
+    #[test]
+    fn repeat_nullable() {
+        // root ::= ("a"?)* "b"
+        let mut builder = GrammarBuilder::default();
+        let optional = builder.repeat(vec![Symbol::byte(b'a')], 0, Some(1));
+        let mut root = builder.repeat(optional, 0, None);
+        root.push(Symbol::byte(b'b'));
+        let root = builder.add("root", vec![root]);
+        let state = GrammarState::new(Arc::new(builder.build(root).unwrap()));
+        assert!(state.accepts(b"b"));
+        let mut repeated = state.clone();
+        assert!(repeated.accept(b"aaab"));
+        assert!(repeated.is_finished());
+        assert!(!state.accepts(b"c"));
+    }
+
+    #[test]
+    fn match_choice() {
+        let choices = ["yes".to_string(), "yesterday".to_string(), "no".to_string()];
+        let state = GrammarState::new(Arc::new(choice_grammar(&choices).unwrap()));
+        let mut yes = state.clone();
+        assert!(yes.accept(b"yes"));
+        assert!(yes.is_accepting());
+        assert!(!yes.is_finished());
+        assert!(yes.accept(b"terday"));
+        assert!(yes.is_finished());
+        assert!(!state.accepts(b"maybe"));
+        assert!(choice_grammar(&[]).is_err());
+    }
+}
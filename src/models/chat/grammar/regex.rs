@@ -0,0 +1,86 @@
+use crate::models::chat::grammar::{Grammar, GrammarBuilder, Symbol};
+use anyhow::{bail, Result};
+use regex_syntax::hir::{Class, Hir, HirKind, Look};
+
+/// Text that matches the regular expression as a whole.
+///
+/// Anchors at the start and end of lines are accepted but have no effect, since the whole output
+/// has to match anyway. Other look-around assertions, such as word boundaries, are rejected.
+pub(crate) fn regex_grammar(pattern: &str) -> Result<Grammar> {
+    let hir = regex_syntax::parse(pattern)?;
+    let mut builder = GrammarBuilder::default();
+    let symbols = compile(&mut builder, &hir)?;
+    let root = builder.add("root", vec![symbols]);
+    builder.build(root)
+}
+
+fn compile(builder: &mut GrammarBuilder, hir: &Hir) -> Result<Vec<Symbol>> {
+    Ok(match hir.kind() {
+        HirKind::Empty => vec![],
+        HirKind::Literal(literal) => literal.0.iter().copied().map(Symbol::byte).collect(),
+        HirKind::Class(Class::Unicode(class)) => {
+            let ranges: Vec<_> = class
+                .iter()
+                .map(|range| (range.start(), range.end()))
+                .collect();
+            vec![builder.char_class(&ranges, false)]
+        }
+        HirKind::Class(Class::Bytes(class)) => vec![Symbol::Bytes(
+            class
+                .iter()
+                .map(|range| (range.start(), range.end()))
+                .collect(),
+        )],
+        HirKind::Look(
+            Look::Start | Look::End | Look::StartLF | Look::EndLF | Look::StartCRLF | Look::EndCRLF,
+        ) => vec![],
+        HirKind::Look(look) => bail!("unsupported regex assertion: {look:?}"),
+        HirKind::Repetition(repetition) => {
+            let symbols = compile(builder, &repetition.sub)?;
+            let min = repetition.min as usize;
+            let max = repetition.max.map(|max| max as usize);
+            builder.repeat(symbols, min, max)
+        }
+        HirKind::Capture(capture) => compile(builder, &capture.sub)?,
+        HirKind::Concat(hirs) => {
+            let mut symbols = vec![];
+            for hir in hirs {
+                symbols.extend(compile(builder, hir)?);
+            }
+            symbols
+        }
+        HirKind::Alternation(hirs) => {
+            let alternatives = hirs
+                .iter()
+                .map(|hir| compile(builder, hir))
+                .collect::<Result<_>>()?;
+            vec![Symbol::Rule(builder.fresh("_alternation", alternatives))]
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::regex_grammar;
+    use crate::models::chat::grammar::GrammarState;
+    use std::sync::Arc;
+
+    #[test]
+    fn match_regex() {
+        let grammar = Arc::new(regex_grammar(r"^(\d{3}-)?\d{4} (yes|no|ça)$").unwrap());
+        let state = GrammarState::new(grammar);
+        for valid in ["555-1234 yes", "1234 no", "1234 ça"] {
+            let mut state = state.clone();
+            assert!(state.accept(valid.as_bytes()), "{valid}");
+            assert!(state.is_finished(), "{valid}");
+        }
+        for invalid in ["55-1234 yes", "1234 maybe", "1234", "1234 yes!"] {
+            let mut state = state.clone();
+            assert!(
+                !(state.accept(invalid.as_bytes()) && state.is_accepting()),
+                "{invalid}"
+            );
+        }
+        assert!(regex_grammar(r"\bword\b").is_err());
+    }
+}
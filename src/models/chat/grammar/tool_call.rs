@@ -0,0 +1,141 @@
+use crate::models::chat::grammar::json::JsonRules;
+use crate::models::chat::grammar::json_schema::compile_schema;
+use crate::models::chat::grammar::{Grammar, GrammarBuilder, Symbol};
+use crate::models::chat::tool_calls::{ToolSyntax, CALL_END, CALL_START};
+use crate::types::chat::completion::FunctionInfo;
+use anyhow::Result;
+use serde_json::{json, Value};
+
+/// Calls to one of the `functions`, written in the `syntax` the tool call parser expects them in,
+/// with arguments that validate against the parameters schema of the called function.
+///
+/// With `parallel` set, one or more calls are generated, otherwise exactly one. The special token
+/// that starts the calls of some syntaxes is left to the model, the grammar starts after it.
+pub(crate) fn tool_call_grammar(
+    syntax: ToolSyntax,
+    functions: &[&FunctionInfo],
+    parallel: bool,
+) -> Result<Grammar> {
+    if functions.is_empty() {
+        anyhow::bail!("no functions to call");
+    }
+    let mut builder = GrammarBuilder::default();
+    let json = JsonRules::new(&mut builder);
+    let empty = json!({"type": "object", "properties": {}});
+    let (start, end) = match syntax {
+        ToolSyntax::Hermes => (format!("{CALL_START}\n{{"), format!("}}\n{CALL_END}")),
+        ToolSyntax::Llama3 | ToolSyntax::Mistral => ("{".to_string(), "}".to_string()),
+    };
+    let mut calls = vec![];
+    for function in functions {
+        let parameters = function.parameters.as_ref().unwrap_or(&empty);
+        let arguments = compile_schema(&mut builder, json, parameters, &function.name)
+            .map_err(|e| anyhow::anyhow!("invalid parameters of {}: {}", function.name, e))?;
+        let name = Value::String(function.name.clone());
+        calls.push(
+            [
+                GrammarBuilder::literal(&format!(
+                    "{start}\"name\": {name}, \"{}\": ",
+                    syntax.arguments_key()
+                )),
+                vec![arguments],
+                GrammarBuilder::literal(&end),
+            ]
+            .concat(),
+        );
+    }
+    let call = Symbol::Rule(builder.fresh("call", calls));
+    let separator = match syntax {
+        ToolSyntax::Hermes | ToolSyntax::Llama3 => "\n",
+        ToolSyntax::Mistral => ", ",
+    };
+    let mut root = vec![call.clone()];
+    if parallel {
+        let next = [GrammarBuilder::literal(separator), vec![call]].concat();
+        root.extend(builder.repeat(next, 0, None));
+    }
+    if syntax == ToolSyntax::Mistral {
+        root = [
+            GrammarBuilder::literal("["),
+            root,
+            GrammarBuilder::literal("]"),
+        ]
+        .concat();
+    }
+    let root = builder.fresh("root", vec![root]);
+    builder.build(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tool_call_grammar;
+    use crate::models::chat::grammar::GrammarState;
+    use crate::models::chat::tool_calls::{parse_tool_calls, ToolSyntax};
+    use crate::types::chat::completion::Tool;
+    use std::sync::Arc;
+
+    #[test]
+    fn forced_calls() {
+        let tools: Vec<Tool> = serde_json::from_str(
+            r#"[
+                {"type": "function", "function": {"name": "weather", "parameters": {
+                    "type": "object",
+                    "properties": {"city": {"type": "string"}},
+                    "required": ["city"]
+                }}},
+                {"type": "function", "function": {"name": "time"}}
+            ]"#,
+        )
+        .unwrap();
+        let functions: Vec<_> = tools.iter().map(|tool| &tool.function).collect();
+        let weather = "<tool_call>\n{\"name\": \"weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>";
+        let time = "<tool_call>\n{\"name\": \"time\", \"arguments\": {}}\n</tool_call>";
+
+        let state = GrammarState::new(Arc::new(
+            tool_call_grammar(ToolSyntax::Hermes, &functions, true).unwrap(),
+        ));
+        let mut calls = state.clone();
+        assert!(calls.accept(weather.as_bytes()));
+        assert!(calls.is_accepting());
+        assert!(calls.accept(format!("\n{time}").as_bytes()));
+        assert!(calls.is_accepting());
+        for invalid in [
+            "<tool_call>\n{\"name\": \"weather\", \"arguments\": {}}",
+            "<tool_call>\n{\"name\": \"other\"",
+            "<tool_call>\n{\"name\": \"time\", \"arguments\": {\"a\": 1}}",
+        ] {
+            assert!(!state.accepts(invalid.as_bytes()), "{invalid}");
+        }
+
+        let state = GrammarState::new(Arc::new(
+            tool_call_grammar(ToolSyntax::Hermes, &functions[..1], false).unwrap(),
+        ));
+        let mut call = state.clone();
+        assert!(call.accept(weather.as_bytes()));
+        assert!(call.is_finished());
+        assert!(!state.accepts(&time.as_bytes()[..25]));
+    }
+
+    #[test]
+    fn calls_in_the_syntax_of_the_format() {
+        let tools: Vec<Tool> =
+            serde_json::from_str(r#"[{"type": "function", "function": {"name": "time"}}]"#)
+                .unwrap();
+        let functions: Vec<_> = tools.iter().map(|tool| &tool.function).collect();
+        for (syntax, text) in [
+            (ToolSyntax::Llama3, "{\"name\": \"time\", \"parameters\": {}}"),
+            (
+                ToolSyntax::Mistral,
+                "[{\"name\": \"time\", \"arguments\": {}}, {\"name\": \"time\", \"arguments\": {}}]",
+            ),
+        ] {
+            let grammar = tool_call_grammar(syntax, &functions, true).unwrap();
+            let mut state = GrammarState::new(Arc::new(grammar));
+            assert!(state.accept(text.as_bytes()), "{text}");
+            assert!(state.is_accepting());
+            let (content, calls) = parse_tool_calls(text, syntax);
+            assert_eq!(content, None);
+            assert_eq!(calls[0].function.name, "time");
+        }
+    }
+}
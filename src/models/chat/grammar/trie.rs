@@ -0,0 +1,139 @@
+use crate::models::chat::grammar::{Grammar, GrammarState, Stack};
+use std::collections::HashMap;
+
+/// Prefix tree of the bytes of all tokens a grammar may produce.
+///
+/// Checking every token against a grammar one by one repeats the work for the bytes tokens have
+/// in common. Walking the tree advances the grammar once per shared prefix, and whole subtrees
+/// are skipped as soon as their prefix is rejected.
+///
+/// Within strings and other repetitions most bytes lead back to the same grammar state, so the
+/// states met during a walk are interned and the transitions between them are memoized.
+#[derive(Debug, Default)]
+pub(crate) struct TokenTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Child nodes by byte, sorted by byte.
+    children: Vec<(u8, usize)>,
+    /// Tokens whose bytes end at this node.
+    tokens: Vec<u32>,
+}
+
+impl TokenTrie {
+    pub(crate) fn new<'a>(tokens: impl IntoIterator<Item = (u32, &'a [u8])>) -> Self {
+        let mut nodes = vec![Node::default()];
+        for (token, bytes) in tokens {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for byte in bytes {
+                node = match nodes[node]
+                    .children
+                    .binary_search_by_key(byte, |(byte, _)| *byte)
+                {
+                    Ok(index) => nodes[node].children[index].1,
+                    Err(index) => {
+                        let child = nodes.len();
+                        nodes.push(Node::default());
+                        nodes[node].children.insert(index, (*byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(token);
+        }
+        Self { nodes }
+    }
+
+    /// All tokens whose bytes the grammar accepts in the given state.
+    pub(crate) fn allowed_tokens(&self, state: &GrammarState) -> Vec<u32> {
+        let mut transitions = Transitions {
+            grammar: &state.grammar,
+            states: vec![state.stacks.clone()],
+            ids: HashMap::from([(state.stacks.clone(), 0)]),
+            next: HashMap::new(),
+        };
+        let mut allowed = vec![];
+        self.collect(0, 0, &mut transitions, &mut allowed);
+        allowed
+    }
+
+    fn collect(
+        &self,
+        node: usize,
+        state: usize,
+        transitions: &mut Transitions,
+        allowed: &mut Vec<u32>,
+    ) {
+        for (byte, child) in &self.nodes[node].children {
+            let Some(next) = transitions.advance(state, *byte) else {
+                continue;
+            };
+            allowed.extend(&self.nodes[*child].tokens);
+            self.collect(*child, next, transitions, allowed);
+        }
+    }
+}
+
+/// Interned grammar states and the memoized transitions between them.
+struct Transitions<'a> {
+    grammar: &'a Grammar,
+    states: Vec<Vec<Stack>>,
+    ids: HashMap<Vec<Stack>, usize>,
+    next: HashMap<(usize, u8), Option<usize>>,
+}
+
+impl Transitions<'_> {
+    fn advance(&mut self, state: usize, byte: u8) -> Option<usize> {
+        if let Some(next) = self.next.get(&(state, byte)) {
+            return *next;
+        }
+        let stacks = self.grammar.advance(&self.states[state], byte);
+        let next = if stacks.is_empty() {
+            None
+        } else {
+            let id = match self.ids.get(&stacks) {
+                Some(id) => *id,
+                None => {
+                    let id = self.states.len();
+                    self.ids.insert(stacks.clone(), id);
+                    self.states.push(stacks);
+                    id
+                }
+            };
+            Some(id)
+        };
+        self.next.insert((state, byte), next);
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenTrie;
+    use crate::models::chat::grammar::{json_object_grammar, GrammarState};
+    use std::sync::Arc;
+
+    #[test]
+    fn allowed_tokens() {
+        let vocab: [&[u8]; 6] = [b"{", b"{\"", b" {", b"}", b"a", b""];
+        let trie = TokenTrie::new(
+            vocab
+                .iter()
+                .enumerate()
+                .map(|(id, bytes)| (id as u32, *bytes)),
+        );
+        let mut state = GrammarState::new(Arc::new(json_object_grammar().unwrap()));
+        let mut allowed = trie.allowed_tokens(&state);
+        allowed.sort();
+        assert_eq!(allowed, vec![0, 1, 2]);
+        assert!(state.accept(b"{"));
+        let mut allowed = trie.allowed_tokens(&state);
+        allowed.sort();
+        assert_eq!(allowed, vec![3]);
+    }
+}
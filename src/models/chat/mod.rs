@@ -1,5 +1,18 @@
 pub(crate) mod chat_format;
+pub(crate) mod context;
+mod detokenizer;
+mod gguf_tokenizer;
+mod grammar;
 mod model;
+mod prefix_cache;
+mod sampling;
+mod scheduler;
+mod stop;
+mod text_completion;
+mod tool_calls;
 mod utils;
+mod vocab;
+mod weights;
 
 pub(crate) use model::{init_model, ChatModel};
+pub(crate) use weights::{Precision, QuantizationType};
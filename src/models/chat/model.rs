@@ -1,418 +1,965 @@
 use crate::configs::ChatModelConfig;
-use crate::models::chat::chat_format::ChatFormat;
-use crate::models::chat::utils::format_size;
-use crate::models::device::{device, token_id};
+use crate::models::chat::chat_format::{ChatFormat, JinjaTemplate, TemplateMetadata};
+use crate::models::chat::context::{drop_oldest_turn, ContextLengthExceeded, Truncation};
+use crate::models::chat::gguf_tokenizer::{eos_token_ids, tokenizer_from_gguf};
+use crate::models::chat::grammar::{
+    choice_grammar, gbnf_grammar, json_object_grammar, json_schema_grammar, regex_grammar,
+    tool_call_grammar, Grammar,
+};
+use crate::models::chat::sampling::Penalties;
+use crate::models::chat::scheduler::{GenerationParams, Scheduler, SequenceEvent};
+use crate::models::chat::tool_calls::{
+    parse_tool_calls, ToolCallEvent, ToolCallParser, ToolSyntax,
+};
+use crate::models::chat::utils::{format_size, Fnv1a};
+use crate::models::chat::weights::{
+    architecture, quantize_checkpoint, quantized_path, read_config, safetensors_files, ChatWeights,
+    HfConfig, Unquantized,
+};
+use crate::models::device::{device, device_name, token_id};
 use crate::types::chat::completion::{
-    AssistantMessage, ChatCompletionChoice, ChatResponseFormat, FinishReason,
+    AssistantMessage, ChatCompletionChoice, ChatCompletionChunkChoice, ChatCompletionDelta,
+    ChatCompletionLogprobs, ChatResponseFormat, FinishReason, FunctionCallDelta, Stop,
+    ToolCallDelta, ToolChoice, ToolChoiceMode, ToolType,
 };
 use crate::types::chat::{
     ChatCompletionRequest, ChatCompletionResponse, ChatCompletionResponseChunk,
 };
 use anyhow::{Error as E, Result};
-use candle_core::quantized::{ggml_file, gguf_file};
-use candle_core::{Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::quantized_llama::ModelWeights;
-use futures_util::{Stream, TryStreamExt};
+use candle_core::quantized::{ggml_file, gguf_file, GgmlDType};
+use candle_core::DType;
+use futures_util::{ready, Stream};
 use silent::prelude::{error, SSEEvent};
-use silent::{SilentError, StatusCode};
-use std::io::Write;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::sync::Arc;
 use tokenizers::Tokenizer;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Request fields that control sampling, shared by the chat and the text completion APIs.
+pub(crate) struct SamplingOptions<'a> {
+    pub(crate) n: Option<usize>,
+    pub(crate) stop: Option<&'a Stop>,
+    /// Defaults to all the room the prompt leaves in the context.
+    pub(crate) max_tokens: Option<usize>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_p: Option<f32>,
+    pub(crate) frequency_penalty: Option<f32>,
+    pub(crate) presence_penalty: Option<f32>,
+    pub(crate) repeat_penalty: Option<f32>,
+    pub(crate) repeat_last_n: Option<usize>,
+    pub(crate) logit_bias: Option<&'a HashMap<String, f32>>,
+    pub(crate) seed: Option<u64>,
+}
 
 #[derive(Clone, Debug)]
 pub(crate) struct ChatModel {
-    tokenizer: Tokenizer,
-    model: ModelWeights,
-    device: Device,
+    pub(crate) tokenizer: Tokenizer,
     seed: u64,
-    eos_token: u32,
-    chat_format: ChatFormat,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    pub(crate) chat_format: ChatFormat,
+    /// The template of the jinja chat format.
+    chat_template: Option<JinjaTemplate>,
+    /// Maximum number of tokens of the prompt and the completion together.
+    pub(crate) context_length: usize,
+    /// Type of most of the weights, such as Q4K.
+    pub(crate) quantization: String,
+    pub(crate) device: &'static str,
+    truncation: Truncation,
+    pub(crate) system_fingerprint: String,
+    pub(crate) scheduler: Scheduler,
+}
+
+/// A model as found on disk, with the headers that are read before the weights.
+enum ModelFile {
+    Gguf(std::fs::File, gguf_file::Content),
+    Ggml(std::fs::File),
+    /// A Hugging Face directory, with its config.json parsed and as text.
+    Safetensors(HfConfig, String),
 }
 
 pub(crate) struct ChatModelStream {
-    pub(crate) response: ChatCompletionResponse,
-    pub(crate) max_tokens: usize,
-    pub(crate) index: usize,
-    pub(crate) result: String,
-    next_token: u32,
-    device: Device,
-    eos_token: u32,
-    all_tokens: Vec<u32>,
-    sampled: usize,
-    model: ModelWeights,
-    tokenizer: Tokenizer,
-    logits_processor: LogitsProcessor,
-    is_json: bool,
-    start_post_prompt: Instant,
-    is_finished: bool,
+    response: ChatCompletionResponse,
+    receiver: UnboundedReceiver<SequenceEvent>,
+    /// Number of choices that have not finished yet.
+    remaining: usize,
+    logprobs: bool,
+    include_usage: bool,
+    /// Tool call parsers of the choices, if the model may call tools.
+    tool_calls: Option<Vec<ToolCallParser>>,
+    /// Serialized events waiting to be sent.
+    queue: VecDeque<String>,
+    done: bool,
+}
+
+impl ChatModelStream {
+    fn push_chunk(&mut self, choice: ChatCompletionChunkChoice) {
+        let chunk = ChatCompletionResponseChunk::from_response(&self.response, vec![choice]);
+        self.queue.push_back(serde_json::to_string(&chunk).unwrap());
+    }
+
+    /// Queue the usage chunk if requested and the `[DONE]` sentinel that ends the stream.
+    fn finish(&mut self) {
+        if self.include_usage {
+            let mut chunk = ChatCompletionResponseChunk::from_response(&self.response, vec![]);
+            chunk.usage = Some(self.response.usage.clone());
+            self.queue.push_back(serde_json::to_string(&chunk).unwrap());
+        }
+        self.queue.push_back("[DONE]".to_string());
+        self.done = true;
+    }
+
+    /// Queue an error event and the `[DONE]` sentinel, which end the stream early.
+    fn fail(&mut self, message: &str) {
+        self.queue.push_back(stream_error(message));
+        self.queue.push_back("[DONE]".to_string());
+        self.done = true;
+    }
+}
+
+/// Data of an event that reports a failed generation, shaped like the error bodies of the API.
+pub(crate) fn stream_error(message: &str) -> String {
+    serde_json::json!({
+        "error": {
+            "message": message,
+            "type": "server_error",
+            "param": null,
+            "code": null,
+        }
+    })
+    .to_string()
 }
 
 impl Stream for ChatModelStream {
     type Item = silent::Result<SSEEvent>;
 
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        if self.index >= self.max_tokens || self.is_finished {
-            std::task::Poll::Ready(None)
-        } else {
-            let chunk: silent::Result<ChatCompletionResponseChunk> = {
-                let index_pos = self.response.usage.prompt_tokens + self.index;
-                let input = Tensor::new(&[self.next_token], &self.device)
-                    .map_err(|e| {
-                        SilentError::business_error(
-                            StatusCode::BAD_REQUEST,
-                            format!("failed to create input tensor: {}", e),
-                        )
-                    })?
-                    .unsqueeze(0)
-                    .map_err(|e| {
-                        SilentError::business_error(
-                            StatusCode::BAD_REQUEST,
-                            format!("failed to unsqueeze input tensor: {}", e),
-                        )
-                    })?;
-                let logits = self.model.forward(&input, index_pos).map_err(|e| {
-                    SilentError::business_error(
-                        StatusCode::BAD_REQUEST,
-                        format!("failed to forward model: {}", e),
-                    )
-                })?;
-                let logits = logits.squeeze(0).map_err(|e| {
-                    SilentError::business_error(
-                        StatusCode::BAD_REQUEST,
-                        format!("failed to squeeze logits: {}", e),
-                    )
-                })?;
-                let logits = {
-                    let start_at = self.all_tokens.len().saturating_sub(64);
-                    candle_transformers::utils::apply_repeat_penalty(
-                        &logits,
-                        1.1,
-                        &self.all_tokens[start_at..],
-                    )
-                    .map_err(|e| {
-                        SilentError::business_error(
-                            StatusCode::BAD_REQUEST,
-                            format!("failed to apply repeat penalty: {}", e),
-                        )
-                    })?
-                };
-                let next_token = self.logits_processor.sample(&logits).map_err(|e| {
-                    SilentError::business_error(
-                        StatusCode::BAD_REQUEST,
-                        format!("failed to sample next token: {}", e),
-                    )
-                })?;
-                self.next_token = next_token;
-                self.all_tokens.push(next_token);
-                let next_str = self.tokenizer.decode(&[next_token], true).map_err(|e| {
-                    SilentError::business_error(
-                        StatusCode::BAD_REQUEST,
-                        format!("failed to decode next token: {}", e),
-                    )
-                })?;
-                self.sampled += 1;
-                if self.next_token == self.eos_token {
-                    self.is_finished = true;
-                    Ok(ChatCompletionResponseChunk::from_response(
-                        &self.response,
-                        vec![ChatCompletionChoice {
-                            finish_reason: Default::default(),
-                            index: 0,
-                            message: AssistantMessage {
-                                content: None,
-                                name: None,
-                                tool_calls: vec![],
-                            },
-                        }],
-                    ))
-                } else {
-                    println!("next_str: {}", next_str);
-                    self.result.push_str(&next_str);
-                    self.index += 1;
-                    Ok(ChatCompletionResponseChunk::from_response(
-                        &self.response,
-                        vec![ChatCompletionChoice {
-                            finish_reason: FinishReason::Null,
-                            index: 0,
-                            message: AssistantMessage {
-                                content: Some(next_str),
-                                name: None,
-                                tool_calls: vec![],
-                            },
-                        }],
-                    ))
+        let this = self.get_mut();
+        loop {
+            if let Some(data) = this.queue.pop_front() {
+                return std::task::Poll::Ready(Some(Ok(SSEEvent::default().data(data))));
+            }
+            if this.done {
+                return std::task::Poll::Ready(None);
+            }
+            if this.remaining == 0 {
+                this.finish();
+                continue;
+            }
+            match ready!(this.receiver.poll_recv(cx)) {
+                Some(SequenceEvent::Token {
+                    index,
+                    text,
+                    logprobs,
+                }) => {
+                    let delta = match this.tool_calls.as_mut() {
+                        Some(parsers) => tool_call_delta(parsers[index].push(&text)),
+                        None => ChatCompletionDelta {
+                            content: Some(text),
+                            ..Default::default()
+                        },
+                    };
+                    if delta.content.is_none() && delta.tool_calls.is_empty() && logprobs.is_empty()
+                    {
+                        continue;
+                    }
+                    let logprobs = this
+                        .logprobs
+                        .then_some(ChatCompletionLogprobs { content: logprobs });
+                    this.push_chunk(ChatCompletionChunkChoice {
+                        index,
+                        delta,
+                        logprobs,
+                        finish_reason: None,
+                    });
                 }
-            };
-            match chunk {
-                Ok(chunk) => std::task::Poll::Ready(Some(Ok(
-                    SSEEvent::default().data(serde_json::to_string(&chunk).unwrap())
-                ))),
-                Err(e) => {
-                    error!("failed to serialize chunk: {}", e);
-                    std::task::Poll::Ready(None)
+                Some(SequenceEvent::Finished {
+                    index,
+                    reason,
+                    completion_tokens,
+                    cached_tokens,
+                }) => {
+                    this.remaining -= 1;
+                    let usage = &mut this.response.usage;
+                    usage.completion_tokens += completion_tokens;
+                    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+                    usage.prompt_tokens_details.cached_tokens = cached_tokens;
+                    let mut reason = reason;
+                    if let Some(parsers) = this.tool_calls.as_mut() {
+                        let parser = &mut parsers[index];
+                        let delta = tool_call_delta(parser.flush());
+                        if parser.has_calls() && reason == FinishReason::Stop {
+                            reason = FinishReason::ToolCalls;
+                        }
+                        if delta.content.is_some() || !delta.tool_calls.is_empty() {
+                            this.push_chunk(ChatCompletionChunkChoice {
+                                index,
+                                delta,
+                                logprobs: None,
+                                finish_reason: None,
+                            });
+                        }
+                    }
+                    this.push_chunk(ChatCompletionChunkChoice {
+                        index,
+                        delta: Default::default(),
+                        logprobs: None,
+                        finish_reason: Some(reason),
+                    });
                 }
+                // never requested by chat completions
+                Some(SequenceEvent::PromptLogprobs { .. }) => {}
+                Some(SequenceEvent::Error(e)) => {
+                    error!("failed to generate chunk: {}", e);
+                    this.fail(&e);
+                }
+                None => this.fail("chat model decode loop has stopped"),
             }
         }
     }
 }
 
 impl ChatModel {
-    pub(crate) fn handle(&self, request: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
-        let ChatCompletionRequest {
-            messages,
-            temperature,
-            top_p,
-            max_tokens,
-            ..
-        } = request;
-        let temperature = temperature.map(|temperature| temperature as f64);
-        let top_p = top_p.map(|top_p| top_p as f64);
-        let prompt = self.chat_format.format_messages(messages)?;
-        let tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?;
-        let mut model = self.model.clone();
-
-        let mut logits_processor = LogitsProcessor::new(self.seed, temperature, top_p);
-        let start_prompt_processing = std::time::Instant::now();
-        let mut response = ChatCompletionResponse::new(request.model.clone());
-        let mut choice = ChatCompletionChoice {
-            finish_reason: Default::default(),
-            index: 0,
-            message: AssistantMessage {
-                content: Some("".to_string()),
-                name: None,
-                tool_calls: vec![],
-            },
+    fn generation_params(&self, request: &ChatCompletionRequest) -> Result<GenerationParams> {
+        let top_logprobs = request.top_logprobs.unwrap_or(0);
+        if top_logprobs > 20 {
+            anyhow::bail!("top_logprobs must be between 0 and 20");
+        }
+        if request.top_logprobs.is_some() && request.logprobs != Some(true) {
+            anyhow::bail!("logprobs must be set to true when top_logprobs is used");
+        }
+        let options = SamplingOptions {
+            n: request.n,
+            stop: request.stop.as_ref(),
+            max_tokens: request.max_tokens,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            frequency_penalty: request.frequency_penalty,
+            presence_penalty: request.presence_penalty,
+            repeat_penalty: request.repeat_penalty,
+            repeat_last_n: request.repeat_last_n,
+            logit_bias: request.logit_bias.as_ref(),
+            seed: request.seed,
         };
-        let mut all_tokens = vec![];
-        let mut next_token = {
-            let input = Tensor::new(tokens.get_ids(), &self.device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, 0)?;
-            let logits = logits.squeeze(0)?;
-            logits_processor.sample(&logits)?
+        let mut params = self.sampling_params(self.prompt_tokens(request)?, options)?;
+        params.logprobs = request.logprobs.unwrap_or(false).then_some(top_logprobs);
+        params.grammar = self.grammar(request)?.map(Arc::new);
+        Ok(params)
+    }
+
+    /// Validate the sampling options of a request for the given prompt.
+    pub(crate) fn sampling_params(
+        &self,
+        prompt_tokens: Vec<u32>,
+        options: SamplingOptions,
+    ) -> Result<GenerationParams> {
+        let room = self.context_length - prompt_tokens.len();
+        let n = options.n.unwrap_or(1);
+        if !(1..=128).contains(&n) {
+            anyhow::bail!("n must be between 1 and 128");
+        }
+        let stop = options
+            .stop
+            .cloned()
+            .map(Stop::into_vec)
+            .unwrap_or_default();
+        if stop.len() > 4 {
+            anyhow::bail!("stop accepts at most 4 sequences");
+        }
+        let frequency_penalty = options.frequency_penalty.unwrap_or(0.);
+        let presence_penalty = options.presence_penalty.unwrap_or(0.);
+        if !(-2.0..=2.0).contains(&frequency_penalty) || !(-2.0..=2.0).contains(&presence_penalty) {
+            anyhow::bail!("frequency_penalty and presence_penalty must be between -2.0 and 2.0");
+        }
+        let penalties = Penalties {
+            repeat_penalty: options.repeat_penalty.unwrap_or(self.repeat_penalty),
+            repeat_last_n: options.repeat_last_n.unwrap_or(self.repeat_last_n),
+            frequency_penalty,
+            presence_penalty,
         };
-        all_tokens.push(next_token);
-        let prompt_dt = start_prompt_processing.elapsed();
-        let mut result = self.tokenizer.decode(&[next_token], true).map_err(E::msg)?;
-        let start_post_prompt = std::time::Instant::now();
-        let mut sampled = 0;
-        for index in 0..max_tokens.unwrap_or(4096) {
-            let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, tokens.len() + index)?;
-            let logits = logits.squeeze(0)?;
-            // let logits = if args.repeat_penalty == 1. {
-            //     logits
-            // } else {
-            //     let start_at = all_tokens.len().saturating_sub(args.repeat_last_n);
-            //     candle_transformers::utils::apply_repeat_penalty(
-            //         &logits,
-            //         args.repeat_penalty,
-            //         &all_tokens[start_at..],
-            //     )?
-            // };
-            let logits = {
-                let start_at = all_tokens.len().saturating_sub(64);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    1.1,
-                    &all_tokens[start_at..],
-                )?
-            };
-            next_token = logits_processor.sample(&logits)?;
-            all_tokens.push(next_token);
-            let next_str = self.tokenizer.decode(&[next_token], true).map_err(E::msg)?;
-            sampled += 1;
-            if next_token == self.eos_token {
-                break;
+        if penalties.repeat_penalty <= 0. {
+            anyhow::bail!("repeat_penalty must be positive");
+        }
+        let logit_bias = self.logit_bias(options.logit_bias)?;
+        Ok(GenerationParams {
+            prompt_tokens,
+            n,
+            stop,
+            max_tokens: options.max_tokens.unwrap_or(room).min(room),
+            temperature: options.temperature.map(|temperature| temperature as f64),
+            top_p: options.top_p.map(|top_p| top_p as f64),
+            penalties,
+            logit_bias,
+            logprobs: None,
+            prompt_logprobs: false,
+            grammar: None,
+            seed: options.seed.unwrap_or(self.seed),
+        })
+    }
+
+    /// The tokens of the prompt, which must leave room for at least one completion token.
+    fn prompt_tokens(&self, request: &ChatCompletionRequest) -> Result<Vec<u32>> {
+        let mut messages = request.messages.clone();
+        loop {
+            let prompt = match &self.chat_template {
+                Some(template) => template.render(messages.clone(), request.tools())?,
+                None => self
+                    .chat_format
+                    .format_messages(messages.clone(), request.tools())?,
             };
-            result.push_str(&next_str);
+            // chat templates write the bos token themselves
+            let add_special_tokens = self.chat_template.is_none();
+            let tokens = self
+                .tokenizer
+                .encode(prompt, add_special_tokens)
+                .map_err(E::msg)?;
+            if tokens.len() < self.context_length {
+                return Ok(tokens.get_ids().to_vec());
+            }
+            if self.truncation == Truncation::Reject || !drop_oldest_turn(&mut messages) {
+                return Err(ContextLengthExceeded {
+                    prompt_tokens: tokens.len(),
+                    context_length: self.context_length,
+                }
+                .into());
+            }
         }
-        let dt = start_post_prompt.elapsed();
-        println!(
-            "\n\n{:4} prompt tokens processed: {:.2} token/s",
-            tokens.len(),
-            tokens.len() as f64 / prompt_dt.as_secs_f64(),
-        );
-        println!(
-            "{sampled:4} tokens generated: {:.2} token/s",
-            sampled as f64 / dt.as_secs_f64(),
-        );
-        choice.message.content = Some(result);
-        response.choices.push(choice);
-        response.usage.prompt_tokens = tokens.len();
-        response.usage.completion_tokens = sampled;
-        response.usage.total_tokens = tokens.len() + sampled;
-        Ok(response)
     }
-    pub(crate) fn stream_handle(&self, request: ChatCompletionRequest) -> Result<ChatModelStream> {
-        let ChatCompletionRequest {
-            messages,
-            temperature,
-            top_p,
-            max_tokens,
-            response_format,
-            ..
-        } = request;
-        let is_json = match response_format.clone() {
-            None => true,
-            Some(format) => {
-                if format.r#type == ChatResponseFormat::Json {
-                    true
-                } else {
-                    false
+
+    /// How the model calls the tools of a request, which its chat format or template decides.
+    /// `None` without tools, and for formats that cannot call tools, which the prompt rejects.
+    fn tool_syntax(&self, request: &ChatCompletionRequest) -> Option<ToolSyntax> {
+        if request.tools().is_empty() {
+            return None;
+        }
+        match &self.chat_template {
+            Some(template) => template.tool_syntax(),
+            None => self.chat_format.tool_syntax(),
+        }
+    }
+
+    /// The grammar that the output is constrained to, if any.
+    fn grammar(&self, request: &ChatCompletionRequest) -> Result<Option<Grammar>> {
+        let response_format = request
+            .response_format
+            .as_ref()
+            .filter(|format| format.r#type != ChatResponseFormat::Text);
+        let tools = request.tools();
+        // the functions that must be called, and whether several calls are allowed
+        let forced_calls = match &request.tool_choice {
+            Some(ToolChoice::Mode(ToolChoiceMode::Required)) => {
+                if tools.is_empty() {
+                    anyhow::bail!("tool_choice required needs at least one tool");
                 }
+                Some((tools.iter().map(|tool| &tool.function).collect(), true))
+            }
+            Some(ToolChoice::Named(choice)) => {
+                let tool = tools
+                    .iter()
+                    .find(|tool| tool.function.name == choice.function.name)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "tool_choice function is not one of the tools: {}",
+                            choice.function.name
+                        )
+                    })?;
+                Some((vec![&tool.function], false))
             }
+            _ => None,
         };
-        let temperature = temperature.map(|temperature| temperature as f64);
-        let top_p = top_p.map(|top_p| top_p as f64);
-        let prompt = self.chat_format.format_messages(messages)?;
-        let tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?;
-        let mut model = self.model.clone();
-
-        let mut logits_processor = LogitsProcessor::new(self.seed, temperature, top_p);
-        let start_prompt_processing = std::time::Instant::now();
-        let mut all_tokens = vec![];
-        let next_token = {
-            let input = Tensor::new(tokens.get_ids(), &self.device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, 0)?;
-            let logits = logits.squeeze(0)?;
-            logits_processor.sample(&logits)?
+        let constraints = [
+            response_format.is_some(),
+            request.guided_choice.is_some(),
+            request.guided_grammar.is_some(),
+            request.guided_regex.is_some(),
+            forced_calls.is_some(),
+        ];
+        if constraints.into_iter().filter(|set| *set).count() > 1 {
+            anyhow::bail!(
+                "only one of response_format, guided_choice, guided_grammar, guided_regex and a forced tool_choice can be used"
+            );
+        }
+        if let Some((functions, parallel)) = forced_calls {
+            let syntax = self
+                .tool_syntax(request)
+                .ok_or_else(|| anyhow::anyhow!("the chat format cannot call tools"))?;
+            return Ok(Some(tool_call_grammar(syntax, &functions, parallel)?));
+        }
+        if let Some(format) = response_format {
+            return match format.r#type {
+                ChatResponseFormat::JsonSchema => {
+                    let json_schema = format.json_schema.as_ref().ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "json_schema is required for the json_schema response format"
+                        )
+                    })?;
+                    let schema = json_schema.schema.clone().unwrap_or(serde_json::json!({}));
+                    let grammar = json_schema_grammar(&schema).map_err(|e| {
+                        anyhow::anyhow!("invalid json_schema {}: {}", json_schema.name, e)
+                    })?;
+                    Ok(Some(grammar))
+                }
+                ChatResponseFormat::JsonObject => Ok(Some(json_object_grammar()?)),
+                ChatResponseFormat::Text => Ok(None),
+            };
+        }
+        if let Some(choices) = &request.guided_choice {
+            let grammar = choice_grammar(choices)
+                .map_err(|e| anyhow::anyhow!("invalid guided_choice: {}", e))?;
+            return Ok(Some(grammar));
+        }
+        if let Some(source) = &request.guided_grammar {
+            let grammar = gbnf_grammar(source)
+                .map_err(|e| anyhow::anyhow!("invalid guided_grammar: {:#}", e))?;
+            return Ok(Some(grammar));
+        }
+        if let Some(pattern) = &request.guided_regex {
+            let grammar = regex_grammar(pattern)
+                .map_err(|e| anyhow::anyhow!("invalid guided_regex: {}", e))?;
+            return Ok(Some(grammar));
+        }
+        Ok(None)
+    }
+
+    fn logit_bias(&self, logit_bias: Option<&HashMap<String, f32>>) -> Result<Vec<(u32, f32)>> {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        logit_bias
+            .into_iter()
+            .flatten()
+            .map(|(token, bias)| {
+                let id = token
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|id| (*id as usize) < vocab_size)
+                    .ok_or_else(|| anyhow::anyhow!("invalid token id in logit_bias: {token}"))?;
+                if !(-100.0..=100.0).contains(bias) {
+                    anyhow::bail!("logit_bias values must be between -100 and 100");
+                }
+                Ok((id, *bias))
+            })
+            .collect()
+    }
+
+    pub(crate) async fn handle(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse> {
+        let params = self.generation_params(&request)?;
+        let prompt_tokens = params.prompt_tokens.len();
+        let n = params.n;
+        let logprobs = params.logprobs.is_some();
+        let tool_syntax = self.tool_syntax(&request);
+        let mut receiver = self.scheduler.submit(params)?;
+        let mut response =
+            ChatCompletionResponse::new(request.model.clone(), self.system_fingerprint.clone());
+        let mut choices: Vec<ChatCompletionChoice> = (0..n)
+            .map(|index| ChatCompletionChoice {
+                finish_reason: Default::default(),
+                index,
+                message: AssistantMessage {
+                    content: Some("".to_string()),
+                    name: None,
+                    tool_calls: vec![],
+                },
+                logprobs: logprobs.then(ChatCompletionLogprobs::default),
+            })
+            .collect();
+        let mut remaining = n;
+        while remaining > 0 {
+            let event = receiver
+                .recv()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("chat model decode loop has stopped"))?;
+            match event {
+                SequenceEvent::Token {
+                    index,
+                    text,
+                    logprobs,
+                } => {
+                    let choice = &mut choices[index];
+                    if let Some(content) = choice.message.content.as_mut() {
+                        content.push_str(&text);
+                    }
+                    if let Some(choice_logprobs) = choice.logprobs.as_mut() {
+                        choice_logprobs.content.extend(logprobs);
+                    }
+                }
+                SequenceEvent::Finished {
+                    index,
+                    reason,
+                    completion_tokens,
+                    cached_tokens,
+                } => {
+                    let choice = &mut choices[index];
+                    choice.finish_reason = reason;
+                    if let Some(syntax) = tool_syntax {
+                        let text = choice.message.content.take().unwrap_or_default();
+                        let (content, tool_calls) = parse_tool_calls(&text, syntax);
+                        if !tool_calls.is_empty() && reason == FinishReason::Stop {
+                            choice.finish_reason = FinishReason::ToolCalls;
+                        }
+                        choice.message.content = content;
+                        choice.message.tool_calls = tool_calls;
+                    }
+                    response.usage.completion_tokens += completion_tokens;
+                    response.usage.prompt_tokens_details.cached_tokens = cached_tokens;
+                    remaining -= 1;
+                }
+                SequenceEvent::PromptLogprobs { .. } => {}
+                SequenceEvent::Error(e) => anyhow::bail!(e),
+            }
+        }
+        response.choices = choices;
+        response.usage.prompt_tokens = prompt_tokens;
+        response.usage.total_tokens = prompt_tokens + response.usage.completion_tokens;
+        Ok(response)
+    }
+
+    pub(crate) fn stream_handle(&self, request: ChatCompletionRequest) -> Result<ChatModelStream> {
+        let params = self.generation_params(&request)?;
+        let n = params.n;
+        let logprobs = params.logprobs.is_some();
+        let mut response =
+            ChatCompletionResponse::new(request.model.clone(), self.system_fingerprint.clone());
+        response.usage.prompt_tokens = params.prompt_tokens.len();
+        response.usage.total_tokens = params.prompt_tokens.len();
+        let receiver = self.scheduler.submit(params)?;
+        let mut stream = ChatModelStream {
+            response,
+            receiver,
+            remaining: n,
+            logprobs,
+            include_usage: request
+                .stream_options
+                .as_ref()
+                .is_some_and(|options| options.include_usage),
+            tool_calls: self
+                .tool_syntax(&request)
+                .map(|syntax| (0..n).map(|_| ToolCallParser::new(syntax)).collect()),
+            queue: VecDeque::new(),
+            done: false,
         };
-        all_tokens.push(next_token);
-        let prompt_dt = start_prompt_processing.elapsed();
-        println!(
-            "\n\n{:4} prompt tokens processed: {:.2} token/s",
-            tokens.len(),
-            tokens.len() as f64 / prompt_dt.as_secs_f64(),
-        );
-        let result = self.tokenizer.decode(&[next_token], true).map_err(E::msg)?;
-        let start_post_prompt = std::time::Instant::now();
-        let sampled = 0;
-        let mut response = ChatCompletionResponse::new(request.model.clone());
-        let mut choice = ChatCompletionChoice {
-            finish_reason: Default::default(),
-            index: 0,
-            message: AssistantMessage {
-                content: Some(result.clone()),
-                name: None,
-                tool_calls: vec![],
+        // the role is only sent once, with an empty first chunk of every choice
+        for index in 0..n {
+            stream.push_chunk(ChatCompletionChunkChoice {
+                index,
+                delta: ChatCompletionDelta {
+                    role: Some("assistant".to_string()),
+                    content: Some("".to_string()),
+                    ..Default::default()
+                },
+                logprobs: None,
+                finish_reason: None,
+            });
+        }
+        Ok(stream)
+    }
+}
+
+/// Merge the parts of a message that were parsed from the same text into a delta.
+fn tool_call_delta(events: Vec<ToolCallEvent>) -> ChatCompletionDelta {
+    let mut delta = ChatCompletionDelta::default();
+    for event in events {
+        match event {
+            ToolCallEvent::Content(text) => {
+                delta
+                    .content
+                    .get_or_insert_with(String::new)
+                    .push_str(&text);
+            }
+            ToolCallEvent::Call { index, id, name } => delta.tool_calls.push(ToolCallDelta {
+                index,
+                id: Some(id),
+                r#type: Some(ToolType::Function),
+                function: FunctionCallDelta {
+                    name: Some(name),
+                    arguments: Some(String::new()),
+                },
+            }),
+            ToolCallEvent::Arguments { index, arguments } => match delta.tool_calls.last_mut() {
+                Some(call) if call.index == index => {
+                    call.function
+                        .arguments
+                        .get_or_insert_with(String::new)
+                        .push_str(&arguments);
+                }
+                _ => delta.tool_calls.push(ToolCallDelta {
+                    index,
+                    id: None,
+                    r#type: None,
+                    function: FunctionCallDelta {
+                        name: None,
+                        arguments: Some(arguments),
+                    },
+                }),
             },
-        };
-        choice.message.content = Some(result);
-        response.choices.push(choice);
-        response.usage.prompt_tokens = tokens.len();
-        response.usage.completion_tokens = sampled;
-        response.usage.total_tokens = tokens.len() + sampled;
-        Ok(ChatModelStream {
-            response: response.clone(),
-            max_tokens: max_tokens.unwrap_or(4096),
-            index: 0,
-            result: "".to_string(),
-            next_token,
-            device: self.device.clone(),
-            eos_token: self.eos_token,
-            all_tokens,
-            sampled,
-            model,
-            tokenizer: self.tokenizer.clone(),
-            logits_processor,
-            is_json,
-            start_post_prompt,
-            is_finished: false,
-        })
+        }
     }
+    delta
 }
 
 pub(crate) fn init_model(args: ChatModelConfig) -> Result<ChatModel> {
     let ChatModelConfig {
         model_id,
+        alias,
         tokenizer,
         cpu,
         seed,
         gqa,
         chat_format,
-        ..
+        chat_template,
+        max_batch_size,
+        prefix_cache_size,
+        repeat_penalty,
+        repeat_last_n,
+        context_length,
+        truncation,
+        dtype,
+        quantize,
     } = args;
     let device = device(cpu)?;
-    // let model_path = args.model_id;
     let model_path = PathBuf::from(model_id);
+    let start = std::time::Instant::now();
+    let model_file = match model_path.extension().and_then(|v| v.to_str()) {
+        _ if model_path.is_dir() => {
+            let (config, json) = read_config(&model_path)?;
+            ModelFile::Safetensors(config, json)
+        }
+        Some("gguf") => {
+            let mut file = std::fs::File::open(&model_path)?;
+            let content =
+                gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&model_path))?;
+            ModelFile::Gguf(file, content)
+        }
+        Some("ggml" | "bin") | Some(_) | None => ModelFile::Ggml(std::fs::File::open(&model_path)?),
+    };
+    if quantize.is_some() && !matches!(model_file, ModelFile::Safetensors(..)) {
+        anyhow::bail!("quantize only applies to safetensors checkpoints");
+    }
+
+    // a tokenizer.json next to the model takes precedence over the vocabulary of a GGUF file
     let tokenizer_filename = match tokenizer {
         None => match model_path.is_dir() {
-            true => PathBuf::from(format!("{:?}/tokenizer.json", model_path)),
-            false => PathBuf::from(format!("{:?}/tokenizer.json", model_path.parent().unwrap())),
-        },
-        Some(tokenizer) => PathBuf::from(tokenizer),
+            true => Some(model_path.join("tokenizer.json")),
+            false => model_path.parent().map(|dir| dir.join("tokenizer.json")),
+        }
+        .filter(|path| path.is_file()),
+        Some(tokenizer) => Some(PathBuf::from(tokenizer)),
+    };
+    let tokenizer = match (tokenizer_filename, &model_file) {
+        (Some(tokenizer_filename), _) => {
+            Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?
+        }
+        (None, ModelFile::Gguf(_, content)) => tokenizer_from_gguf(content)?,
+        (None, _) => anyhow::bail!(
+            "cannot find tokenizer.json next to {}, set tokenizer in the config",
+            model_path.display()
+        ),
     };
 
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-
-    let mut file = std::fs::File::open(&model_path)?;
-    let start = std::time::Instant::now();
-
-    let eos_token = token_id(&tokenizer, &chat_format.get_eos_token())?;
-    println!("eos_token: {}", eos_token);
+    let template_metadata = match &model_file {
+        ModelFile::Gguf(_, content) => TemplateMetadata::from_gguf(content),
+        ModelFile::Safetensors(config, _) => TemplateMetadata::from_checkpoint(
+            &model_path,
+            config.bos_token_id,
+            config.eos_token_ids().first().copied(),
+        ),
+        ModelFile::Ggml(_) => TemplateMetadata::default(),
+    };
+    let chat_template = match chat_format {
+        ChatFormat::Jinja => Some(JinjaTemplate::load(
+            chat_template.as_deref(),
+            template_metadata,
+            &tokenizer,
+        )?),
+        _ => None,
+    };
+    // files without eos metadata end generations with the token of the chat format
+    let mut eos_tokens = match &model_file {
+        ModelFile::Gguf(_, content) => eos_token_ids(content, &tokenizer),
+        ModelFile::Safetensors(config, _) => config.eos_token_ids(),
+        ModelFile::Ggml(_) => vec![],
+    };
+    if eos_tokens.is_empty() {
+        eos_tokens.push(match &chat_template {
+            Some(template) => token_id(&tokenizer, template.eos_token())?,
+            None => token_id(&tokenizer, &chat_format.get_eos_token())?,
+        });
+    }
+    println!("eos_tokens: {:?}", eos_tokens);
 
-    let model = match model_path.extension().and_then(|v| v.to_str()) {
-        Some("gguf") => {
-            let model = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
-            let mut total_size_in_bytes = 0;
-            for (_, tensor) in model.tensor_infos.iter() {
-                let elem_count = tensor.shape.elem_count();
-                total_size_in_bytes +=
-                    elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
-            }
-            println!(
-                "loaded {:?} tensors ({}) in {:.2}s",
-                model.tensor_infos.len(),
-                &format_size(total_size_in_bytes),
-                start.elapsed().as_secs_f32(),
-            );
-            ModelWeights::from_gguf(model, &mut file, &device)?
+    let mut model_files = match &model_file {
+        ModelFile::Safetensors(..) => {
+            let mut files = vec![model_path.join("config.json")];
+            files.extend(safetensors_files(&model_path)?);
+            files
         }
-        Some("ggml" | "bin") | Some(_) | None => {
-            let model = ggml_file::Content::read(&mut file, &device)
-                .map_err(|e| e.with_path(model_path))?;
-            let mut total_size_in_bytes = 0;
-            for (_, tensor) in model.tensors.iter() {
-                let elem_count = tensor.shape().elem_count();
-                total_size_in_bytes +=
-                    elem_count * tensor.dtype().type_size() / tensor.dtype().block_size();
+        _ => vec![model_path.clone()],
+    };
+    let (model, kv_bytes_per_token, trained_context_length, quantization) =
+        match (model_file, quantize) {
+            (ModelFile::Safetensors(config, _), Some(quantize)) => {
+                let quantized = quantized_path(&model_path, quantize);
+                quantize_checkpoint(&config, &model_files, quantize, &quantized)?;
+                println!(
+                    "quantized weights in {} ready in {:.2}s",
+                    quantized.display(),
+                    start.elapsed().as_secs_f32(),
+                );
+                let mut file = std::fs::File::open(&quantized)?;
+                let model =
+                    gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&quantized))?;
+                let kv_bytes_per_token = gguf_kv_bytes_per_token(&model)?;
+                let quantization = quantization(
+                    model
+                        .tensor_infos
+                        .values()
+                        .map(|tensor| (tensor.ggml_dtype, tensor.shape.elem_count())),
+                );
+                model_files.push(quantized);
+                (
+                    ChatWeights::from_gguf(model, &mut file, &device)?,
+                    kv_bytes_per_token,
+                    Some(config.max_position_embeddings),
+                    quantization,
+                )
             }
-            println!(
-                "loaded {:?} tensors ({}) in {:.2}s",
-                model.tensors.len(),
-                &format_size(total_size_in_bytes),
-                start.elapsed().as_secs_f32(),
-            );
-            println!("params: {:?}", model.hparams);
-            ModelWeights::from_ggml(model, gqa, &device)?
-        }
+            (ModelFile::Safetensors(config, json), None) => {
+                let dtype = dtype.map_or_else(|| config.dtype(&device), DType::from);
+                let model = Unquantized::load(&config, &json, &model_files[1..], dtype, &device)?;
+                println!(
+                    "loaded {} safetensors files in {:.2}s",
+                    model_files.len() - 1,
+                    start.elapsed().as_secs_f32(),
+                );
+                (
+                    ChatWeights::Unquantized(Box::new(model)),
+                    config.kv_bytes_per_token(dtype),
+                    Some(config.max_position_embeddings),
+                    dtype.as_str().to_string(),
+                )
+            }
+            (ModelFile::Gguf(ref mut file, model), _) => {
+                let mut total_size_in_bytes = 0;
+                for (_, tensor) in model.tensor_infos.iter() {
+                    let elem_count = tensor.shape.elem_count();
+                    total_size_in_bytes +=
+                        elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+                }
+                println!(
+                    "loaded {:?} tensors ({}) in {:.2}s",
+                    model.tensor_infos.len(),
+                    &format_size(total_size_in_bytes),
+                    start.elapsed().as_secs_f32(),
+                );
+                let kv_bytes_per_token = gguf_kv_bytes_per_token(&model)?;
+                let trained_context_length = gguf_context_length(&model);
+                let quantization = quantization(
+                    model
+                        .tensor_infos
+                        .values()
+                        .map(|tensor| (tensor.ggml_dtype, tensor.shape.elem_count())),
+                );
+                (
+                    ChatWeights::from_gguf(model, file, &device)?,
+                    kv_bytes_per_token,
+                    trained_context_length,
+                    quantization,
+                )
+            }
+            (ModelFile::Ggml(ref mut file), _) => {
+                let model = ggml_file::Content::read(file, &device)
+                    .map_err(|e| e.with_path(&model_path))?;
+                let mut total_size_in_bytes = 0;
+                for (_, tensor) in model.tensors.iter() {
+                    let elem_count = tensor.shape().elem_count();
+                    total_size_in_bytes +=
+                        elem_count * tensor.dtype().type_size() / tensor.dtype().block_size();
+                }
+                println!(
+                    "loaded {:?} tensors ({}) in {:.2}s",
+                    model.tensors.len(),
+                    &format_size(total_size_in_bytes),
+                    start.elapsed().as_secs_f32(),
+                );
+                println!("params: {:?}", model.hparams);
+                let kv_bytes_per_token =
+                    2 * model.hparams.n_layer as usize * model.hparams.n_embd as usize / gqa * 4;
+                let quantization = quantization(
+                    model
+                        .tensors
+                        .values()
+                        .map(|tensor| (tensor.dtype(), tensor.shape().elem_count())),
+                );
+                (
+                    ChatWeights::from_ggml(model, gqa, &device)?,
+                    kv_bytes_per_token,
+                    None,
+                    quantization,
+                )
+            }
+        };
+
+    let prompt_format = match &chat_template {
+        Some(template) => template.source().to_string(),
+        None => format!("{chat_format:?}"),
     };
-    Ok(ChatModel {
-        tokenizer,
+    let system_fingerprint = system_fingerprint(
+        &model_files,
+        &tokenizer,
+        &prompt_format,
+        cpu,
+        gqa,
+        repeat_penalty,
+        repeat_last_n,
+    )?;
+    println!("system_fingerprint: {}", system_fingerprint);
+    // positions past max_seq_len are not supported by the model implementation
+    let max_seq_len = model.max_seq_len();
+    let context_length = context_length
+        .or(trained_context_length)
+        .unwrap_or(max_seq_len)
+        .min(max_seq_len);
+    println!("context_length: {}", context_length);
+    let device_name = device_name(&device);
+    let scheduler = Scheduler::new(
+        alias,
         model,
+        tokenizer.clone(),
         device,
+        eos_tokens,
+        max_batch_size,
+        prefix_cache_size * 1_000_000,
+        kv_bytes_per_token,
+    )?;
+    Ok(ChatModel {
+        tokenizer,
         seed,
-        eos_token,
+        repeat_penalty,
+        repeat_last_n,
         chat_format,
+        chat_template,
+        context_length,
+        quantization,
+        device: device_name,
+        truncation,
+        system_fingerprint,
+        scheduler,
     })
 }
+
+/// Name of the ggml type that holds most of the weights, which is how the quantization of a model
+/// file is usually named.
+fn quantization(tensors: impl Iterator<Item = (GgmlDType, usize)>) -> String {
+    let mut counts: HashMap<GgmlDType, usize> = HashMap::new();
+    for (dtype, elem_count) in tensors {
+        *counts.entry(dtype).or_default() += elem_count;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, elem_count)| *elem_count)
+        .map(|(dtype, _)| ggml_type_name(dtype))
+        .unwrap_or_default()
+}
+
+/// The lowercase name llama.cpp gives a ggml type, such as q4_0, q4_k or f16.
+fn ggml_type_name(dtype: GgmlDType) -> String {
+    let name = format!("{dtype:?}").to_lowercase();
+    match name.strip_suffix('k') {
+        Some(base) => format!("{base}_k"),
+        None => name,
+    }
+}
+
+/// Identify everything that determines the output for a given request and seed.
+///
+/// Model files are identified by their size and header, which holds the hyperparameters and
+/// tensor layout, so that fingerprinting does not read gigabytes of weights on startup.
+/// `prompt_format` names the chat format, or is the source of its Jinja template.
+fn system_fingerprint(
+    model_files: &[PathBuf],
+    tokenizer: &Tokenizer,
+    prompt_format: &str,
+    cpu: bool,
+    gqa: usize,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+) -> Result<String> {
+    let mut hasher = Fnv1a::default();
+    for model_file in model_files {
+        let file = std::fs::File::open(model_file)?;
+        file.metadata()?.len().hash(&mut hasher);
+        let mut header = vec![];
+        file.take(4 * 1024 * 1024).read_to_end(&mut header)?;
+        hasher.write(&header);
+    }
+    tokenizer
+        .to_string(false)
+        .map_err(E::msg)?
+        .hash(&mut hasher);
+    prompt_format.hash(&mut hasher);
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    cpu.hash(&mut hasher);
+    gqa.hash(&mut hasher);
+    repeat_penalty.to_bits().hash(&mut hasher);
+    repeat_last_n.hash(&mut hasher);
+    Ok(format!("fp_{:016x}", hasher.finish()))
+}
+
+/// Number of tokens the model was trained with, if the metadata has it.
+fn gguf_context_length(content: &gguf_file::Content) -> Option<usize> {
+    let architecture = architecture(content).ok()?;
+    let key = format!("{architecture}.context_length");
+    Some(content.metadata.get(&key)?.to_u32().ok()? as usize)
+}
+
+/// Size of the key and value cache entries of a single token, as the models keep them in f32.
+fn gguf_kv_bytes_per_token(content: &gguf_file::Content) -> Result<usize> {
+    let metadata = |key: &str| {
+        content
+            .metadata
+            .get(key)
+            .ok_or_else(|| anyhow::anyhow!("cannot find {key} in metadata"))
+    };
+    let architecture = architecture(content)?;
+    let block_count = metadata(&format!("{architecture}.block_count"))?.to_u32()? as usize;
+    let embedding_length =
+        metadata(&format!("{architecture}.embedding_length"))?.to_u32()? as usize;
+    let head_count = metadata(&format!("{architecture}.attention.head_count"))?.to_u32()? as usize;
+    let head_count_kv = match metadata(&format!("{architecture}.attention.head_count_kv")) {
+        Ok(value) => value.to_u32()? as usize,
+        Err(_) => head_count,
+    };
+    Ok(2 * block_count * head_count_kv * (embedding_length / head_count) * 4)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::quantization;
     use crate::models::chat::init_model;
     use crate::types::chat::ChatCompletionRequest;
     use crate::Config;
 
+    #[test]
+    fn dominant_quantization() {
+        use candle_core::quantized::GgmlDType;
+        let tensors = [
+            (GgmlDType::F32, 4096),
+            (GgmlDType::Q6K, 1 << 20),
+            (GgmlDType::Q4K, 1 << 24),
+        ];
+        assert_eq!(quantization(tensors.into_iter()), "q4_k");
+        assert_eq!(quantization([(GgmlDType::Q4_0, 1)].into_iter()), "q4_0");
+        assert_eq!(quantization([(GgmlDType::F16, 1)].into_iter()), "f16");
+        assert_eq!(quantization(std::iter::empty()), "");
+    }
+
     #[test]
     fn chat_test() {
         let config = Config::load("test_chat_config.toml".to_string()).unwrap();
@@ -441,7 +988,10 @@ mod tests {
     ]
 }"#;
         let request = serde_json::from_str::<ChatCompletionRequest>(json_str).unwrap();
-        let result = model.handle(request).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(model.handle(request))
+            .unwrap();
         println!("result: {:?}", result);
     }
 }
@@ -0,0 +1,235 @@
+/// Radix tree of model state snapshots keyed on prompt token sequences.
+///
+/// Each snapshot holds the state of the model (its KV cache) after processing the tokens on the
+/// path from the root. The capacity is expressed in bytes, the size of each snapshot is given when
+/// it is stored; once the capacity is exceeded the least recently used snapshots are dropped.
+pub(crate) struct PrefixCache<S> {
+    root: Node<S>,
+    capacity: usize,
+    cached_bytes: usize,
+    clock: u64,
+}
+
+struct Node<S> {
+    tokens: Vec<u32>,
+    children: Vec<Node<S>>,
+    snapshot: Option<S>,
+    /// Size of the snapshot in bytes.
+    size: usize,
+    last_used: u64,
+}
+
+impl<S> Node<S> {
+    fn new(tokens: Vec<u32>) -> Self {
+        Self {
+            tokens,
+            children: vec![],
+            snapshot: None,
+            size: 0,
+            last_used: 0,
+        }
+    }
+}
+
+impl<S: Clone> PrefixCache<S> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            root: Node::new(vec![]),
+            capacity,
+            cached_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// Return the snapshot of the longest cached prefix of `tokens` and its length.
+    pub(crate) fn lookup(&mut self, tokens: &[u32]) -> Option<(usize, S)> {
+        self.clock += 1;
+        let clock = self.clock;
+        let mut node = &mut self.root;
+        let mut depth = 0;
+        let mut best = None;
+        loop {
+            let rest = &tokens[depth..];
+            let Some(child) = node
+                .children
+                .iter_mut()
+                .find(|child| rest.starts_with(&child.tokens))
+            else {
+                break;
+            };
+            depth += child.tokens.len();
+            if let Some(snapshot) = &child.snapshot {
+                child.last_used = clock;
+                best = Some((depth, snapshot.clone()));
+            }
+            node = child;
+        }
+        best
+    }
+
+    /// Length of the longest prefix `tokens` shares with any sequence in the tree, whether or not
+    /// a snapshot exists at that point.
+    pub(crate) fn common_prefix(&self, tokens: &[u32]) -> usize {
+        let mut node = &self.root;
+        let mut depth = 0;
+        loop {
+            let rest = &tokens[depth..];
+            let Some(child) = node
+                .children
+                .iter()
+                .find(|child| rest.first() == child.tokens.first())
+            else {
+                return depth;
+            };
+            let shared = common_len(&child.tokens, rest);
+            depth += shared;
+            if shared < child.tokens.len() {
+                return depth;
+            }
+            node = child;
+        }
+    }
+
+    /// Store the snapshot of `size` bytes taken after processing `tokens`, evicting old snapshots
+    /// if needed.
+    pub(crate) fn insert(&mut self, tokens: &[u32], snapshot: S, size: usize) {
+        if tokens.is_empty() || size > self.capacity {
+            return;
+        }
+        self.clock += 1;
+        let replaced = insert_at(&mut self.root, tokens, snapshot, size, self.clock);
+        self.cached_bytes = self.cached_bytes + size - replaced;
+        while self.cached_bytes > self.capacity {
+            let Some((_, path)) = least_recently_used(&self.root, &[]) else {
+                break;
+            };
+            let mut node = &mut self.root;
+            for index in path {
+                node = &mut node.children[index];
+            }
+            node.snapshot = None;
+            self.cached_bytes -= std::mem::take(&mut node.size);
+        }
+        prune(&mut self.root);
+    }
+}
+
+fn common_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Returns the size of the snapshot that was replaced, 0 if there was none.
+fn insert_at<S>(node: &mut Node<S>, tokens: &[u32], snapshot: S, size: usize, clock: u64) -> usize {
+    if tokens.is_empty() {
+        node.snapshot = Some(snapshot);
+        node.last_used = clock;
+        return std::mem::replace(&mut node.size, size);
+    }
+    let Some(index) = node
+        .children
+        .iter()
+        .position(|child| child.tokens.first() == tokens.first())
+    else {
+        let mut child = Node::new(tokens.to_vec());
+        child.snapshot = Some(snapshot);
+        child.size = size;
+        child.last_used = clock;
+        node.children.push(child);
+        return 0;
+    };
+    let child = &mut node.children[index];
+    let shared = common_len(&child.tokens, tokens);
+    if shared < child.tokens.len() {
+        let mut parent = Node::new(child.tokens[..shared].to_vec());
+        let mut old = std::mem::replace(child, Node::new(vec![]));
+        old.tokens.drain(..shared);
+        parent.children.push(old);
+        *child = parent;
+    }
+    insert_at(child, &tokens[shared..], snapshot, size, clock)
+}
+
+fn least_recently_used<S>(node: &Node<S>, path: &[usize]) -> Option<(u64, Vec<usize>)> {
+    let mut best = node
+        .snapshot
+        .as_ref()
+        .map(|_| (node.last_used, path.to_vec()));
+    for (index, child) in node.children.iter().enumerate() {
+        let mut child_path = path.to_vec();
+        child_path.push(index);
+        if let Some(candidate) = least_recently_used(child, &child_path) {
+            match &best {
+                Some((used, _)) if *used <= candidate.0 => {}
+                _ => best = Some(candidate),
+            }
+        }
+    }
+    best
+}
+
+/// Remove branches without snapshots and merge nodes that only forward to a single child.
+fn prune<S>(node: &mut Node<S>) {
+    for child in node.children.iter_mut() {
+        prune(child);
+    }
+    node.children
+        .retain(|child| child.snapshot.is_some() || !child.children.is_empty());
+    for child in node.children.iter_mut() {
+        if child.snapshot.is_none() && child.children.len() == 1 {
+            let mut grandchild = child.children.pop().unwrap();
+            child.tokens.append(&mut grandchild.tokens);
+            *child = Node {
+                tokens: std::mem::take(&mut child.tokens),
+                ..grandchild
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrefixCache;
+
+    #[test]
+    fn lookup_longest_prefix() {
+        let mut cache = PrefixCache::new(100);
+        cache.insert(&[1, 2, 3], "a", 3);
+        cache.insert(&[1, 2, 3, 4, 5], "b", 5);
+        cache.insert(&[1, 2, 7], "c", 3);
+        assert_eq!(cache.lookup(&[1, 2, 3, 4, 5, 6]), Some((5, "b")));
+        assert_eq!(cache.lookup(&[1, 2, 3, 4]), Some((3, "a")));
+        assert_eq!(cache.lookup(&[1, 2, 7, 8]), Some((3, "c")));
+        assert_eq!(cache.lookup(&[1, 2]), None);
+        assert_eq!(cache.common_prefix(&[1, 2, 3, 4, 9]), 4);
+        assert_eq!(cache.common_prefix(&[1, 2, 8]), 2);
+        assert_eq!(cache.common_prefix(&[9]), 0);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = PrefixCache::new(8);
+        cache.insert(&[1, 2, 3], "a", 3);
+        cache.insert(&[4, 5, 6], "b", 3);
+        assert_eq!(cache.lookup(&[1, 2, 3]), Some((3, "a")));
+        cache.insert(&[7, 8, 9], "c", 3);
+        assert_eq!(cache.lookup(&[4, 5, 6]), None);
+        assert_eq!(cache.lookup(&[1, 2, 3]), Some((3, "a")));
+        assert_eq!(cache.lookup(&[7, 8, 9]), Some((3, "c")));
+        assert_eq!(cache.common_prefix(&[4, 5, 6]), 0);
+    }
+
+    #[test]
+    fn evict_by_size() {
+        let mut cache = PrefixCache::new(10);
+        cache.insert(&[1, 2], "a", 4);
+        cache.insert(&[3], "b", 4);
+        cache.insert(&[1, 2], "c", 6);
+        assert_eq!(cache.lookup(&[1, 2]), Some((2, "c")));
+        assert_eq!(cache.lookup(&[3]), Some((1, "b")));
+        cache.insert(&[4], "d", 11);
+        assert_eq!(cache.lookup(&[4]), None);
+        cache.insert(&[5], "e", 1);
+        assert_eq!(cache.lookup(&[1, 2]), None);
+        assert_eq!(cache.lookup(&[3]), Some((1, "b")));
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{HashMap, HashSet};
+
+/// Draws the next token from processed logits.
+///
+/// Without a temperature (or a temperature close to zero) the most likely token is picked,
+/// otherwise the token is sampled from the temperature scaled distribution, optionally limited
+/// to the top_p probability mass.
+pub(crate) struct Sampler {
+    rng: StdRng,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+}
+
+impl Sampler {
+    pub(crate) fn new(seed: u64, temperature: Option<f64>, top_p: Option<f64>) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            temperature: temperature
+                .filter(|temperature| *temperature >= 1e-7)
+                .map(|temperature| temperature as f32),
+            top_p: top_p
+                .filter(|top_p| *top_p > 0. && *top_p < 1.)
+                .map(|top_p| top_p as f32),
+        }
+    }
+
+    /// Whether draws are limited to the top_p probability mass.
+    pub(crate) fn has_top_p(&self) -> bool {
+        self.top_p.is_some()
+    }
+
+    /// Log probabilities of the distribution the next token is drawn from.
+    pub(crate) fn log_probs(&self, logits: &[f32]) -> Vec<f32> {
+        log_softmax(logits, self.temperature.unwrap_or(1.))
+    }
+
+    pub(crate) fn sample(&mut self, log_probs: &[f32]) -> Result<u32> {
+        if self.temperature.is_none() {
+            return log_probs
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(token, _)| token as u32)
+                .ok_or_else(|| anyhow::anyhow!("empty logits"));
+        }
+        let mut probs: Vec<f32> = log_probs.iter().map(|log_prob| log_prob.exp()).collect();
+        if let Some(top_p) = self.top_p {
+            let mut order: Vec<usize> = (0..probs.len()).collect();
+            order.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+            let mut cumulative = 0.;
+            for token in order {
+                if cumulative >= top_p {
+                    probs[token] = 0.;
+                } else {
+                    cumulative += probs[token];
+                }
+            }
+        }
+        let distribution = WeightedIndex::new(&probs)?;
+        Ok(distribution.sample(&mut self.rng) as u32)
+    }
+}
+
+/// Log probabilities of the distribution of the logits scaled by `temperature`.
+pub(crate) fn log_softmax(logits: &[f32], temperature: f32) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits
+        .iter()
+        .map(|logit| ((logit - max) / temperature).exp())
+        .sum();
+    let log_sum = sum.ln();
+    logits
+        .iter()
+        .map(|logit| (logit - max) / temperature - log_sum)
+        .collect()
+}
+
+/// Indices and values of the `k` largest log probabilities, most likely first, leaving out
+/// tokens that cannot be sampled.
+pub(crate) fn top_k(log_probs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut top: Vec<(u32, f32)> = log_probs
+        .iter()
+        .enumerate()
+        .filter(|(_, log_prob)| log_prob.is_finite())
+        .map(|(token, log_prob)| (token as u32, *log_prob))
+        .collect();
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    top.truncate(k);
+    top
+}
+
+/// Penalties applied to the logits of the next token based on the tokens generated so far.
+#[derive(Debug, Clone)]
+pub(crate) struct Penalties {
+    /// Multiplicative penalty for tokens within the last `repeat_last_n` generated tokens,
+    /// 1.0 disables it.
+    pub(crate) repeat_penalty: f32,
+    pub(crate) repeat_last_n: usize,
+    /// Subtracted from a token's logit once per occurrence in the completion.
+    pub(crate) frequency_penalty: f32,
+    /// Subtracted from a token's logit if it occurs in the completion at all.
+    pub(crate) presence_penalty: f32,
+}
+
+impl Penalties {
+    pub(crate) fn is_disabled(&self) -> bool {
+        (self.repeat_penalty == 1. || self.repeat_last_n == 0)
+            && self.frequency_penalty == 0.
+            && self.presence_penalty == 0.
+    }
+
+    pub(crate) fn apply(&self, logits: &mut [f32], generated: &[u32]) {
+        if self.is_disabled() {
+            return;
+        }
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for token in generated {
+            *counts.entry(*token).or_default() += 1;
+        }
+        for (token, count) in counts {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= count as f32 * self.frequency_penalty + self.presence_penalty;
+            }
+        }
+        if self.repeat_penalty != 1. {
+            let start_at = generated.len().saturating_sub(self.repeat_last_n);
+            let mut seen = HashSet::new();
+            for token in &generated[start_at..] {
+                if !seen.insert(*token) {
+                    continue;
+                }
+                if let Some(logit) = logits.get_mut(*token as usize) {
+                    if *logit >= 0. {
+                        *logit /= self.repeat_penalty;
+                    } else {
+                        *logit *= self.repeat_penalty;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Add the OpenAI style biases to the logits, a bias of -100 bans the token outright.
+pub(crate) fn apply_logit_bias(logits: &mut [f32], logit_bias: &[(u32, f32)]) {
+    for (token, bias) in logit_bias {
+        if let Some(logit) = logits.get_mut(*token as usize) {
+            if *bias <= -100. {
+                *logit = f32::NEG_INFINITY;
+            } else {
+                *logit += bias;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_logit_bias, top_k, Penalties, Sampler};
+
+    #[test]
+    fn apply_penalties() {
+        let penalties = Penalties {
+            repeat_penalty: 2.,
+            repeat_last_n: 2,
+            frequency_penalty: 0.5,
+            presence_penalty: 1.,
+        };
+        let mut logits = vec![4., -4., 4., 4.];
+        penalties.apply(&mut logits, &[0, 0, 1, 2]);
+        // token 0 is out of the repeat window: 4 - 2 * 0.5 - 1
+        // token 1: (-4 - 0.5 - 1) * 2, token 2: (4 - 0.5 - 1) / 2
+        assert_eq!(logits, vec![2., -11., 1.25, 4.]);
+    }
+
+    #[test]
+    fn disabled_penalties() {
+        let penalties = Penalties {
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+        };
+        let mut logits = vec![1., 2.];
+        penalties.apply(&mut logits, &[0, 1, 1]);
+        assert_eq!(logits, vec![1., 2.]);
+    }
+
+    #[test]
+    fn logit_bias() {
+        let mut logits = vec![1., 2., 3.];
+        apply_logit_bias(&mut logits, &[(0, 5.), (2, -100.)]);
+        assert_eq!(logits, vec![6., 2., f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn sample_from_log_probs() {
+        let logits = vec![1., 3., 2.];
+        let mut greedy = Sampler::new(42, None, None);
+        let log_probs = greedy.log_probs(&logits);
+        let total: f32 = log_probs.iter().map(|log_prob| log_prob.exp()).sum();
+        assert!((total - 1.).abs() < 1e-5);
+        assert_eq!(greedy.sample(&log_probs).unwrap(), 1);
+        assert_eq!(top_k(&log_probs, 2)[1].0, 2);
+        let mut nucleus = Sampler::new(42, Some(0.8), Some(0.1));
+        let log_probs = nucleus.log_probs(&logits);
+        assert_eq!(nucleus.sample(&log_probs).unwrap(), 1);
+    }
+}
@@ -0,0 +1,671 @@
+use crate::models::chat::detokenizer::Detokenizer;
+use crate::models::chat::grammar::{Grammar, GrammarState, TokenTrie};
+use crate::models::chat::prefix_cache::PrefixCache;
+use crate::models::chat::sampling::{apply_logit_bias, log_softmax, top_k, Penalties, Sampler};
+use crate::models::chat::stop::StopMatcher;
+use crate::models::chat::vocab::Vocabulary;
+use crate::models::chat::weights::{ChatWeights, SequenceState};
+use crate::types::chat::completion::{ChatCompletionTokenLogprob, FinishReason, TopLogprob};
+use anyhow::{Error as E, Result};
+use candle_core::{DType, Device, Tensor};
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Instant;
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Sampling parameters of a single generation request.
+#[derive(Debug, Clone)]
+pub(crate) struct GenerationParams {
+    pub(crate) prompt_tokens: Vec<u32>,
+    /// Number of independent choices sampled from the shared prompt.
+    pub(crate) n: usize,
+    /// Sequences that end generation when they appear in the decoded text.
+    pub(crate) stop: Vec<String>,
+    pub(crate) max_tokens: usize,
+    pub(crate) temperature: Option<f64>,
+    pub(crate) top_p: Option<f64>,
+    pub(crate) penalties: Penalties,
+    /// Biases added to the logits of the given token ids.
+    pub(crate) logit_bias: Vec<(u32, f32)>,
+    /// Number of alternatives to report along with the log probability of each sampled token,
+    /// `None` if log probabilities were not requested.
+    pub(crate) logprobs: Option<usize>,
+    /// Whether to report the log probabilities of the prompt tokens, with `logprobs`
+    /// alternatives each.
+    pub(crate) prompt_logprobs: bool,
+    /// Grammar the generated text must follow, generation stops once it is complete.
+    pub(crate) grammar: Option<Arc<Grammar>>,
+    pub(crate) seed: u64,
+}
+
+/// Events sent back to the handler that submitted a generation request.
+#[derive(Debug)]
+pub(crate) enum SequenceEvent {
+    /// Newly decoded text of choice `index`, with the log probabilities of the tokens it was
+    /// decoded from when they were requested.
+    Token {
+        index: usize,
+        text: String,
+        logprobs: Vec<ChatCompletionTokenLogprob>,
+    },
+    /// Log probabilities of the prompt tokens after `first_token`, which has none as nothing
+    /// precedes it. Special tokens such as the bos token are left out, as they are not part of
+    /// the text. Sent before the events of the choices, if they were requested.
+    PromptLogprobs {
+        first_token: Option<String>,
+        logprobs: Vec<ChatCompletionTokenLogprob>,
+    },
+    /// Choice `index` stopped generating.
+    Finished {
+        index: usize,
+        reason: FinishReason,
+        completion_tokens: usize,
+        cached_tokens: usize,
+    },
+    /// The sequence failed and was removed from the decode loop.
+    Error(String),
+}
+
+struct Admission {
+    params: GenerationParams,
+    sender: UnboundedSender<SequenceEvent>,
+}
+
+/// Tokens of a prompt processed between two decode steps.
+const PREFILL_CHUNK: usize = 256;
+
+/// Handle to the decode loop of a chat model.
+///
+/// Every chat model owns one decode loop running on a dedicated OS thread, so inference never
+/// blocks the async runtime; handlers only await the event channel. Requests are admitted into
+/// the loop at token boundaries and their prompts are processed a chunk at a time between decode
+/// steps. Each step advances all active sequences by one token in a single batched forward pass,
+/// and finished or abandoned sequences are removed before the next one, so a long generation
+/// does not hold up the requests that arrive after it.
+#[derive(Clone, Debug)]
+pub(crate) struct Scheduler {
+    sender: Sender<Admission>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        name: String,
+        model: ChatWeights,
+        tokenizer: Tokenizer,
+        device: Device,
+        eos_tokens: Vec<u32>,
+        max_batch_size: usize,
+        prefix_cache_bytes: usize,
+        kv_bytes_per_token: usize,
+    ) -> Result<Self> {
+        let (sender, receiver) = channel();
+        let vocab = Vocabulary::new(&tokenizer);
+        let worker = Worker {
+            model,
+            trie: TokenTrie::new(vocab.text_tokens()),
+            vocab,
+            tokenizer,
+            device,
+            eos_tokens,
+            max_batch_size: max_batch_size.max(1),
+            prefix_cache: PrefixCache::new(prefix_cache_bytes),
+            kv_bytes_per_token,
+            receiver,
+            waiting: VecDeque::new(),
+            prefilling: VecDeque::new(),
+            active: Vec::new(),
+        };
+        std::thread::Builder::new()
+            .name(format!("chat-{name}"))
+            .spawn(move || worker.run())?;
+        Ok(Self { sender })
+    }
+
+    /// Queue a request for admission and return the receiver of its events.
+    pub(crate) fn submit(
+        &self,
+        params: GenerationParams,
+    ) -> Result<UnboundedReceiver<SequenceEvent>> {
+        let (sender, receiver) = unbounded_channel();
+        self.sender
+            .send(Admission { params, sender })
+            .map_err(|_| anyhow::anyhow!("chat model decode loop has stopped"))?;
+        Ok(receiver)
+    }
+}
+
+/// The decode loop, which steps up to `max_batch_size` sequences together.
+struct Worker {
+    model: ChatWeights,
+    tokenizer: Tokenizer,
+    vocab: Vocabulary,
+    trie: TokenTrie,
+    device: Device,
+    /// Tokens that end a sequence, such as `</s>` and `<|eot_id|>`.
+    eos_tokens: Vec<u32>,
+    max_batch_size: usize,
+    prefix_cache: PrefixCache<Snapshot>,
+    /// Size of the keys and values the model caches for a single token.
+    kv_bytes_per_token: usize,
+    receiver: Receiver<Admission>,
+    waiting: VecDeque<Admission>,
+    /// Admitted requests whose prompts are being processed, the first one a chunk per step.
+    prefilling: VecDeque<Prefill>,
+    active: Vec<Sequence>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        loop {
+            if self.active.is_empty() && self.prefilling.is_empty() && self.waiting.is_empty() {
+                match self.receiver.recv() {
+                    Ok(admission) => self.waiting.push_back(admission),
+                    Err(_) => return,
+                }
+            }
+            while let Ok(admission) = self.receiver.try_recv() {
+                self.waiting.push_back(admission);
+            }
+            self.admit();
+            self.prefill();
+            self.step();
+        }
+    }
+
+    /// Start processing the prompts of waiting requests while there is room in the batch.
+    fn admit(&mut self) {
+        loop {
+            let admitted: usize = self.prefilling.iter().map(|prefill| prefill.params.n).sum();
+            if self.active.len() + admitted >= self.max_batch_size {
+                break;
+            }
+            let Some(Admission { params, sender }) = self.waiting.pop_front() else {
+                break;
+            };
+            let prefill = Prefill::new(params, sender, &self.model, &mut self.prefix_cache);
+            self.prefilling.push_back(prefill);
+        }
+    }
+
+    /// Process the next chunk of the first prompt, and start its choices once it is done.
+    fn prefill(&mut self) {
+        let Some(prefill) = self.prefilling.front_mut() else {
+            return;
+        };
+        if prefill.sender.is_closed() {
+            self.prefilling.pop_front();
+            return;
+        }
+        let advanced = prefill.advance(
+            &self.model,
+            &self.vocab,
+            &self.device,
+            &mut self.prefix_cache,
+            self.kv_bytes_per_token,
+        );
+        let snapshot = match advanced {
+            Ok(None) => return,
+            Ok(Some(snapshot)) => snapshot,
+            Err(e) => {
+                let _ = prefill.sender.send(SequenceEvent::Error(e.to_string()));
+                self.prefilling.pop_front();
+                return;
+            }
+        };
+        let Some(Prefill {
+            params,
+            sender,
+            cached_tokens,
+            ..
+        }) = self.prefilling.pop_front()
+        else {
+            return;
+        };
+        for index in 0..params.n {
+            let mut sequence = Sequence::new(
+                index,
+                &params,
+                snapshot.clone(),
+                cached_tokens,
+                sender.clone(),
+            );
+            // a request for the prompt alone, such as scoring an echoed prompt
+            if params.max_tokens == 0 {
+                sequence.finished(FinishReason::Length);
+            } else {
+                self.active.push(sequence);
+            }
+        }
+    }
+
+    /// Sample the next token of every active sequence and feed them to the model together.
+    fn step(&mut self) {
+        let Self {
+            model,
+            tokenizer,
+            vocab,
+            trie,
+            device,
+            eos_tokens,
+            active,
+            ..
+        } = self;
+        active.retain_mut(
+            |sequence| match sequence.step(tokenizer, vocab, trie, eos_tokens) {
+                Ok(running) => running,
+                Err(e) => {
+                    let _ = sequence.sender.send(SequenceEvent::Error(e.to_string()));
+                    false
+                }
+            },
+        );
+        if active.is_empty() {
+            return;
+        }
+        let tokens: Vec<u32> = active.iter().map(Sequence::last_token).collect();
+        let mut states: Vec<&mut SequenceState> = active
+            .iter_mut()
+            .map(|sequence| &mut sequence.state)
+            .collect();
+        match model.decode(&mut states, &tokens, device) {
+            Ok(logits) => {
+                for (sequence, logits) in active.iter_mut().zip(logits) {
+                    sequence.logits = logits;
+                }
+            }
+            Err(e) => {
+                for sequence in active.drain(..) {
+                    let _ = sequence.sender.send(SequenceEvent::Error(e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Sequence state after processing a prompt prefix, along with the logits of its last token.
+#[derive(Clone)]
+struct Snapshot {
+    state: SequenceState,
+    logits: Tensor,
+}
+
+impl Snapshot {
+    /// Bytes the snapshot holds on to, the KV cache of `tokens` tokens and the logits.
+    fn size(&self, tokens: usize, kv_bytes_per_token: usize) -> usize {
+        tokens * kv_bytes_per_token + self.logits.elem_count() * self.logits.dtype().size_in_bytes()
+    }
+}
+
+/// A request whose prompt is being processed, starting from the longest cached prefix.
+///
+/// A snapshot is also stored where the prompt branches off from previously seen prompts, so that
+/// a shared system prompt gets cached even when every conversation continues differently. For
+/// models that process the tokens after a prefix one by one, an uncached prompt is only split
+/// there when the branch covers at least half of it.
+struct Prefill {
+    params: GenerationParams,
+    sender: UnboundedSender<SequenceEvent>,
+    state: SequenceState,
+    /// Logits of the last processed token, `None` before the first one.
+    logits: Option<Tensor>,
+    /// Number of tokens served from the cache.
+    cached_tokens: usize,
+    /// Where to store a snapshot on the way, 0 for nowhere.
+    branch: usize,
+    /// Log probabilities of the processed prompt tokens, if they were requested.
+    prompt_logprobs: Option<Vec<ChatCompletionTokenLogprob>>,
+    start: Instant,
+}
+
+impl Prefill {
+    /// Start from the longest cached prefix of the prompt.
+    ///
+    /// Prompts whose log probabilities are reported start from scratch: the implementations only
+    /// return the logits of the last token of their input, and the prefix cache only keeps those
+    /// of the last token of a prefix, so the prompt goes through the model one token at a time.
+    fn new(
+        params: GenerationParams,
+        sender: UnboundedSender<SequenceEvent>,
+        model: &ChatWeights,
+        prefix_cache: &mut PrefixCache<Snapshot>,
+    ) -> Self {
+        let prompt_tokens = &params.prompt_tokens;
+        let cached = match params.prompt_logprobs {
+            true => None,
+            false => prefix_cache.lookup(prompt_tokens),
+        };
+        let (cached_tokens, state, logits) = match cached {
+            Some((cached, snapshot)) => (cached, snapshot.state, Some(snapshot.logits)),
+            None => (0, model.new_sequence(), None),
+        };
+        let prompt_len = prompt_tokens.len();
+        let branch = prefix_cache.common_prefix(prompt_tokens);
+        let split =
+            cached_tokens > 0 || model.prefills_after_prefix() || branch >= prompt_len - branch;
+        let branch = match split && !params.prompt_logprobs {
+            true => branch,
+            false => 0,
+        };
+        let prompt_logprobs = params.prompt_logprobs.then(Vec::new);
+        Self {
+            params,
+            sender,
+            state,
+            logits,
+            cached_tokens,
+            branch,
+            prompt_logprobs,
+            start: Instant::now(),
+        }
+    }
+
+    /// Process the next chunk of the prompt, returning the state after the whole prompt once it
+    /// is done.
+    ///
+    /// Implementations that cannot take several tokens past the start of a sequence process the
+    /// uncached part of the prompt up to the branch point at once.
+    fn advance(
+        &mut self,
+        model: &ChatWeights,
+        vocab: &Vocabulary,
+        device: &Device,
+        prefix_cache: &mut PrefixCache<Snapshot>,
+        kv_bytes_per_token: usize,
+    ) -> Result<Option<Snapshot>> {
+        let prompt_tokens = &self.params.prompt_tokens;
+        let prompt_len = prompt_tokens.len();
+        if prompt_len == 0 {
+            anyhow::bail!("prompt is empty");
+        }
+        let position = self.state.position();
+        let end = match self.branch > position && self.branch < prompt_len {
+            true => self.branch,
+            false => prompt_len,
+        };
+        let scored = self.prompt_logprobs.is_some();
+        let end = match position == 0 && !model.prefills_after_prefix() && !scored {
+            true => end,
+            false => end.min(position + PREFILL_CHUNK),
+        };
+        if let Some(prompt_logprobs) = self.prompt_logprobs.as_mut() {
+            let top_logprobs = self.params.logprobs.unwrap_or(0);
+            for token in &prompt_tokens[position..end] {
+                if let (Some(logits), false) = (&self.logits, vocab.is_special(*token)) {
+                    let log_probs =
+                        log_softmax(&logits.to_dtype(DType::F32)?.to_vec1::<f32>()?, 1.);
+                    prompt_logprobs.push(token_logprob(vocab, *token, &log_probs, top_logprobs));
+                }
+                self.logits = Some(model.prefill(&mut self.state, &[*token], device)?);
+            }
+        } else if position < end {
+            self.logits =
+                Some(model.prefill(&mut self.state, &prompt_tokens[position..end], device)?);
+            if end == self.branch || end == prompt_len {
+                let snapshot = self.snapshot()?;
+                let size = snapshot.size(end, kv_bytes_per_token);
+                prefix_cache.insert(&prompt_tokens[..end], snapshot, size);
+            }
+        }
+        if end < prompt_len {
+            return Ok(None);
+        }
+        if let Some(logprobs) = self.prompt_logprobs.take() {
+            let first = prompt_tokens[0];
+            let _ = self.sender.send(SequenceEvent::PromptLogprobs {
+                first_token: (!vocab.is_special(first)).then(|| token_text(vocab.bytes(first))),
+                logprobs,
+            });
+        }
+        let prompt_dt = self.start.elapsed();
+        println!(
+            "{:4} prompt tokens processed ({} cached): {:.2} token/s",
+            prompt_len,
+            self.cached_tokens,
+            (prompt_len - self.cached_tokens) as f64 / prompt_dt.as_secs_f64(),
+        );
+        Ok(Some(self.snapshot()?))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        let logits = self
+            .logits
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("no prompt logits"))?;
+        Ok(Snapshot {
+            state: self.state.clone(),
+            logits,
+        })
+    }
+}
+
+struct Sequence {
+    index: usize,
+    state: SequenceState,
+    sampler: Sampler,
+    sender: UnboundedSender<SequenceEvent>,
+    cached_tokens: usize,
+    generated: Vec<u32>,
+    max_tokens: usize,
+    detokenizer: Detokenizer,
+    stop: StopMatcher,
+    penalties: Penalties,
+    logit_bias: Vec<(u32, f32)>,
+    logprobs: Option<usize>,
+    grammar: Option<GrammarState>,
+    /// Log probabilities of sampled tokens whose text has not been sent yet.
+    pending_logprobs: Vec<ChatCompletionTokenLogprob>,
+    logits: Tensor,
+    start_post_prompt: Instant,
+}
+
+impl Sequence {
+    fn new(
+        index: usize,
+        params: &GenerationParams,
+        snapshot: Snapshot,
+        cached_tokens: usize,
+        sender: UnboundedSender<SequenceEvent>,
+    ) -> Self {
+        // every choice gets its own random stream so that they differ from each other
+        let seed = params.seed.wrapping_add(index as u64);
+        Self {
+            index,
+            state: snapshot.state,
+            sampler: Sampler::new(seed, params.temperature, params.top_p),
+            sender,
+            cached_tokens,
+            generated: vec![],
+            max_tokens: params.max_tokens,
+            detokenizer: Detokenizer::default(),
+            stop: StopMatcher::new(params.stop.clone()),
+            penalties: params.penalties.clone(),
+            logit_bias: params.logit_bias.clone(),
+            logprobs: params.logprobs,
+            grammar: params.grammar.clone().map(GrammarState::new),
+            pending_logprobs: vec![],
+            logits: snapshot.logits,
+            start_post_prompt: Instant::now(),
+        }
+    }
+
+    /// Sample the next token, which the decode loop then feeds back into the model.
+    ///
+    /// Returns `false` once the sequence is finished or its receiver has gone away.
+    fn step(
+        &mut self,
+        tokenizer: &Tokenizer,
+        vocab: &Vocabulary,
+        trie: &TokenTrie,
+        eos_tokens: &[u32],
+    ) -> Result<bool> {
+        let mut logits = self.logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        self.penalties.apply(&mut logits, &self.generated);
+        apply_logit_bias(&mut logits, &self.logit_bias);
+        let (next_token, log_probs) = self.sample(&logits, vocab, trie, eos_tokens)?;
+        self.generated.push(next_token);
+        if eos_tokens.contains(&next_token) {
+            return self.finish(tokenizer, FinishReason::Stop);
+        }
+        if let Some(grammar) = self.grammar.as_mut() {
+            if !grammar.accept(vocab.bytes(next_token)) {
+                anyhow::bail!("sampled a token the grammar does not allow");
+            }
+        }
+        if let Some(top_logprobs) = self.logprobs {
+            self.pending_logprobs
+                .push(token_logprob(vocab, next_token, &log_probs, top_logprobs));
+        }
+        let text = self
+            .detokenizer
+            .push(next_token, |tokens| decode(tokenizer, tokens))?;
+        let output = self.stop.push(&text);
+        if !self.send_text(output.text) {
+            return Ok(false);
+        }
+        if output.stopped {
+            return Ok(self.finished(FinishReason::Stop));
+        }
+        if self.grammar.as_ref().is_some_and(GrammarState::is_finished) {
+            return self.finish(tokenizer, FinishReason::Stop);
+        }
+        if self.generated.len() >= self.max_tokens {
+            return self.finish(tokenizer, FinishReason::Length);
+        }
+        Ok(true)
+    }
+
+    /// The token sampled last, the input of the next forward pass.
+    fn last_token(&self) -> u32 {
+        self.generated.last().copied().unwrap_or_default()
+    }
+
+    /// Sample the next token, restricted to the tokens the grammar allows if there is one.
+    ///
+    /// Returns the token and the log probabilities of the distribution it was drawn from.
+    fn sample(
+        &mut self,
+        logits: &[f32],
+        vocab: &Vocabulary,
+        trie: &TokenTrie,
+        eos_tokens: &[u32],
+    ) -> Result<(u32, Vec<f32>)> {
+        let log_probs = self.sampler.log_probs(logits);
+        let Some(grammar) = &self.grammar else {
+            let token = self.sampler.sample(&log_probs)?;
+            return Ok((token, log_probs));
+        };
+        // drawing from all tokens until an allowed one comes up samples from the same
+        // distribution as masking, so the mask is only computed once a draw is rejected. top_p
+        // would cut the distribution before masking rather than after, so it always masks.
+        if self.logprobs.is_none() && !self.sampler.has_top_p() {
+            let token = self.sampler.sample(&log_probs)?;
+            let allowed = if eos_tokens.contains(&token) {
+                grammar.is_accepting()
+            } else {
+                !vocab.is_special(token) && grammar.accepts(vocab.bytes(token))
+            };
+            if allowed {
+                return Ok((token, log_probs));
+            }
+        }
+        let mut masked = vec![f32::NEG_INFINITY; logits.len()];
+        let mut allowed = trie.allowed_tokens(grammar);
+        if grammar.is_accepting() {
+            allowed.extend(eos_tokens);
+        }
+        for token in allowed {
+            if let Some(logit) = logits.get(token as usize) {
+                masked[token as usize] = *logit;
+            }
+        }
+        if masked.iter().all(|logit| *logit == f32::NEG_INFINITY) {
+            anyhow::bail!("no token is allowed by the grammar");
+        }
+        let log_probs = self.sampler.log_probs(&masked);
+        let token = self.sampler.sample(&log_probs)?;
+        Ok((token, log_probs))
+    }
+
+    /// Send text to the client along with the pending log probabilities, returns `false` if the
+    /// client has gone away.
+    ///
+    /// Log probabilities stay pending while their text is held back, and are dropped along with
+    /// the text of a stop sequence.
+    fn send_text(&mut self, text: String) -> bool {
+        if text.is_empty() {
+            return !self.sender.is_closed();
+        }
+        let event = SequenceEvent::Token {
+            index: self.index,
+            text,
+            logprobs: std::mem::take(&mut self.pending_logprobs),
+        };
+        self.sender.send(event).is_ok()
+    }
+
+    /// Release the remaining text, unless it runs into a stop sequence, and finish.
+    fn finish(&mut self, tokenizer: &Tokenizer, reason: FinishReason) -> Result<bool> {
+        let rest = self.detokenizer.flush(|tokens| decode(tokenizer, tokens))?;
+        let output = self.stop.push(&rest);
+        let mut text = output.text;
+        if !output.stopped {
+            text.push_str(&self.stop.flush());
+        }
+        self.send_text(text);
+        Ok(self.finished(reason))
+    }
+
+    fn finished(&mut self, reason: FinishReason) -> bool {
+        let sampled = self.generated.len();
+        println!(
+            "{sampled:4} tokens generated: {:.2} token/s",
+            sampled as f64 / self.start_post_prompt.elapsed().as_secs_f64(),
+        );
+        let _ = self.sender.send(SequenceEvent::Finished {
+            index: self.index,
+            reason,
+            completion_tokens: sampled,
+            cached_tokens: self.cached_tokens,
+        });
+        false
+    }
+}
+
+/// The log probability of `token` and the `top_logprobs` most likely alternatives.
+fn token_logprob(
+    vocab: &Vocabulary,
+    token: u32,
+    log_probs: &[f32],
+    top_logprobs: usize,
+) -> ChatCompletionTokenLogprob {
+    let bytes = vocab.bytes(token).to_vec();
+    ChatCompletionTokenLogprob {
+        token: token_text(&bytes),
+        logprob: log_probs
+            .get(token as usize)
+            .copied()
+            .unwrap_or(f32::NEG_INFINITY),
+        bytes,
+        top_logprobs: top_k(log_probs, top_logprobs)
+            .into_iter()
+            .map(|(token, logprob)| {
+                let bytes = vocab.bytes(token).to_vec();
+                TopLogprob {
+                    token: token_text(&bytes),
+                    logprob,
+                    bytes,
+                }
+            })
+            .collect(),
+    }
+}
+
+fn token_text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn decode(tokenizer: &Tokenizer, tokens: &[u32]) -> Result<String> {
+    tokenizer.decode(tokens, true).map_err(E::msg)
+}
@@ -0,0 +1,105 @@
+/// Text released by the [`StopMatcher`] after feeding it newly decoded text.
+#[derive(Debug, PartialEq)]
+pub(crate) struct StopOutput {
+    /// Text that can be sent to the client.
+    pub(crate) text: String,
+    /// Whether a stop sequence was hit, the stop sequence itself is never part of `text`.
+    pub(crate) stopped: bool,
+}
+
+/// Finds stop sequences in generated text, even when they span several tokens.
+///
+/// Text that could be the beginning of a stop sequence is held back until it is clear whether
+/// the sequence completes, so that no part of a stop sequence reaches the client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopMatcher {
+    pub(crate) fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, text: &str) -> StopOutput {
+        self.pending.push_str(text);
+        let hit = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(position) = hit {
+            let text = self.pending[..position].to_string();
+            self.pending.clear();
+            return StopOutput {
+                text,
+                stopped: true,
+            };
+        }
+        let held = self.partial_match_len();
+        let text = self.pending[..self.pending.len() - held].to_string();
+        self.pending.drain(..self.pending.len() - held);
+        StopOutput {
+            text,
+            stopped: false,
+        }
+    }
+
+    /// Release the held back text once generation ends without hitting a stop sequence.
+    pub(crate) fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Length of the longest suffix of the pending text that starts one of the stop sequences.
+    fn partial_match_len(&self) -> usize {
+        (1..=self.pending.len())
+            .rev()
+            .filter(|len| self.pending.is_char_boundary(self.pending.len() - len))
+            .find(|len| {
+                let suffix = &self.pending[self.pending.len() - len..];
+                self.stops.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StopMatcher, StopOutput};
+
+    fn output(text: &str, stopped: bool) -> StopOutput {
+        StopOutput {
+            text: text.to_string(),
+            stopped,
+        }
+    }
+
+    #[test]
+    fn stop_across_tokens() {
+        let mut matcher = StopMatcher::new(vec!["###".to_string(), "\nUser:".to_string()]);
+        assert_eq!(matcher.push("Hello"), output("Hello", false));
+        assert_eq!(matcher.push(" world\n"), output(" world", false));
+        assert_eq!(matcher.push("Us"), output("", false));
+        assert_eq!(matcher.push("er: hi"), output("", true));
+    }
+
+    #[test]
+    fn release_partial_match() {
+        let mut matcher = StopMatcher::new(vec!["###".to_string()]);
+        assert_eq!(matcher.push("a #"), output("a ", false));
+        assert_eq!(matcher.push("# b"), output("## b", false));
+        assert_eq!(matcher.push("c#"), output("c", false));
+        assert_eq!(matcher.flush(), "#");
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let mut matcher = StopMatcher::new(vec!["。再见".to_string()]);
+        assert_eq!(matcher.push("你好。"), output("你好", false));
+        assert_eq!(matcher.push("再见！"), output("", true));
+    }
+}
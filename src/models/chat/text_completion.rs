@@ -0,0 +1,557 @@
+use crate::models::chat::context::ContextLengthExceeded;
+use crate::models::chat::model::{stream_error, ChatModel, SamplingOptions};
+use crate::models::chat::scheduler::{GenerationParams, SequenceEvent};
+use crate::types::chat::completion::{ChatCompleteUsage, ChatCompletionTokenLogprob};
+use crate::types::completion::{
+    CompletionChoice, CompletionLogprobs, CompletionRequest, CompletionResponse, Prompt,
+};
+use anyhow::{Error as E, Result};
+use futures_util::Stream;
+use silent::prelude::{error, SSEEvent};
+use std::collections::VecDeque;
+use std::task::Poll;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Tokens that frame a fill-in-the-middle prompt, in the conventions of common code models.
+const FIM_TOKENS: &[[&str; 3]] = &[
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+];
+
+/// Legacy text completions, generated from raw prompts without a chat format.
+impl ChatModel {
+    /// Generation parameters and echoed text of every prompt of the request.
+    fn completion_params(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<Vec<(GenerationParams, String)>> {
+        let logprobs = request.logprobs;
+        if logprobs.is_some_and(|logprobs| logprobs > 5) {
+            anyhow::bail!("logprobs must be between 0 and 5");
+        }
+        let prompt_logprobs = request.echo && logprobs.is_some();
+        if prompt_logprobs && request.suffix.is_some() {
+            anyhow::bail!("echo with logprobs is not supported together with suffix");
+        }
+        // scoring an echoed prompt generates nothing
+        if request.max_tokens == Some(0) && !request.echo {
+            anyhow::bail!("max_tokens must be at least 1 unless echo is set");
+        }
+        let prompts = match &request.prompt {
+            Prompt::Text(text) => vec![(text.clone(), None)],
+            Prompt::Texts(texts) => texts.iter().map(|text| (text.clone(), None)).collect(),
+            Prompt::Tokens(tokens) => vec![self.token_prompt(tokens)?],
+            Prompt::TokenLists(lists) => lists
+                .iter()
+                .map(|tokens| self.token_prompt(tokens))
+                .collect::<Result<_>>()?,
+        };
+        if prompts.is_empty() {
+            anyhow::bail!("prompt must not be empty");
+        }
+        prompts
+            .into_iter()
+            .map(|(text, tokens)| {
+                let tokens = match (&request.suffix, tokens) {
+                    (Some(suffix), _) => self.encode(&self.fill_in_the_middle(&text, suffix)?)?,
+                    (None, Some(tokens)) => tokens,
+                    (None, None) => self.encode(&text)?,
+                };
+                if tokens.len() >= self.context_length {
+                    return Err(ContextLengthExceeded {
+                        prompt_tokens: tokens.len(),
+                        context_length: self.context_length,
+                    }
+                    .into());
+                }
+                let options = SamplingOptions {
+                    n: request.n,
+                    stop: request.stop.as_ref(),
+                    max_tokens: Some(request.max_tokens.unwrap_or(16)),
+                    temperature: request.temperature,
+                    top_p: request.top_p,
+                    frequency_penalty: request.frequency_penalty,
+                    presence_penalty: request.presence_penalty,
+                    repeat_penalty: request.repeat_penalty,
+                    repeat_last_n: request.repeat_last_n,
+                    logit_bias: request.logit_bias.as_ref(),
+                    seed: request.seed,
+                };
+                let mut params = self.sampling_params(tokens, options)?;
+                params.logprobs = logprobs;
+                params.prompt_logprobs = prompt_logprobs;
+                // an echo with log probabilities is the text of the scored tokens, which comes
+                // with them
+                let echo = match request.echo && !prompt_logprobs {
+                    true => text,
+                    false => String::new(),
+                };
+                Ok((params, echo))
+            })
+            .collect()
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        let tokens = self.tokenizer.encode(text, true).map_err(E::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    }
+
+    /// The text of a prompt given as tokens, along with the tokens, which are used as they are so
+    /// that their log probabilities line up with the tokenization of the client.
+    fn token_prompt(&self, tokens: &[u32]) -> Result<(String, Option<Vec<u32>>)> {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        if let Some(token) = tokens.iter().find(|token| **token as usize >= vocab_size) {
+            anyhow::bail!("prompt token {token} is outside of the vocabulary");
+        }
+        let text = self.tokenizer.decode(tokens, false).map_err(E::msg)?;
+        Ok((text, Some(tokens.to_vec())))
+    }
+
+    /// A prompt for the text between `prefix` and `suffix`, if the model knows how to fill in.
+    fn fill_in_the_middle(&self, prefix: &str, suffix: &str) -> Result<String> {
+        let [start, middle, end] = FIM_TOKENS
+            .iter()
+            .find(|tokens| {
+                tokens
+                    .iter()
+                    .all(|token| self.tokenizer.token_to_id(token).is_some())
+            })
+            .ok_or_else(|| anyhow::anyhow!("suffix is not supported by this model"))?;
+        Ok(format!("{start}{prefix}{middle}{suffix}{end}"))
+    }
+
+    pub(crate) async fn complete(&self, request: CompletionRequest) -> Result<CompletionResponse> {
+        let prompts = self.completion_params(&request)?;
+        let logprobs = request.logprobs.is_some();
+        let mut response =
+            CompletionResponse::new(request.model.clone(), self.system_fingerprint.clone());
+        let mut usage = usage();
+        let mut receivers = vec![];
+        for (params, echo) in prompts {
+            usage.prompt_tokens += params.prompt_tokens.len();
+            let n = params.n;
+            let first = response.choices.len();
+            response
+                .choices
+                .extend((0..n).map(|index| CompletionChoice {
+                    text: echo.clone(),
+                    index: first + index,
+                    logprobs: logprobs.then(CompletionLogprobs::default),
+                    finish_reason: None,
+                }));
+            receivers.push((first, n, self.scheduler.submit(params)?));
+        }
+        for (first, n, mut receiver) in receivers {
+            let mut remaining = n;
+            while remaining > 0 {
+                let event = receiver
+                    .recv()
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("chat model decode loop has stopped"))?;
+                match event {
+                    SequenceEvent::PromptLogprobs {
+                        first_token,
+                        logprobs,
+                    } => {
+                        let prompt_logprobs = prompt_completion_logprobs(first_token, logprobs);
+                        let echo = prompt_logprobs.tokens.concat();
+                        for choice in &mut response.choices[first..first + n] {
+                            choice.text = echo.clone();
+                            choice.logprobs = Some(prompt_logprobs.clone());
+                        }
+                    }
+                    SequenceEvent::Token {
+                        index,
+                        text,
+                        logprobs,
+                    } => {
+                        let choice = &mut response.choices[first + index];
+                        let mut offset = choice.text.chars().count();
+                        choice.text.push_str(&text);
+                        if let Some(choice_logprobs) = choice.logprobs.as_mut() {
+                            if let Some((offset_before, token)) = choice_logprobs
+                                .text_offset
+                                .last()
+                                .zip(choice_logprobs.tokens.last())
+                            {
+                                offset = offset_before + token.chars().count();
+                            }
+                            choice_logprobs.extend(completion_logprobs(logprobs, &mut offset));
+                        }
+                    }
+                    SequenceEvent::Finished {
+                        index,
+                        reason,
+                        completion_tokens,
+                        cached_tokens,
+                    } => {
+                        response.choices[first + index].finish_reason = Some(reason);
+                        usage.completion_tokens += completion_tokens;
+                        // the choices of a prompt share its cached prefix
+                        if remaining == n {
+                            usage.prompt_tokens_details.cached_tokens += cached_tokens;
+                        }
+                        remaining -= 1;
+                    }
+                    SequenceEvent::Error(e) => anyhow::bail!(e),
+                }
+            }
+        }
+        usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+        response.usage = Some(usage);
+        Ok(response)
+    }
+
+    pub(crate) fn stream_complete(&self, request: CompletionRequest) -> Result<CompletionStream> {
+        let prompts = self.completion_params(&request)?;
+        let mut stream = CompletionStream {
+            response: CompletionResponse::new(
+                request.model.clone(),
+                self.system_fingerprint.clone(),
+            ),
+            receivers: vec![],
+            remaining: 0,
+            offsets: vec![],
+            logprobs: request.logprobs.is_some(),
+            include_usage: request
+                .stream_options
+                .as_ref()
+                .is_some_and(|options| options.include_usage),
+            usage: usage(),
+            queue: VecDeque::new(),
+            done: false,
+        };
+        for (params, echo) in prompts {
+            stream.usage.prompt_tokens += params.prompt_tokens.len();
+            let first = stream.offsets.len();
+            for index in first..first + params.n {
+                if !echo.is_empty() {
+                    stream.push_chunk(CompletionChoice {
+                        text: echo.clone(),
+                        index,
+                        logprobs: None,
+                        finish_reason: None,
+                    });
+                }
+                stream.offsets.push(echo.chars().count());
+            }
+            stream.remaining += params.n;
+            stream.receivers.push(PromptReceiver {
+                first,
+                n: params.n,
+                remaining: params.n,
+                receiver: self.scheduler.submit(params)?,
+            });
+        }
+        Ok(stream)
+    }
+}
+
+fn usage() -> ChatCompleteUsage {
+    ChatCompleteUsage {
+        completion_tokens: 0,
+        prompt_tokens: 0,
+        total_tokens: 0,
+        prompt_tokens_details: Default::default(),
+    }
+}
+
+/// Legacy logprobs of generated tokens, whose text starts at `offset` in the choice.
+fn completion_logprobs(
+    logprobs: Vec<ChatCompletionTokenLogprob>,
+    offset: &mut usize,
+) -> CompletionLogprobs {
+    let mut completion_logprobs = CompletionLogprobs::default();
+    for logprob in logprobs {
+        completion_logprobs.text_offset.push(*offset);
+        *offset += logprob.token.chars().count();
+        completion_logprobs.top_logprobs.push(Some(
+            logprob
+                .top_logprobs
+                .into_iter()
+                .map(|top| (top.token, top.logprob))
+                .collect(),
+        ));
+        completion_logprobs
+            .token_logprobs
+            .push(Some(logprob.logprob));
+        completion_logprobs.tokens.push(logprob.token);
+    }
+    completion_logprobs
+}
+
+/// Legacy logprobs of an echoed prompt, whose text is that of its tokens. The first token, if
+/// it is part of the text, has none.
+fn prompt_completion_logprobs(
+    first_token: Option<String>,
+    logprobs: Vec<ChatCompletionTokenLogprob>,
+) -> CompletionLogprobs {
+    let mut prompt_logprobs = CompletionLogprobs::default();
+    let mut offset = 0;
+    if let Some(first_token) = first_token {
+        prompt_logprobs.text_offset.push(0);
+        offset = first_token.chars().count();
+        prompt_logprobs.tokens.push(first_token);
+        prompt_logprobs.token_logprobs.push(None);
+        prompt_logprobs.top_logprobs.push(None);
+    }
+    prompt_logprobs.extend(completion_logprobs(logprobs, &mut offset));
+    prompt_logprobs
+}
+
+/// Events of the choices generated from one prompt.
+struct PromptReceiver {
+    /// Index of the first choice of the prompt.
+    first: usize,
+    n: usize,
+    /// Number of choices of the prompt that have not finished yet.
+    remaining: usize,
+    receiver: UnboundedReceiver<SequenceEvent>,
+}
+
+pub(crate) struct CompletionStream {
+    response: CompletionResponse,
+    /// Receivers of the prompts whose choices have not all finished.
+    receivers: Vec<PromptReceiver>,
+    /// Number of choices that have not finished yet.
+    remaining: usize,
+    /// Character offset of the next token of every choice.
+    offsets: Vec<usize>,
+    logprobs: bool,
+    include_usage: bool,
+    usage: ChatCompleteUsage,
+    /// Serialized events waiting to be sent.
+    queue: VecDeque<String>,
+    done: bool,
+}
+
+impl CompletionStream {
+    fn push_chunk(&mut self, choice: CompletionChoice) {
+        let chunk = self.response.chunk(vec![choice]);
+        self.queue.push_back(serde_json::to_string(&chunk).unwrap());
+    }
+
+    /// Queue the usage chunk if requested and the `[DONE]` sentinel that ends the stream.
+    fn finish(&mut self) {
+        if self.include_usage {
+            let mut chunk = self.response.chunk(vec![]);
+            self.usage.total_tokens = self.usage.prompt_tokens + self.usage.completion_tokens;
+            chunk.usage = Some(self.usage.clone());
+            self.queue.push_back(serde_json::to_string(&chunk).unwrap());
+        }
+        self.queue.push_back("[DONE]".to_string());
+        self.done = true;
+    }
+
+    /// Queue an error event and the `[DONE]` sentinel, which end the stream early.
+    fn fail(&mut self, message: &str) {
+        self.queue.push_back(stream_error(message));
+        self.queue.push_back("[DONE]".to_string());
+        self.done = true;
+    }
+
+    /// Handle an event of the prompt at `position` in the receivers.
+    fn handle(&mut self, position: usize, event: SequenceEvent) {
+        let prompt = &mut self.receivers[position];
+        let first = prompt.first;
+        match event {
+            SequenceEvent::PromptLogprobs {
+                first_token,
+                logprobs,
+            } => {
+                let n = prompt.n;
+                let logprobs = prompt_completion_logprobs(first_token, logprobs);
+                let echo = logprobs.tokens.concat();
+                let offset = echo.chars().count();
+                for index in first..first + n {
+                    self.offsets[index] = offset;
+                    self.push_chunk(CompletionChoice {
+                        text: echo.clone(),
+                        index,
+                        logprobs: Some(logprobs.clone()),
+                        finish_reason: None,
+                    });
+                }
+            }
+            SequenceEvent::Token {
+                index,
+                text,
+                logprobs,
+            } => {
+                let index = first + index;
+                let offset = &mut self.offsets[index];
+                let logprobs = self.logprobs.then(|| completion_logprobs(logprobs, offset));
+                self.push_chunk(CompletionChoice {
+                    text,
+                    index,
+                    logprobs,
+                    finish_reason: None,
+                });
+            }
+            SequenceEvent::Finished {
+                index,
+                reason,
+                completion_tokens,
+                cached_tokens,
+            } => {
+                // the choices of a prompt share its cached prefix
+                if prompt.remaining == prompt.n {
+                    self.usage.prompt_tokens_details.cached_tokens += cached_tokens;
+                }
+                prompt.remaining -= 1;
+                self.remaining -= 1;
+                self.usage.completion_tokens += completion_tokens;
+                self.push_chunk(CompletionChoice {
+                    text: String::new(),
+                    index: first + index,
+                    logprobs: None,
+                    finish_reason: Some(reason),
+                });
+            }
+            SequenceEvent::Error(e) => {
+                error!("failed to generate completion chunk: {}", e);
+                self.fail(&e);
+            }
+        }
+    }
+}
+
+impl Stream for CompletionStream {
+    type Item = silent::Result<SSEEvent>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(data) = this.queue.pop_front() {
+                return Poll::Ready(Some(Ok(SSEEvent::default().data(data))));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            if this.remaining == 0 {
+                this.finish();
+                continue;
+            }
+            let ready = this
+                .receivers
+                .iter_mut()
+                .enumerate()
+                .find_map(|(position, prompt)| match prompt.receiver.poll_recv(cx) {
+                    Poll::Ready(event) => Some((position, event)),
+                    Poll::Pending => None,
+                });
+            match ready {
+                Some((position, Some(event))) => this.handle(position, event),
+                // the sequences of a finished prompt drop their senders
+                Some((position, None)) if this.receivers[position].remaining == 0 => {
+                    this.receivers.remove(position);
+                }
+                Some((_, None)) => {
+                    error!("chat model decode loop has stopped");
+                    this.fail("chat model decode loop has stopped");
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        completion_logprobs, prompt_completion_logprobs, usage, CompletionStream, PromptReceiver,
+    };
+    use crate::models::chat::scheduler::SequenceEvent;
+    use crate::types::chat::completion::{ChatCompletionTokenLogprob, FinishReason, TopLogprob};
+    use crate::types::completion::CompletionResponse;
+    use std::collections::VecDeque;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn legacy_logprobs() {
+        let logprob = |token: &str, logprob: f32| ChatCompletionTokenLogprob {
+            token: token.to_string(),
+            logprob,
+            bytes: token.as_bytes().to_vec(),
+            top_logprobs: vec![TopLogprob {
+                token: token.to_string(),
+                logprob,
+                bytes: token.as_bytes().to_vec(),
+            }],
+        };
+        let mut offset = 3;
+        let logprobs =
+            completion_logprobs(vec![logprob("é", -0.5), logprob(" b", -1.0)], &mut offset);
+        assert_eq!(logprobs.tokens, ["é", " b"]);
+        assert_eq!(logprobs.token_logprobs, [Some(-0.5), Some(-1.0)]);
+        assert_eq!(logprobs.text_offset, [3, 4]);
+        let top_logprobs = logprobs.top_logprobs[1].as_ref().unwrap();
+        assert_eq!(top_logprobs.get(" b"), Some(&-1.0));
+        assert_eq!(offset, 6);
+
+        let logprobs = prompt_completion_logprobs(Some("a".to_string()), vec![logprob(" b", -1.0)]);
+        assert_eq!(logprobs.tokens, ["a", " b"]);
+        assert_eq!(logprobs.token_logprobs, [None, Some(-1.0)]);
+        assert!(logprobs.top_logprobs[0].is_none());
+        assert_eq!(logprobs.text_offset, [0, 1]);
+    }
+
+    #[test]
+    fn echo_offsets() {
+        let logprob = |token: &str| ChatCompletionTokenLogprob {
+            token: token.to_string(),
+            logprob: -1.0,
+            bytes: token.as_bytes().to_vec(),
+            top_logprobs: vec![],
+        };
+        // a prompt that started with a bos token, which has been left out
+        let logprobs = prompt_completion_logprobs(
+            None,
+            vec![logprob("Héllo"), logprob(","), logprob(" wörld")],
+        );
+        let text = logprobs.tokens.concat();
+        assert_eq!(logprobs.token_logprobs, [Some(-1.0); 3]);
+        for (token, offset) in logprobs.tokens.iter().zip(&logprobs.text_offset) {
+            let rest: String = text.chars().skip(*offset).collect();
+            assert!(rest.starts_with(token.as_str()));
+        }
+    }
+
+    #[test]
+    fn count_cached_tokens_once_per_prompt() {
+        let (sender, receiver) = unbounded_channel();
+        let mut stream = CompletionStream {
+            response: CompletionResponse::new("model".to_string(), String::new()),
+            receivers: vec![PromptReceiver {
+                first: 0,
+                n: 2,
+                remaining: 2,
+                receiver,
+            }],
+            remaining: 2,
+            offsets: vec![0, 0],
+            logprobs: false,
+            include_usage: true,
+            usage: usage(),
+            queue: VecDeque::new(),
+            done: false,
+        };
+        for index in 0..2 {
+            stream.handle(
+                0,
+                SequenceEvent::Finished {
+                    index,
+                    reason: FinishReason::Length,
+                    completion_tokens: 3,
+                    cached_tokens: 5,
+                },
+            );
+        }
+        drop(sender);
+        assert_eq!(stream.usage.prompt_tokens_details.cached_tokens, 5);
+        assert_eq!(stream.usage.completion_tokens, 6);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::types::chat::completion::{FunctionCall, ToolCall, ToolType};
+
+pub(crate) const CALL_START: &str = "<tool_call>";
+pub(crate) const CALL_END: &str = "</tool_call>";
+
+/// How a model writes tool calls, which its chat format decides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ToolSyntax {
+    /// `<tool_call>{"name": ..., "arguments": ...}</tool_call>` blocks anywhere in the message, the
+    /// Hermes format of ChatML and Qwen2.5 models.
+    Hermes,
+    /// A message that is `{"name": ..., "parameters": ...}` objects, as Llama 3.1 templates ask for
+    /// after the `<|python_tag|>` token.
+    Llama3,
+    /// A message that is a `[{"name": ..., "arguments": ...}]` list after the `[TOOL_CALLS]` token
+    /// of Mistral models.
+    Mistral,
+}
+
+impl ToolSyntax {
+    /// The key of the arguments in a call object.
+    pub(crate) fn arguments_key(self) -> &'static str {
+        match self {
+            Self::Llama3 => "parameters",
+            Self::Hermes | Self::Mistral => "arguments",
+        }
+    }
+
+    /// For syntaxes whose calls make up the whole message, the token before the calls and the
+    /// character the calls start with. The token is special and usually not decoded.
+    fn leading(self) -> Option<(&'static str, char)> {
+        match self {
+            Self::Hermes => None,
+            Self::Llama3 => Some(("<|python_tag|>", '{')),
+            Self::Mistral => Some(("[TOOL_CALLS]", '[')),
+        }
+    }
+
+    fn call_id(self) -> String {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        match self {
+            // mistral templates only accept ids of nine alphanumeric characters
+            Self::Mistral => id[..9].to_string(),
+            Self::Hermes | Self::Llama3 => format!("call_{id}"),
+        }
+    }
+}
+
+/// A part of a generated message.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ToolCallEvent {
+    Content(String),
+    /// The start of a tool call, by its index among the calls of the message.
+    Call {
+        index: usize,
+        id: String,
+        name: String,
+    },
+    Arguments {
+        index: usize,
+        arguments: String,
+    },
+}
+
+/// Splits generated text into content and tool calls, which the model writes in its
+/// [`ToolSyntax`].
+///
+/// Text is fed in as it is generated. The arguments of a call are released while they are being
+/// generated, once the name of the function is known. Whitespace before a call is dropped, so that
+/// a message that only calls tools has no content.
+#[derive(Debug)]
+pub(crate) struct ToolCallParser {
+    syntax: ToolSyntax,
+    /// Text that has not been handled yet.
+    buffer: String,
+    /// Trailing whitespace of the content, released once more content follows.
+    whitespace: String,
+    call: Option<CallState>,
+    calls: usize,
+    /// For syntaxes whose calls make up the whole message, whether it does, once that is known.
+    leading: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+struct CallState {
+    /// Text of the call between the tags, or of its object, so far.
+    text: String,
+    /// Whether the call has been announced with its name.
+    started: bool,
+    arguments: Option<ValueScanner>,
+    /// The end of the call object, for syntaxes without tags around calls.
+    object: Option<ValueScanner>,
+}
+
+/// Finds the end of a JSON value, such as the arguments of a call, while it is being generated.
+#[derive(Debug)]
+struct ValueScanner {
+    /// Offset into the call text up to which the arguments have been released.
+    position: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    done: bool,
+}
+
+impl ToolCallParser {
+    pub(crate) fn new(syntax: ToolSyntax) -> Self {
+        Self {
+            syntax,
+            buffer: String::new(),
+            whitespace: String::new(),
+            call: None,
+            calls: 0,
+            leading: None,
+        }
+    }
+
+    pub(crate) fn has_calls(&self) -> bool {
+        self.calls > 0
+    }
+
+    pub(crate) fn push(&mut self, text: &str) -> Vec<ToolCallEvent> {
+        self.buffer.push_str(text);
+        let mut events = vec![];
+        if let Some((token, start)) = self.syntax.leading() {
+            self.push_leading(token, start, &mut events);
+            return events;
+        }
+        loop {
+            let tag = if self.call.is_some() {
+                CALL_END
+            } else {
+                CALL_START
+            };
+            let (end, found) = match self.buffer.find(tag) {
+                Some(start) => (start, true),
+                None => (self.buffer.len() - partial_tag(&self.buffer, tag), false),
+            };
+            let text: String = self.buffer.drain(..end).collect();
+            match self.call.as_mut() {
+                Some(call) => {
+                    call.text.push_str(&text);
+                    self.progress(&mut events);
+                }
+                None => self.content(text, &mut events),
+            }
+            if !found {
+                return events;
+            }
+            self.buffer.drain(..tag.len());
+            match self.call.is_some() {
+                true => self.end_call(&mut events),
+                false => {
+                    self.whitespace.clear();
+                    self.call = Some(CallState::default());
+                }
+            }
+        }
+    }
+
+    /// Calls that make up the whole message, when it starts with them.
+    fn push_leading(&mut self, token: &str, start: char, events: &mut Vec<ToolCallEvent>) {
+        if self.leading.is_none() {
+            let text = self.buffer.trim_start();
+            let calls = text.strip_prefix(token).unwrap_or(text).trim_start();
+            if calls.is_empty() || token.starts_with(text) {
+                // not known yet
+                return;
+            }
+            self.leading = Some(calls.starts_with(start));
+            if calls.starts_with(start) {
+                self.buffer = calls.to_string();
+            }
+        }
+        if self.leading == Some(false) {
+            let text = std::mem::take(&mut self.buffer);
+            self.content(text, events);
+            return;
+        }
+        loop {
+            let Some(call) = self.call.as_mut() else {
+                // the separators between calls and the brackets of a list are dropped
+                let Some(start) = self.buffer.find('{') else {
+                    self.buffer.clear();
+                    return;
+                };
+                self.buffer.drain(..start);
+                self.call = Some(CallState {
+                    object: Some(ValueScanner::new(0)),
+                    ..Default::default()
+                });
+                continue;
+            };
+            call.text.push_str(&std::mem::take(&mut self.buffer));
+            let Some(object) = call.object.as_mut() else {
+                return;
+            };
+            object.scan(&call.text);
+            if !object.done {
+                self.progress(events);
+                return;
+            }
+            self.buffer = call.text.split_off(object.position);
+            self.end_call(events);
+        }
+    }
+
+    /// Handle the rest of the text once generation has stopped, ending an unterminated call.
+    pub(crate) fn flush(&mut self) -> Vec<ToolCallEvent> {
+        let mut events = vec![];
+        let text = std::mem::take(&mut self.buffer);
+        match self.call.as_mut() {
+            Some(call) => {
+                call.text.push_str(&text);
+                self.end_call(&mut events);
+            }
+            None => self.content(text, &mut events),
+        }
+        if !self.has_calls() && !self.whitespace.is_empty() {
+            events.push(ToolCallEvent::Content(std::mem::take(&mut self.whitespace)));
+        }
+        events
+    }
+
+    fn content(&mut self, text: String, events: &mut Vec<ToolCallEvent>) {
+        self.whitespace.push_str(&text);
+        let trimmed = self.whitespace.trim_end().len();
+        if trimmed > 0 {
+            let content = self.whitespace.drain(..trimmed).collect();
+            events.push(ToolCallEvent::Content(content));
+        }
+    }
+
+    /// Announce the call once its name is complete and release the arguments generated so far.
+    fn progress(&mut self, events: &mut Vec<ToolCallEvent>) {
+        let index = self.calls;
+        let Some(call) = self.call.as_mut() else {
+            return;
+        };
+        if !call.started {
+            let Some(name) = string_value(&call.text, "name") else {
+                return;
+            };
+            call.started = true;
+            events.push(ToolCallEvent::Call {
+                index,
+                id: self.syntax.call_id(),
+                name,
+            });
+        }
+        if call.arguments.is_none() {
+            let Some(position) = value_start(&call.text, self.syntax.arguments_key()) else {
+                return;
+            };
+            call.arguments = Some(ValueScanner::new(position));
+        }
+        if let Some(scanner) = call.arguments.as_mut() {
+            let arguments = scanner.scan(&call.text);
+            if !arguments.is_empty() {
+                events.push(ToolCallEvent::Arguments { index, arguments });
+            }
+        }
+    }
+
+    fn end_call(&mut self, events: &mut Vec<ToolCallEvent>) {
+        self.progress(events);
+        let Some(call) = self.call.take() else {
+            return;
+        };
+        if !call.started {
+            // not a valid call, so it is passed on as it was generated
+            let text = match self.syntax.leading() {
+                Some(_) => call.text,
+                None => format!("{CALL_START}{}{CALL_END}", call.text),
+            };
+            self.content(text, events);
+            return;
+        }
+        if call.arguments.is_none() {
+            events.push(ToolCallEvent::Arguments {
+                index: self.calls,
+                arguments: "{}".to_string(),
+            });
+        }
+        self.calls += 1;
+    }
+}
+
+impl ValueScanner {
+    fn new(position: usize) -> Self {
+        Self {
+            position,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            done: false,
+        }
+    }
+
+    /// The text of the value after the part scanned before, up to the end of the value.
+    fn scan(&mut self, text: &str) -> String {
+        let start = self.position;
+        for (offset, c) in text[start..].char_indices() {
+            if self.done {
+                break;
+            }
+            if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    '\\' => self.escaped = true,
+                    '"' => {
+                        self.in_string = false;
+                        self.done = self.depth == 0;
+                    }
+                    _ => {}
+                }
+            } else {
+                match c {
+                    '"' => self.in_string = true,
+                    '{' | '[' => self.depth += 1,
+                    '}' | ']' if self.depth > 0 => {
+                        self.depth -= 1;
+                        self.done = self.depth == 0;
+                    }
+                    // the end of a number or literal, which is not part of it
+                    ',' | '}' | ']' if self.depth == 0 => {
+                        self.done = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            self.position = start + offset + c.len_utf8();
+        }
+        text[start..self.position].to_string()
+    }
+}
+
+/// Length of the longest suffix of `text` that is the start of `tag`.
+fn partial_tag(text: &str, tag: &str) -> usize {
+    (1..tag.len().min(text.len() + 1))
+        .rev()
+        .find(|len| text.ends_with(&tag[..*len]))
+        .unwrap_or(0)
+}
+
+/// Offset of the value of `key` in a JSON object that is still being generated.
+fn value_start(text: &str, key: &str) -> Option<usize> {
+    let quoted = format!("\"{key}\"");
+    let after_key = text.find(&quoted)? + quoted.len();
+    let colon = after_key + text[after_key..].find(|c: char| !c.is_whitespace())?;
+    if !text[colon..].starts_with(':') {
+        return None;
+    }
+    let value = colon + 1 + text[colon + 1..].find(|c: char| !c.is_whitespace())?;
+    Some(value)
+}
+
+/// The string value of `key` in a JSON object that is still being generated, once it is complete.
+fn string_value(text: &str, key: &str) -> Option<String> {
+    let start = value_start(text, key)?;
+    serde_json::Deserializer::from_str(&text[start..])
+        .into_iter::<String>()
+        .next()?
+        .ok()
+}
+
+/// Split a complete message into its content and its tool calls.
+pub(crate) fn parse_tool_calls(text: &str, syntax: ToolSyntax) -> (Option<String>, Vec<ToolCall>) {
+    let mut parser = ToolCallParser::new(syntax);
+    let mut events = parser.push(text);
+    events.extend(parser.flush());
+    let mut content = String::new();
+    let mut tool_calls: Vec<ToolCall> = vec![];
+    for event in events {
+        match event {
+            ToolCallEvent::Content(text) => content.push_str(&text),
+            ToolCallEvent::Call { id, name, .. } => tool_calls.push(ToolCall {
+                id,
+                r#type: ToolType::Function,
+                function: FunctionCall {
+                    name,
+                    arguments: String::new(),
+                },
+            }),
+            ToolCallEvent::Arguments { index, arguments } => {
+                tool_calls[index].function.arguments.push_str(&arguments);
+            }
+        }
+    }
+    let content = (!content.is_empty() || tool_calls.is_empty()).then_some(content);
+    (content, tool_calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tool_calls, ToolCallEvent, ToolCallParser, ToolSyntax};
+
+    #[test]
+    fn parse_complete_message() {
+        let text = "Let me check.\n<tool_call>\n{\"arguments\": {\"city\": \"Paris\"}, \"name\": \"weather\"}\n</tool_call>\n<tool_call>\n{\"name\": \"time\", \"arguments\": {\"zone\": \"CET\"}}\n</tool_call>";
+        let (content, calls) = parse_tool_calls(text, ToolSyntax::Hermes);
+        assert_eq!(content.as_deref(), Some("Let me check."));
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].function.name, "weather");
+        assert_eq!(calls[0].function.arguments, "{\"city\": \"Paris\"}");
+        assert_eq!(calls[1].function.name, "time");
+        assert_eq!(calls[1].function.arguments, "{\"zone\": \"CET\"}");
+        assert_ne!(calls[0].id, calls[1].id);
+
+        let (content, calls) =
+            parse_tool_calls("<tool_call>not json</tool_call> \n", ToolSyntax::Hermes);
+        assert_eq!(
+            content.as_deref(),
+            Some("<tool_call>not json</tool_call> \n")
+        );
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn stream_arguments() {
+        let text = "\n<tool_call>\n{\"name\": \"search\", \"arguments\": {\"q\": \"a}\\\"b\", \"n\": [1]}}\n</tool_call>";
+        let mut parser = ToolCallParser::new(ToolSyntax::Hermes);
+        let mut events = vec![];
+        let mut start = 0;
+        for (end, _) in text.char_indices().skip(1) {
+            events.extend(parser.push(&text[start..end]));
+            start = end;
+        }
+        events.extend(parser.push(&text[start..]));
+        events.extend(parser.flush());
+        assert!(parser.has_calls());
+        assert!(matches!(
+            &events[0],
+            ToolCallEvent::Call { index: 0, name, .. } if name == "search"
+        ));
+        let arguments: String = events[1..]
+            .iter()
+            .map(|event| match event {
+                ToolCallEvent::Arguments {
+                    index: 0,
+                    arguments,
+                } => arguments.as_str(),
+                event => panic!("unexpected event {event:?}"),
+            })
+            .collect();
+        assert_eq!(arguments, "{\"q\": \"a}\\\"b\", \"n\": [1]}");
+    }
+
+    #[test]
+    fn leading_calls() {
+        let text = "{\"name\": \"weather\", \"parameters\": {\"city\": \"Paris\"}}";
+        let (content, calls) = parse_tool_calls(text, ToolSyntax::Llama3);
+        assert_eq!(content, None);
+        assert_eq!(calls[0].function.name, "weather");
+        assert_eq!(calls[0].function.arguments, "{\"city\": \"Paris\"}");
+        let (content, calls) = parse_tool_calls("It is {sunny}.", ToolSyntax::Llama3);
+        assert_eq!(content.as_deref(), Some("It is {sunny}."));
+        assert!(calls.is_empty());
+
+        let text = " [{\"name\": \"weather\", \"arguments\": {\"city\": \"Paris\"}}, {\"name\": \"time\", \"arguments\": {}}]";
+        let mut parser = ToolCallParser::new(ToolSyntax::Mistral);
+        let mut events = vec![];
+        let mut start = 0;
+        for (end, _) in text.char_indices().skip(1) {
+            events.extend(parser.push(&text[start..end]));
+            start = end;
+        }
+        events.extend(parser.push(&text[start..]));
+        events.extend(parser.flush());
+        let names: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                ToolCallEvent::Call { index, id, name } => {
+                    assert_eq!(id.len(), 9);
+                    Some((*index, name.as_str()))
+                }
+                ToolCallEvent::Arguments { .. } => None,
+                event => panic!("unexpected event {event:?}"),
+            })
+            .collect();
+        assert_eq!(names, [(0, "weather"), (1, "time")]);
+        let (content, calls) =
+            parse_tool_calls("[TOOL_CALLS][{\"name\": \"time\"}]", ToolSyntax::Mistral);
+        assert_eq!(content, None);
+        assert_eq!(calls[0].function.arguments, "{}");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+/// Raw bytes of every token in the vocabulary.
+///
+/// Decoding a single token with the tokenizer drops the leading space of SentencePiece tokens and
+/// turns partial UTF-8 sequences into replacement characters, so anything that reasons about
+/// the text of individual tokens works on these bytes instead.
+#[derive(Debug, Clone)]
+pub(crate) struct Vocabulary {
+    tokens: Vec<Vec<u8>>,
+    special: Vec<bool>,
+}
+
+impl Vocabulary {
+    pub(crate) fn new(tokenizer: &Tokenizer) -> Self {
+        let vocab = tokenizer.get_vocab(true);
+        let size = vocab.values().max().map_or(0, |id| *id as usize + 1);
+        let byte_level = tokenizer
+            .get_decoder()
+            .and_then(|decoder| serde_json::to_string(decoder).ok())
+            .is_some_and(|decoder| decoder.contains("ByteLevel"));
+        let byte_decoder: HashMap<char, u8> = bytes_to_unicode()
+            .into_iter()
+            .map(|(byte, char)| (char, byte))
+            .collect();
+        let mut tokens = vec![vec![]; size];
+        for (token, id) in vocab {
+            tokens[id as usize] = if byte_level {
+                token
+                    .chars()
+                    .map(|char| byte_decoder.get(&char).copied())
+                    .collect::<Option<Vec<u8>>>()
+                    .unwrap_or_else(|| token.into_bytes())
+            } else if let Some(byte) = parse_byte_token(&token) {
+                vec![byte]
+            } else {
+                token.replace('\u{2581}', " ").into_bytes()
+            };
+        }
+        let mut special = vec![false; size];
+        for (id, token) in tokenizer.get_added_tokens_decoder() {
+            if token.special {
+                if let Some(special) = special.get_mut(id as usize) {
+                    *special = true;
+                }
+            }
+        }
+        Self { tokens, special }
+    }
+
+    /// Bytes of `token`, empty for ids outside of the vocabulary.
+    pub(crate) fn bytes(&self, token: u32) -> &[u8] {
+        self.tokens.get(token as usize).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether `token` is a special token such as `<s>` or `<|im_end|>`.
+    pub(crate) fn is_special(&self, token: u32) -> bool {
+        self.special.get(token as usize).copied().unwrap_or(true)
+    }
+
+    /// Ids and bytes of all tokens that stand for text, leaving out special tokens.
+    pub(crate) fn text_tokens(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.tokens
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !self.special[*id])
+            .map(|(id, bytes)| (id as u32, bytes.as_slice()))
+    }
+}
+
+/// Parse SentencePiece byte fallback tokens such as `<0x0A>`.
+fn parse_byte_token(token: &str) -> Option<u8> {
+    let hex = token.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+/// The byte to printable character mapping of GPT-2 style byte level BPE tokenizers.
+fn bytes_to_unicode() -> Vec<(u8, char)> {
+    let mut printable: Vec<u8> = (b'!'..=b'~')
+        .chain(0xA1..=0xAC)
+        .chain(0xAE..=0xFF)
+        .collect();
+    let mut chars: Vec<u32> = printable.iter().map(|byte| *byte as u32).collect();
+    let mut n = 0;
+    for byte in 0..=255u8 {
+        if !printable.contains(&byte) {
+            printable.push(byte);
+            chars.push(256 + n);
+            n += 1;
+        }
+    }
+    printable
+        .into_iter()
+        .zip(chars)
+        .filter_map(|(byte, char)| char::from_u32(char).map(|char| (byte, char)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bytes_to_unicode, parse_byte_token};
+
+    #[test]
+    fn byte_tokens() {
+        assert_eq!(parse_byte_token("<0x0A>"), Some(b'\n'));
+        assert_eq!(parse_byte_token("<0xE4>"), Some(0xE4));
+        assert_eq!(parse_byte_token("<s>"), None);
+        let mapping = bytes_to_unicode();
+        assert_eq!(mapping.len(), 256);
+        assert!(mapping.contains(&(b' ', '\u{120}')));
+        assert!(mapping.contains(&(b'a', 'a')));
+    }
+}
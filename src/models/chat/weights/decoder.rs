@@ -0,0 +1,390 @@
+use candle_core::quantized::{gguf_file, QMatMul};
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{Embedding, LayerNorm, RmsNorm};
+use candle_transformers::utils::repeat_kv;
+use std::io::{Read, Seek};
+
+/// A pre-norm decoder with gated SiLU feed forward layers, the shape that llama, mistral and
+/// stablelm GGUF files share.
+///
+/// Norms with biases are layer norms, the others RMS norms. Attention biases and partial rotary
+/// embeddings are used when the file has them.
+///
+/// The keys and values of every sequence live in a [`KvCache`] outside of the weights, so a batch
+/// of sequences at different positions advances in a single forward pass.
+#[derive(Debug, Clone)]
+pub(crate) struct Decoder {
+    tok_embeddings: Embedding,
+    layers: Vec<Layer>,
+    norm: Norm,
+    output: QMatMul,
+    heads: Heads,
+}
+
+/// Shape of the attention heads, shared by all layers.
+#[derive(Debug, Clone)]
+struct Heads {
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    /// Rotary embeddings apply to the first `rope_dim` dimensions of each head.
+    rope_dim: usize,
+    /// Whether the rotary embeddings rotate adjacent pairs of dimensions like llama, rather than
+    /// the two halves of the rotated dimensions like NeoX.
+    interleaved: bool,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+#[derive(Debug, Clone)]
+enum Norm {
+    Rms(RmsNorm),
+    Layer(LayerNorm),
+}
+
+impl Module for Norm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Rms(norm) => norm.forward(xs),
+            Self::Layer(norm) => norm.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Projection {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl Module for Projection {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.weight.forward(xs)?;
+        match &self.bias {
+            Some(bias) => ys.broadcast_add(bias),
+            None => Ok(ys),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Layer {
+    attn_norm: Norm,
+    attn_q: Projection,
+    attn_k: Projection,
+    attn_v: Projection,
+    attn_output: QMatMul,
+    ffn_norm: Norm,
+    ffn_gate: QMatMul,
+    ffn_up: QMatMul,
+    ffn_down: QMatMul,
+}
+
+/// Keys and values of the tokens of one sequence, for every layer of a [`Decoder`].
+///
+/// The tensors are never written to in place, so clones share them until either one grows.
+#[derive(Debug, Clone)]
+pub(crate) struct KvCache {
+    layers: Vec<Option<(Tensor, Tensor)>>,
+    len: usize,
+}
+
+impl KvCache {
+    /// Number of tokens cached, the position of the next one.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Layer {
+    /// Attention of every row of `xs` over its sequence, whose keys and values for this layer
+    /// are at `index` in `caches`.
+    fn attention(
+        &self,
+        heads: &Heads,
+        xs: &Tensor,
+        index: usize,
+        caches: &mut [&mut KvCache],
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = xs.dims3()?;
+        let xs = self.attn_norm.forward(xs)?;
+        let split = |projection: &Projection, n_head: usize| {
+            projection
+                .forward(&xs)?
+                .reshape((b_sz, seq_len, n_head, heads.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let positions: Vec<usize> = caches.iter().map(|cache| cache.len).collect();
+        let q = heads.rotate(&split(&self.attn_q, heads.n_head)?, &positions)?;
+        let k = heads.rotate(&split(&self.attn_k, heads.n_kv_head)?, &positions)?;
+        let v = split(&self.attn_v, heads.n_kv_head)?;
+        // the projections are batched, attention runs per sequence over its own cache
+        let mut ys = Vec::with_capacity(b_sz);
+        for (row, cache) in caches.iter_mut().enumerate() {
+            let k = k.narrow(0, row, 1)?;
+            let v = v.narrow(0, row, 1)?;
+            let (k, v) = match &cache.layers[index] {
+                Some((k_cache, v_cache)) => (
+                    Tensor::cat(&[k_cache, &k], 2)?,
+                    Tensor::cat(&[v_cache, &v], 2)?,
+                ),
+                None => (k, v),
+            };
+            cache.layers[index] = Some((k.clone(), v.clone()));
+            let k = repeat_kv(k, heads.n_head / heads.n_kv_head)?;
+            let v = repeat_kv(v, heads.n_head / heads.n_kv_head)?.contiguous()?;
+
+            let q = q.narrow(0, row, 1)?;
+            let scores = (q.matmul(&k.t()?)? / (heads.head_dim as f64).sqrt())?;
+            let scores = match seq_len {
+                1 => scores,
+                _ => scores.broadcast_add(&causal_mask(seq_len, positions[row], xs.device())?)?,
+            };
+            let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+            ys.push(probs.matmul(&v)?);
+        }
+        let ys = Tensor::cat(&ys, 0)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, n_embd))?;
+        self.attn_output.forward(&ys)
+    }
+}
+
+impl Heads {
+    /// Apply the rotary embeddings to `xs`, whose row `i` starts at `positions[i]`.
+    ///
+    /// Several rows are only ever a single token each, they are rotated in one go by treating
+    /// the batch as the sequence.
+    fn rotate(&self, xs: &Tensor, positions: &[usize]) -> Result<Tensor> {
+        let (b_sz, _, seq_len, _) = xs.dims4()?;
+        if b_sz == 1 {
+            let cos = self.cos.narrow(0, positions[0], seq_len)?;
+            let sin = self.sin.narrow(0, positions[0], seq_len)?;
+            return self.rope(xs, &cos, &sin);
+        }
+        if seq_len != 1 {
+            candle_core::bail!("batched inputs must hold a single token per sequence")
+        }
+        let positions: Vec<u32> = positions.iter().map(|position| *position as u32).collect();
+        let positions = Tensor::new(positions.as_slice(), xs.device())?;
+        let cos = self.cos.index_select(&positions, 0)?;
+        let sin = self.sin.index_select(&positions, 0)?;
+        let xs = xs.transpose(0, 2)?.contiguous()?;
+        self.rope(&xs, &cos, &sin)?.transpose(0, 2)?.contiguous()
+    }
+
+    /// Apply the rotary embeddings to the first `rope_dim` dimensions of `xs`.
+    fn rope(&self, xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let rope = match self.interleaved {
+            true => candle_nn::rotary_emb::rope_i,
+            false => candle_nn::rotary_emb::rope,
+        };
+        if self.rope_dim == self.head_dim {
+            return rope(xs, cos, sin);
+        }
+        let rotated = xs.narrow(3, 0, self.rope_dim)?.contiguous()?;
+        let rotated = rope(&rotated, cos, sin)?;
+        let rest = xs.narrow(3, self.rope_dim, self.head_dim - self.rope_dim)?;
+        Tensor::cat(&[&rotated, &rest], 3)?.contiguous()
+    }
+}
+
+/// Reads the tensors of a GGUF file.
+struct Loader<'a, R> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+    eps: f64,
+}
+
+impl<R: Read + Seek> Loader<'_, R> {
+    fn has(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    fn tensor(&mut self, name: &str) -> Result<Tensor> {
+        self.content
+            .tensor(self.reader, name, self.device)?
+            .dequantize(self.device)
+    }
+
+    fn matmul(&mut self, name: &str) -> Result<QMatMul> {
+        QMatMul::from_qtensor(self.content.tensor(self.reader, name, self.device)?)
+    }
+
+    fn projection(&mut self, name: &str) -> Result<Projection> {
+        let bias = format!("{name}.bias");
+        Ok(Projection {
+            weight: self.matmul(&format!("{name}.weight"))?,
+            bias: match self.has(&bias) {
+                true => Some(self.tensor(&bias)?),
+                false => None,
+            },
+        })
+    }
+
+    fn norm(&mut self, name: &str) -> Result<Norm> {
+        let weight = self.tensor(&format!("{name}.weight"))?;
+        let bias = format!("{name}.bias");
+        Ok(match self.has(&bias) {
+            true => Norm::Layer(LayerNorm::new(weight, self.tensor(&bias)?, self.eps)),
+            false => Norm::Rms(RmsNorm::new(weight, self.eps)),
+        })
+    }
+}
+
+impl Decoder {
+    pub(crate) fn from_gguf<R: Read + Seek>(
+        architecture: &str,
+        content: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let metadata = |key: &str| {
+            let key = format!("{architecture}.{key}");
+            match content.metadata.get(&key) {
+                None => candle_core::bail!("cannot find {key} in metadata"),
+                Some(value) => Ok(value),
+            }
+        };
+        let n_head = metadata("attention.head_count")?.to_u32()? as usize;
+        let n_kv_head = metadata("attention.head_count_kv")?.to_u32()? as usize;
+        let embedding_length = metadata("embedding_length")?.to_u32()? as usize;
+        let block_count = metadata("block_count")?.to_u32()? as usize;
+        let context_length = metadata("context_length")?.to_u32()? as usize;
+        let head_dim = embedding_length / n_head;
+        let rope_dim = metadata("rope.dimension_count")
+            .and_then(|value| value.to_u32())
+            .map_or(head_dim, |dim| dim as usize);
+        let rope_freq_base = metadata("rope.freq_base")
+            .and_then(|value| value.to_f32())
+            .unwrap_or(10000.);
+        let eps = metadata("attention.layer_norm_rms_epsilon")
+            .or_else(|_| metadata("attention.layer_norm_epsilon"))?
+            .to_f32()? as f64;
+
+        let mut loader = Loader {
+            content: &content,
+            reader,
+            device,
+            eps,
+        };
+        let tok_embeddings = loader.tensor("token_embd.weight")?;
+        let norm = loader.norm("output_norm")?;
+        let output = match loader.has("output.weight") {
+            true => loader.matmul("output.weight")?,
+            // tied embeddings
+            false => loader.matmul("token_embd.weight")?,
+        };
+        let layers = (0..block_count)
+            .map(|index| {
+                let prefix = format!("blk.{index}");
+                Ok(Layer {
+                    attn_norm: loader.norm(&format!("{prefix}.attn_norm"))?,
+                    attn_q: loader.projection(&format!("{prefix}.attn_q"))?,
+                    attn_k: loader.projection(&format!("{prefix}.attn_k"))?,
+                    attn_v: loader.projection(&format!("{prefix}.attn_v"))?,
+                    attn_output: loader.matmul(&format!("{prefix}.attn_output.weight"))?,
+                    ffn_norm: loader.norm(&format!("{prefix}.ffn_norm"))?,
+                    ffn_gate: loader.matmul(&format!("{prefix}.ffn_gate.weight"))?,
+                    ffn_up: loader.matmul(&format!("{prefix}.ffn_up.weight"))?,
+                    ffn_down: loader.matmul(&format!("{prefix}.ffn_down.weight"))?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let theta: Vec<_> = (0..rope_dim)
+            .step_by(2)
+            .map(|i| 1. / rope_freq_base.powf(i as f32 / rope_dim as f32))
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), device)?;
+        let angles = Tensor::arange(0, context_length as u32, device)?
+            .to_dtype(DType::F32)?
+            .unsqueeze(1)?
+            .broadcast_mul(&theta.unsqueeze(0)?)?;
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output,
+            heads: Heads {
+                n_head,
+                n_kv_head,
+                head_dim,
+                rope_dim,
+                // llama.cpp permutes the query and key weights of llama models for adjacent pairs
+                interleaved: matches!(architecture, "llama" | "mistral"),
+                cos: angles.cos()?,
+                sin: angles.sin()?,
+            },
+        })
+    }
+
+    /// Longest sequence the rotary embeddings cover.
+    pub(crate) fn max_seq_len(&self) -> usize {
+        self.heads.cos.dims()[0]
+    }
+
+    /// An empty cache for a new sequence.
+    pub(crate) fn new_cache(&self) -> KvCache {
+        KvCache {
+            layers: vec![None; self.layers.len()],
+            len: 0,
+        }
+    }
+
+    /// Logits of the last token of every row of the `(batch, seq_len)` tokens `xs`, row `i`
+    /// continuing the sequence cached in `caches[i]`.
+    ///
+    /// A batch of more than one row takes a single token per sequence.
+    pub(crate) fn forward(&self, xs: &Tensor, caches: &mut [&mut KvCache]) -> Result<Tensor> {
+        let (b_sz, seq_len) = xs.dims2()?;
+        if b_sz != caches.len() {
+            candle_core::bail!("{b_sz} rows for {} caches", caches.len())
+        }
+        let mut hidden = self.tok_embeddings.forward(xs)?;
+        for (index, layer) in self.layers.iter().enumerate() {
+            let attn = layer.attention(&self.heads, &hidden, index, caches)?;
+            hidden = (attn + hidden)?;
+            let normed = layer.ffn_norm.forward(&hidden)?;
+            let gate = candle_nn::ops::silu(&layer.ffn_gate.forward(&normed)?)?;
+            let ffn = layer
+                .ffn_down
+                .forward(&(gate * layer.ffn_up.forward(&normed)?)?)?;
+            hidden = (ffn + hidden)?;
+        }
+        for cache in caches.iter_mut() {
+            cache.len += seq_len;
+        }
+        let hidden = self.norm.forward(&hidden.i((.., seq_len - 1, ..))?)?;
+        self.output.forward(&hidden)
+    }
+}
+
+/// Additive mask that keeps the `seq_len` new tokens from attending to the ones after them.
+fn causal_mask(seq_len: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<f32> = (0..seq_len)
+        .flat_map(|i| {
+            (0..index_pos + seq_len).map(move |j| match j > index_pos + i {
+                true => f32::NEG_INFINITY,
+                false => 0.,
+            })
+        })
+        .collect();
+    Tensor::from_vec(mask, (seq_len, index_pos + seq_len), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::causal_mask;
+    use candle_core::Device;
+
+    #[test]
+    fn mask_after_prefix() {
+        let mask = causal_mask(2, 1, &Device::Cpu).unwrap();
+        let mask: Vec<Vec<f32>> = mask.to_vec2().unwrap();
+        assert_eq!(mask[0], [0., 0., f32::NEG_INFINITY]);
+        assert_eq!(mask[1], [0., 0., 0.]);
+    }
+}
@@ -0,0 +1,256 @@
+mod decoder;
+mod quantize;
+mod safetensors;
+
+pub(crate) use quantize::{quantize_checkpoint, quantized_path, QuantizationType};
+pub(crate) use safetensors::{read_config, safetensors_files, HfConfig, Precision, Unquantized};
+
+use crate::models::chat::weights::decoder::{Decoder, KvCache};
+use anyhow::Result;
+use candle_core::quantized::{ggml_file, gguf_file};
+use candle_core::{Device, Tensor};
+use candle_transformers::models::{
+    quantized_gemma3, quantized_llama, quantized_phi, quantized_phi3, quantized_qwen2,
+};
+use std::io::{Read, Seek};
+
+/// What a sequence keeps between forward passes.
+#[derive(Debug, Clone)]
+pub(crate) enum SequenceState {
+    /// The keys and values of a sequence of the decoder.
+    Cache(KvCache),
+    /// A copy of an implementation that holds its own KV cache, at `position`.
+    Model { model: ChatWeights, position: usize },
+}
+
+impl SequenceState {
+    /// Number of tokens processed, the position of the next one.
+    pub(crate) fn position(&self) -> usize {
+        match self {
+            Self::Cache(cache) => cache.len(),
+            Self::Model { position, .. } => *position,
+        }
+    }
+}
+
+/// Architectures that use the llama implementation under their own metadata keys.
+const LLAMA_ALIASES: &[&str] = &["mixtral"];
+
+/// The architectures there is an implementation of.
+const ARCHITECTURES: &str = "llama, mistral, mixtral, phi2, phi3, gemma3, qwen2 and stablelm";
+
+/// Weights of a chat model, for the architectures there is an implementation of.
+///
+/// Except for the decoder, every variant holds its KV cache, so a clone snapshots the state of a
+/// sequence.
+#[derive(Debug, Clone)]
+pub(crate) enum ChatWeights {
+    /// llama, mistral and stablelm, which decode sequences in batches.
+    Decoder(Decoder),
+    /// Mixtral mixtures of experts and GGML files.
+    Llama(quantized_llama::ModelWeights),
+    Phi2(quantized_phi::ModelWeights),
+    Phi3(quantized_phi3::ModelWeights),
+    Gemma3(quantized_gemma3::ModelWeights),
+    Qwen2(quantized_qwen2::ModelWeights),
+    /// Weights of a Hugging Face checkpoint that are not quantized.
+    Unquantized(Box<Unquantized>),
+}
+
+impl ChatWeights {
+    /// Load the weights of the architecture named by `general.architecture`, llama if unset.
+    pub(crate) fn from_gguf<R: Read + Seek>(
+        mut content: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let architecture = architecture(&content)?;
+        // llama files may hold a mixture of experts, which the decoder does not implement
+        let experts = content
+            .metadata
+            .get("llama.expert_count")
+            .and_then(|value| value.to_u32().ok())
+            .unwrap_or(0);
+        Ok(match architecture.as_str() {
+            "llama" | "mistral" | "stablelm" if experts == 0 => {
+                Self::Decoder(Decoder::from_gguf(&architecture, content, reader, device)?)
+            }
+            "llama" => Self::Llama(quantized_llama::ModelWeights::from_gguf(
+                content, reader, device,
+            )?),
+            alias if LLAMA_ALIASES.contains(&alias) => {
+                alias_metadata(&mut content, alias, "llama");
+                Self::Llama(quantized_llama::ModelWeights::from_gguf(
+                    content, reader, device,
+                )?)
+            }
+            "phi2" => Self::Phi2(quantized_phi::ModelWeights::from_gguf(
+                content, reader, device,
+            )?),
+            "phi3" => Self::Phi3(quantized_phi3::ModelWeights::from_gguf(
+                false, content, reader, device,
+            )?),
+            "gemma3" => Self::Gemma3(quantized_gemma3::ModelWeights::from_gguf(
+                content, reader, device,
+            )?),
+            "qwen2" => Self::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
+                content, reader, device,
+            )?),
+            "gemma" | "gemma2" => anyhow::bail!(
+                "unsupported architecture {architecture}, of the gemma models only gemma3 is supported, supported are {ARCHITECTURES}"
+            ),
+            architecture => {
+                anyhow::bail!("unsupported architecture {architecture}, supported are {ARCHITECTURES}")
+            }
+        })
+    }
+
+    /// Load the weights of a GGML file, which only ever held llama models.
+    pub(crate) fn from_ggml(
+        content: ggml_file::Content,
+        gqa: usize,
+        device: &Device,
+    ) -> Result<Self> {
+        Ok(Self::Llama(quantized_llama::ModelWeights::from_ggml(
+            content, gqa, device,
+        )?))
+    }
+
+    /// The state of a new sequence.
+    pub(crate) fn new_sequence(&self) -> SequenceState {
+        match self {
+            Self::Decoder(decoder) => SequenceState::Cache(decoder.new_cache()),
+            model => SequenceState::Model {
+                model: model.clone(),
+                position: 0,
+            },
+        }
+    }
+
+    /// Feed `tokens` to the sequence and return the logits of the last one.
+    ///
+    /// Implementations whose attention masks only cover inputs at the start of a sequence are fed
+    /// the tokens after the first one one at a time, the others take them in a single forward
+    /// pass.
+    pub(crate) fn prefill(
+        &self,
+        state: &mut SequenceState,
+        tokens: &[u32],
+        device: &Device,
+    ) -> Result<Tensor> {
+        match (self, state) {
+            (Self::Decoder(decoder), SequenceState::Cache(cache)) => {
+                let input = Tensor::new(tokens, device)?.unsqueeze(0)?;
+                Ok(decoder.forward(&input, &mut [cache])?.squeeze(0)?)
+            }
+            (_, SequenceState::Model { model, position }) => {
+                let index_pos = *position;
+                *position += tokens.len();
+                if index_pos == 0 || model.prefills_after_prefix() {
+                    let input = Tensor::new(tokens, device)?.unsqueeze(0)?;
+                    return model.forward(&input, index_pos);
+                }
+                let mut logits = None;
+                for (offset, token) in tokens.iter().enumerate() {
+                    let input = Tensor::new(&[*token], device)?.unsqueeze(0)?;
+                    logits = Some(model.forward(&input, index_pos + offset)?);
+                }
+                logits.ok_or_else(|| anyhow::anyhow!("no tokens to process"))
+            }
+            _ => anyhow::bail!("sequence state of another implementation"),
+        }
+    }
+
+    /// Feed `tokens[i]` to sequence `i` and return the logits of each.
+    ///
+    /// The decoder takes all of them in one forward pass, the other implementations step every
+    /// sequence on its own.
+    pub(crate) fn decode(
+        &self,
+        states: &mut [&mut SequenceState],
+        tokens: &[u32],
+        device: &Device,
+    ) -> Result<Vec<Tensor>> {
+        let Self::Decoder(decoder) = self else {
+            return states
+                .iter_mut()
+                .zip(tokens)
+                .map(|(state, token)| self.prefill(state, &[*token], device))
+                .collect();
+        };
+        let mut caches = states
+            .iter_mut()
+            .map(|state| match &mut **state {
+                SequenceState::Cache(cache) => Ok(cache),
+                SequenceState::Model { .. } => {
+                    anyhow::bail!("sequence state of another implementation")
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let input = Tensor::new(tokens, device)?.unsqueeze(1)?;
+        let logits = decoder.forward(&input, &mut caches)?;
+        (0..tokens.len()).map(|row| Ok(logits.get(row)?)).collect()
+    }
+
+    /// Logits of the last of the `(1, seq_len)` tokens `input`, which start at `index_pos`, for
+    /// the implementations that hold their own KV cache.
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        Ok(match self {
+            Self::Llama(model) => model.forward(input, index_pos)?,
+            Self::Phi2(model) => model.forward(input, index_pos)?,
+            Self::Phi3(model) => model.forward(input, index_pos)?,
+            Self::Gemma3(model) => model.forward(input, index_pos)?,
+            Self::Qwen2(model) => model.forward(input, index_pos)?,
+            Self::Unquantized(model) => model.forward(input, index_pos)?,
+            Self::Decoder(_) => anyhow::bail!("the decoder keeps its KV cache in the sequence"),
+        }
+        .squeeze(0)?)
+    }
+
+    /// Whether inputs of several tokens may start past position zero, the other implementations
+    /// only build attention masks for inputs at the start of a sequence.
+    pub(crate) fn prefills_after_prefix(&self) -> bool {
+        match self {
+            Self::Llama(_) | Self::Phi2(_) | Self::Phi3(_) | Self::Gemma3(_) | Self::Qwen2(_) => {
+                false
+            }
+            Self::Decoder(_) => true,
+            Self::Unquantized(model) => model.prefills_after_prefix(),
+        }
+    }
+
+    /// Longest sequence the implementation supports, its rotary embeddings or masks end there.
+    pub(crate) fn max_seq_len(&self) -> usize {
+        match self {
+            Self::Llama(_) => quantized_llama::MAX_SEQ_LEN,
+            Self::Phi2(_) => quantized_phi::MAX_SEQ_LEN,
+            // built for the context length in the metadata
+            Self::Phi3(_) | Self::Qwen2(_) => usize::MAX,
+            Self::Gemma3(_) => quantized_gemma3::MAX_SEQ_LEN,
+            Self::Decoder(model) => model.max_seq_len(),
+            Self::Unquantized(model) => model.max_seq_len(),
+        }
+    }
+}
+
+/// The `general.architecture` of a GGUF file, llama for files from before it was written.
+pub(crate) fn architecture(content: &gguf_file::Content) -> Result<String> {
+    match content.metadata.get("general.architecture") {
+        Some(value) => Ok(value.to_string()?.clone()),
+        None => Ok("llama".to_string()),
+    }
+}
+
+/// Copy the metadata of architecture `from` to the keys `to` reads them from.
+fn alias_metadata(content: &mut gguf_file::Content, from: &str, to: &str) {
+    let prefix = format!("{from}.");
+    let aliased: Vec<_> = content
+        .metadata
+        .iter()
+        .filter_map(|(key, value)| {
+            let key = key.strip_prefix(&prefix)?;
+            Some((format!("{to}.{key}"), value.clone()))
+        })
+        .collect();
+    content.metadata.extend(aliased);
+}
@@ -0,0 +1,258 @@
+use super::safetensors::HfConfig;
+use anyhow::{Context, Result};
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Tensor};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Type that the linear weights of a safetensors model are quantized to on load.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum QuantizationType {
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    #[serde(rename = "q2_k")]
+    Q2K,
+    #[serde(rename = "q3_k")]
+    Q3K,
+    #[serde(rename = "q4_k")]
+    Q4K,
+    #[serde(rename = "q5_k")]
+    Q5K,
+    #[serde(rename = "q6_k")]
+    Q6K,
+}
+
+impl From<QuantizationType> for GgmlDType {
+    fn from(quantization: QuantizationType) -> Self {
+        match quantization {
+            QuantizationType::Q4_0 => GgmlDType::Q4_0,
+            QuantizationType::Q4_1 => GgmlDType::Q4_1,
+            QuantizationType::Q5_0 => GgmlDType::Q5_0,
+            QuantizationType::Q5_1 => GgmlDType::Q5_1,
+            QuantizationType::Q8_0 => GgmlDType::Q8_0,
+            QuantizationType::Q2K => GgmlDType::Q2K,
+            QuantizationType::Q3K => GgmlDType::Q3K,
+            QuantizationType::Q4K => GgmlDType::Q4K,
+            QuantizationType::Q5K => GgmlDType::Q5K,
+            QuantizationType::Q6K => GgmlDType::Q6K,
+        }
+    }
+}
+
+/// Names of Hugging Face tensors within a layer and the GGUF names they are written under.
+const LAYER_TENSORS: &[(&str, &str)] = &[
+    ("input_layernorm", "attn_norm"),
+    ("post_attention_layernorm", "ffn_norm"),
+    ("self_attn.q_proj", "attn_q"),
+    ("self_attn.k_proj", "attn_k"),
+    ("self_attn.v_proj", "attn_v"),
+    ("self_attn.o_proj", "attn_output"),
+    ("mlp.gate_proj", "ffn_gate"),
+    ("mlp.up_proj", "ffn_up"),
+    ("mlp.down_proj", "ffn_down"),
+];
+
+/// The file the quantized weights of a model directory are cached in.
+pub(crate) fn quantized_path(dir: &Path, quantization: QuantizationType) -> PathBuf {
+    let name = format!("{:?}", GgmlDType::from(quantization)).to_lowercase();
+    dir.join(format!("model.{name}.gguf"))
+}
+
+/// Write the weights of a llama, mistral or qwen2 checkpoint to a GGUF file at `path`, with the
+/// linear weights quantized, unless the file is newer than the checkpoint already.
+///
+/// `files` are config.json, whose hyperparameters go into the GGUF metadata, followed by the
+/// safetensors files. The GGUF file holds no tokenizer, the tokenizer.json of the directory is
+/// used with it.
+pub(crate) fn quantize_checkpoint(
+    config: &HfConfig,
+    files: &[PathBuf],
+    quantization: QuantizationType,
+    path: &Path,
+) -> Result<()> {
+    if is_newer(path, files)? {
+        return Ok(());
+    }
+    let (architecture, permute) = match config.architectures.first().map(String::as_str) {
+        // the llama implementation rotates interleaved pairs rather than halves
+        Some("LlamaForCausalLM" | "MistralForCausalLM") => ("llama", true),
+        Some("Qwen2ForCausalLM") => ("qwen2", false),
+        _ => anyhow::bail!(
+            "quantize supports llama, mistral and qwen2 checkpoints, not {:?}",
+            config.architectures
+        ),
+    };
+    let u32 = |value: usize| gguf_file::Value::U32(value as u32);
+    let metadata = [
+        (
+            "general.architecture",
+            gguf_file::Value::String(architecture.to_string()),
+        ),
+        ("context_length", u32(config.max_position_embeddings)),
+        ("embedding_length", u32(config.hidden_size)),
+        ("block_count", u32(config.num_hidden_layers)),
+        ("attention.head_count", u32(config.num_attention_heads)),
+        ("attention.head_count_kv", u32(config.num_key_value_heads())),
+        ("rope.dimension_count", u32(config.head_dim())),
+        (
+            "attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(config.rms_norm_eps.unwrap_or(1e-6) as f32),
+        ),
+        (
+            "rope.freq_base",
+            gguf_file::Value::F32(config.rope_theta.unwrap_or(10000.)),
+        ),
+    ];
+    let metadata: Vec<(String, gguf_file::Value)> = metadata
+        .into_iter()
+        .map(|(key, value)| match key.starts_with("general.") {
+            true => (key.to_string(), value),
+            false => (format!("{architecture}.{key}"), value),
+        })
+        .collect();
+
+    let safetensors = unsafe { MmapedSafetensors::multi(&files[1..])? };
+    let mut names: Vec<String> = safetensors
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    let mut tensors = vec![];
+    for name in names {
+        let Some(gguf_name) = gguf_name(&name) else {
+            continue;
+        };
+        let mut tensor = safetensors
+            .load(&name, &Device::Cpu)?
+            .to_dtype(DType::F32)?;
+        if permute && gguf_name.ends_with("attn_q.weight") {
+            tensor = permute_heads(&tensor, config.num_attention_heads)?;
+        } else if permute && gguf_name.ends_with("attn_k.weight") {
+            tensor = permute_heads(&tensor, config.num_key_value_heads())?;
+        }
+        tensors.push((gguf_name, quantize(&tensor, quantization.into())?));
+    }
+
+    // written under another name first, so that an interrupted run leaves no partial cache
+    let partial = path.with_extension("gguf.partial");
+    let written = write_gguf(&partial, &metadata, &tensors)
+        .and_then(|()| Ok(std::fs::rename(&partial, path)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written
+}
+
+fn write_gguf(
+    path: &Path,
+    metadata: &[(String, gguf_file::Value)],
+    tensors: &[(String, QTensor)],
+) -> Result<()> {
+    let mut file =
+        std::fs::File::create(path).with_context(|| format!("cannot create {}", path.display()))?;
+    gguf_file::write(
+        &mut file,
+        &metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .collect::<Vec<_>>(),
+        &tensors
+            .iter()
+            .map(|(name, tensor)| (name.as_str(), tensor))
+            .collect::<Vec<_>>(),
+    )?;
+    Ok(())
+}
+
+/// Whether `path` exists and was written after all of `files`.
+fn is_newer(path: &Path, files: &[PathBuf]) -> Result<bool> {
+    let Ok(modified) = std::fs::metadata(path).and_then(|metadata| metadata.modified()) else {
+        return Ok(false);
+    };
+    for file in files {
+        if std::fs::metadata(file)?.modified()? > modified {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The GGUF name of a Hugging Face tensor, `None` for tensors that are not needed.
+fn gguf_name(name: &str) -> Option<String> {
+    match name {
+        "model.embed_tokens.weight" => return Some("token_embd.weight".to_string()),
+        "model.norm.weight" => return Some("output_norm.weight".to_string()),
+        "lm_head.weight" => return Some("output.weight".to_string()),
+        _ => {}
+    }
+    let rest = name.strip_prefix("model.layers.")?;
+    let (index, rest) = rest.split_once('.')?;
+    let (tensor, kind) = rest.rsplit_once('.')?;
+    let (_, gguf) = LAYER_TENSORS.iter().find(|(hf, _)| *hf == tensor)?;
+    Some(format!("blk.{index}.{gguf}.{kind}"))
+}
+
+/// Reorder the rows of a query or key projection from rotating halves of each head to rotating
+/// interleaved pairs.
+fn permute_heads(weight: &Tensor, n_head: usize) -> candle_core::Result<Tensor> {
+    let (rows, cols) = weight.dims2()?;
+    weight
+        .reshape((n_head, 2, rows / n_head / 2, cols))?
+        .transpose(1, 2)?
+        .reshape((rows, cols))
+}
+
+/// Quantize matrices whose rows split into whole blocks, falling back to q8_0 for rows that do
+/// not split into k-quant blocks, and keep vectors such as norms in f32.
+fn quantize(tensor: &Tensor, dtype: GgmlDType) -> candle_core::Result<QTensor> {
+    let dtype = match tensor.dims() {
+        [_, cols] if cols % dtype.block_size() == 0 => dtype,
+        [_, cols] if cols % GgmlDType::Q8_0.block_size() == 0 => GgmlDType::Q8_0,
+        _ => GgmlDType::F32,
+    };
+    QTensor::quantize(tensor, dtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gguf_name, permute_heads};
+    use candle_core::{Device, Tensor};
+
+    #[test]
+    fn tensor_names() {
+        assert_eq!(
+            gguf_name("model.layers.3.self_attn.q_proj.bias").as_deref(),
+            Some("blk.3.attn_q.bias")
+        );
+        assert_eq!(
+            gguf_name("model.layers.0.mlp.down_proj.weight").as_deref(),
+            Some("blk.0.ffn_down.weight")
+        );
+        assert_eq!(
+            gguf_name("model.embed_tokens.weight").as_deref(),
+            Some("token_embd.weight")
+        );
+        assert_eq!(
+            gguf_name("model.layers.0.self_attn.rotary_emb.inv_freq"),
+            None
+        );
+    }
+
+    #[test]
+    fn interleave_halves() {
+        // one head of dimension 4 whose rows are the halves [0, 1] and [2, 3]
+        let weight = Tensor::arange(0f32, 4., &Device::Cpu)
+            .unwrap()
+            .reshape((4, 1))
+            .unwrap();
+        let permuted: Vec<Vec<f32>> = permute_heads(&weight, 1).unwrap().to_vec2().unwrap();
+        assert_eq!(permuted, [[0.], [2.], [1.], [3.]]);
+    }
+}
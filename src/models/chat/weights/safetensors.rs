@@ -0,0 +1,282 @@
+use anyhow::{Context, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{llama, mistral, phi, phi3, qwen2};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Type of the weights of a safetensors model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Precision {
+    F32,
+    Bf16,
+    F16,
+}
+
+impl From<Precision> for DType {
+    fn from(precision: Precision) -> Self {
+        match precision {
+            Precision::F32 => DType::F32,
+            Precision::Bf16 => DType::BF16,
+            Precision::F16 => DType::F16,
+        }
+    }
+}
+
+/// The fields of config.json that all architectures share.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct HfConfig {
+    #[serde(default)]
+    pub(crate) architectures: Vec<String>,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    pub(crate) hidden_size: usize,
+    head_dim: Option<usize>,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) rms_norm_eps: Option<f64>,
+    pub(crate) rope_theta: Option<f32>,
+    torch_dtype: Option<String>,
+    pub(crate) bos_token_id: Option<u32>,
+    #[serde(default)]
+    eos_token_id: Option<TokenIds>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum TokenIds {
+    Single(u32),
+    Multiple(Vec<u32>),
+}
+
+impl HfConfig {
+    /// The weight type to load: f32 on the CPU, else the type the model was saved in.
+    pub(crate) fn dtype(&self, device: &Device) -> DType {
+        match (device, self.torch_dtype.as_deref()) {
+            (Device::Cpu, _) => DType::F32,
+            (_, Some("bfloat16")) => DType::BF16,
+            (_, Some("float32")) => DType::F32,
+            _ => DType::F16,
+        }
+    }
+
+    /// The tokens that end a generation.
+    pub(crate) fn eos_token_ids(&self) -> Vec<u32> {
+        match &self.eos_token_id {
+            Some(TokenIds::Single(id)) => vec![*id],
+            Some(TokenIds::Multiple(ids)) => ids.clone(),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    pub(crate) fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    /// Bytes of the keys and values that attention caches per token.
+    pub(crate) fn kv_bytes_per_token(&self, dtype: DType) -> usize {
+        2 * self.num_hidden_layers
+            * self.num_key_value_heads()
+            * self.head_dim()
+            * dtype.size_in_bytes()
+    }
+}
+
+/// Read config.json of a model directory, returning it parsed and as text.
+pub(crate) fn read_config(dir: &Path) -> Result<(HfConfig, String)> {
+    let path = dir.join("config.json");
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| format!("cannot read {}", path.display()))?;
+    Ok((serde_json::from_str(&json)?, json))
+}
+
+/// The safetensors files of a model directory, the shards of model.safetensors.index.json or
+/// else model.safetensors.
+pub(crate) fn safetensors_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let index = dir.join("model.safetensors.index.json");
+    if !index.is_file() {
+        return Ok(vec![dir.join("model.safetensors")]);
+    }
+    let index: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&index)?)?;
+    let shards: BTreeSet<&str> = index["weight_map"]
+        .as_object()
+        .context("model.safetensors.index.json has no weight_map")?
+        .values()
+        .filter_map(|file| file.as_str())
+        .collect();
+    Ok(shards.into_iter().map(|file| dir.join(file)).collect())
+}
+
+/// phi-2, which does not implement `Debug`.
+#[derive(Clone)]
+struct Phi(phi::Model);
+
+impl std::fmt::Debug for Phi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Phi")
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Model {
+    Llama {
+        model: llama::Llama,
+        cache: llama::Cache,
+        /// Cleared cache to restart sequences from.
+        empty: llama::Cache,
+    },
+    Mistral(mistral::Model),
+    Qwen2(qwen2::ModelForCausalLM),
+    Phi(Phi),
+    Phi3(phi3::Model),
+}
+
+/// A chat model with the weights of a Hugging Face checkpoint, as saved by training.
+#[derive(Debug, Clone)]
+pub(crate) struct Unquantized {
+    model: Model,
+    max_seq_len: usize,
+}
+
+impl Unquantized {
+    /// Load the implementation of the first of the `architectures` of config.json that there is
+    /// one of, mapping the safetensors files into memory.
+    pub(crate) fn load(
+        config: &HfConfig,
+        json: &str,
+        files: &[PathBuf],
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(files, dtype, device)? };
+        let architecture = config
+            .architectures
+            .iter()
+            .find(|architecture| ARCHITECTURES.contains(&architecture.as_str()))
+            .with_context(|| {
+                format!(
+                    "unsupported architectures {:?}, supported are {}",
+                    config.architectures,
+                    ARCHITECTURES.join(", ")
+                )
+            })?;
+        let model = match architecture.as_str() {
+            "LlamaForCausalLM" => {
+                let config = serde_json::from_str::<llama::LlamaConfig>(json)?.into_config(false);
+                let empty = llama::Cache::new(true, dtype, &config, device)?;
+                Model::Llama {
+                    model: llama::Llama::load(vb, &config)?,
+                    cache: empty.clone(),
+                    empty,
+                }
+            }
+            "MistralForCausalLM" => {
+                Model::Mistral(mistral::Model::new(&serde_json::from_str(json)?, vb)?)
+            }
+            "Qwen2ForCausalLM" => Model::Qwen2(qwen2::ModelForCausalLM::new(
+                &serde_json::from_str(json)?,
+                vb,
+            )?),
+            "PhiForCausalLM" => Model::Phi(Phi(phi::Model::new(&serde_json::from_str(json)?, vb)?)),
+            _ => Model::Phi3(phi3::Model::new(&serde_json::from_str(json)?, vb)?),
+        };
+        Ok(Self {
+            model,
+            max_seq_len: config.max_position_embeddings,
+        })
+    }
+
+    pub(crate) fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Whether the attention masks of the implementation cover inputs past position zero.
+    pub(crate) fn prefills_after_prefix(&self) -> bool {
+        match &self.model {
+            Model::Llama { .. } | Model::Phi(_) => false,
+            Model::Mistral(_) | Model::Qwen2(_) | Model::Phi3(_) => true,
+        }
+    }
+
+    /// Logits of the last of the `(1, seq_len)` tokens `input`, which start at `index_pos`.
+    pub(crate) fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        // the implementations append to their caches whatever the position, so sequences that
+        // start over clear them
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        Ok(match &mut self.model {
+            Model::Llama { model, cache, .. } => model.forward(input, index_pos, cache)?,
+            Model::Mistral(model) => model.forward(input, index_pos)?.squeeze(1)?,
+            Model::Qwen2(model) => model.forward(input, index_pos)?.squeeze(1)?,
+            Model::Phi(Phi(model)) => model.forward(input)?,
+            Model::Phi3(model) => model.forward(input, index_pos)?.squeeze(1)?,
+        })
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match &mut self.model {
+            Model::Llama { cache, empty, .. } => *cache = empty.clone(),
+            Model::Mistral(model) => model.clear_kv_cache(),
+            Model::Qwen2(model) => model.clear_kv_cache(),
+            Model::Phi(Phi(model)) => model.clear_kv_cache(),
+            Model::Phi3(model) => model.clear_kv_cache(),
+        }
+    }
+}
+
+const ARCHITECTURES: &[&str] = &[
+    "LlamaForCausalLM",
+    "MistralForCausalLM",
+    "Qwen2ForCausalLM",
+    "PhiForCausalLM",
+    "Phi3ForCausalLM",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{safetensors_files, HfConfig};
+    use candle_core::{DType, Device};
+
+    #[test]
+    fn read_config_and_shards() {
+        let config: HfConfig = serde_json::from_str(
+            r#"{"architectures": ["Qwen2ForCausalLM"], "num_hidden_layers": 2,
+            "num_attention_heads": 4, "num_key_value_heads": 2, "hidden_size": 64,
+            "max_position_embeddings": 128, "torch_dtype": "bfloat16",
+            "eos_token_id": [1, 2]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.eos_token_ids(), [1, 2]);
+        assert_eq!(config.kv_bytes_per_token(DType::BF16), 2 * 2 * 2 * 16 * 2);
+        assert_eq!(config.dtype(&Device::Cpu), DType::F32);
+
+        let dir = std::env::temp_dir().join(format!("safetensors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(
+            safetensors_files(&dir).unwrap(),
+            [dir.join("model.safetensors")]
+        );
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            r#"{"weight_map": {"a": "model-00002.safetensors", "b": "model-00001.safetensors",
+            "c": "model-00001.safetensors"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            safetensors_files(&dir).unwrap(),
+            [
+                dir.join("model-00001.safetensors"),
+                dir.join("model-00002.safetensors")
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -29,3 +29,14 @@ pub fn device(cpu: bool) -> Result<Device> {
         Ok(Device::Cpu)
     }
 }
+
+/// Short name of the kind of `device`.
+pub fn device_name(device: &Device) -> &'static str {
+    if device.is_cuda() {
+        "cuda"
+    } else if device.is_metal() {
+        "metal"
+    } else {
+        "cpu"
+    }
+}
@@ -0,0 +1,387 @@
+mod nomic_bert;
+
+use crate::configs::EmbeddingModelConfig;
+use crate::models::device::{device, device_name};
+use crate::models::embedding::nomic_bert::NomicBertModel;
+use crate::types::embedding::{
+    Embedding as EmbeddingData, EmbeddingInput, EmbeddingRequest, EmbeddingResponse,
+    EmbeddingUsage, EmbeddingVector, EncodingFormat,
+};
+use anyhow::{Error as E, Result};
+use candle_core::{DType, Device, Module, Tensor, D};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{bert, jina_bert};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+use tokio::sync::oneshot;
+
+/// Largest number of inputs encoded in one forward pass.
+const MAX_BATCH_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EmbeddingArchitecture {
+    Bert,
+    JinaBert,
+    NomicBert,
+}
+
+impl EmbeddingArchitecture {
+    /// The architecture described by the config.json of a model.
+    fn detect(config: &Value) -> Self {
+        let architectures = config["architectures"].as_array();
+        let jina = architectures.is_some_and(|architectures| {
+            architectures
+                .iter()
+                .any(|name| name.as_str().is_some_and(|name| name.contains("JinaBert")))
+        });
+        match config["model_type"].as_str() {
+            Some("nomic_bert") => Self::NomicBert,
+            _ if jina || config["position_embedding_type"] == "alibi" => Self::JinaBert,
+            _ => Self::Bert,
+        }
+    }
+}
+
+/// How the states of the tokens of an input are combined into its embedding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Pooling {
+    /// The average over all tokens.
+    #[default]
+    Mean,
+    /// The state of the first token.
+    Cls,
+}
+
+enum Encoder {
+    Bert(bert::BertModel),
+    JinaBert(jina_bert::BertModel),
+    NomicBert(NomicBertModel),
+}
+
+impl Encoder {
+    /// Hidden states of the last layer for a batch of padded inputs.
+    fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> candle_core::Result<Tensor> {
+        let token_type_ids = input_ids.zeros_like()?;
+        match self {
+            Self::Bert(model) => model.forward(input_ids, &token_type_ids, Some(attention_mask)),
+            Self::JinaBert(model) => model.forward(input_ids),
+            Self::NomicBert(model) => model.forward(input_ids, &token_type_ids, attention_mask),
+        }
+    }
+
+    /// Number of inputs one forward pass can take, the JinaBERT implementation does not mask
+    /// padding.
+    fn batch_size(&self) -> usize {
+        match self {
+            Self::JinaBert(_) => 1,
+            _ => MAX_BATCH_SIZE,
+        }
+    }
+}
+
+struct Job {
+    inputs: Vec<Vec<u32>>,
+    dimensions: Option<usize>,
+    reply: oneshot::Sender<Result<Vec<Vec<f32>>>>,
+}
+
+/// Handle to the workers of an embedding model.
+///
+/// Inputs are tokenized by the handler and encoded on a pool of dedicated OS threads.
+#[derive(Clone, Debug)]
+pub(crate) struct Embedding {
+    tokenizer: Tokenizer,
+    /// Longest input the model can encode, in tokens.
+    pub(crate) max_input_tokens: usize,
+    hidden_size: usize,
+    pub(crate) device: &'static str,
+    sender: Sender<Job>,
+}
+
+impl Embedding {
+    pub(crate) async fn handle(&self, request: EmbeddingRequest) -> Result<EmbeddingResponse> {
+        let inputs = match request.input {
+            EmbeddingInput::Text(text) => vec![self.encode(text)?],
+            EmbeddingInput::Texts(texts) => texts
+                .into_iter()
+                .map(|text| self.encode(text))
+                .collect::<Result<_>>()?,
+            EmbeddingInput::Tokens(tokens) => vec![tokens],
+            EmbeddingInput::TokenLists(lists) => lists,
+        };
+        if inputs.is_empty() {
+            anyhow::bail!("input must not be empty");
+        }
+        for (index, tokens) in inputs.iter().enumerate() {
+            if tokens.is_empty() {
+                anyhow::bail!("input {index} must not be empty");
+            }
+            if tokens.len() > self.max_input_tokens {
+                anyhow::bail!(
+                    "input {index} has {} tokens, more than the {} this model supports",
+                    tokens.len(),
+                    self.max_input_tokens
+                );
+            }
+        }
+        if let Some(dimensions) = request.dimensions {
+            if dimensions == 0 || dimensions > self.hidden_size {
+                anyhow::bail!("dimensions must be between 1 and {}", self.hidden_size);
+            }
+        }
+        let prompt_tokens = inputs.iter().map(Vec::len).sum();
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .send(Job {
+                inputs,
+                dimensions: request.dimensions,
+                reply,
+            })
+            .map_err(|_| anyhow::anyhow!("embedding workers have stopped"))?;
+        let embeddings = receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("embedding worker dropped the request"))??;
+        let data = embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| {
+                let embedding = match request.encoding_format {
+                    EncodingFormat::Float => EmbeddingVector::Float(embedding),
+                    EncodingFormat::Base64 => EmbeddingVector::Base64(base64(&embedding)),
+                };
+                EmbeddingData::new(index, embedding)
+            })
+            .collect();
+        let usage = EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        };
+        Ok(EmbeddingResponse::new(request.model, data, usage))
+    }
+
+    fn encode(&self, text: String) -> Result<Vec<u32>> {
+        let tokens = self.tokenizer.encode(text, true).map_err(E::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    }
+
+    fn spawn(
+        name: &str,
+        embedder: Embedder,
+        tokenizer: Tokenizer,
+        max_input_tokens: usize,
+        hidden_size: usize,
+        workers: usize,
+    ) -> Result<Self> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let device = device_name(&embedder.device);
+        for index in 0..workers.max(1) {
+            let embedder = embedder.clone();
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("embedding-{name}-{index}"))
+                .spawn(move || embedder.run(receiver))?;
+        }
+        Ok(Self {
+            tokenizer,
+            max_input_tokens,
+            hidden_size,
+            device,
+            sender,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Embedder {
+    encoder: Arc<Encoder>,
+    pooling: Pooling,
+    normalize: bool,
+    device: Device,
+}
+
+impl Embedder {
+    fn run(self, receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            let Ok(Job {
+                inputs,
+                dimensions,
+                reply,
+            }) = job
+            else {
+                return;
+            };
+            let _ = reply.send(self.handle(inputs, dimensions));
+        }
+    }
+
+    fn handle(&self, inputs: Vec<Vec<u32>>, dimensions: Option<usize>) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(self.encoder.batch_size()) {
+            embeddings.extend(self.embed(batch)?);
+        }
+        for embedding in embeddings.iter_mut() {
+            if let Some(dimensions) = dimensions {
+                embedding.truncate(dimensions);
+            }
+            if self.normalize {
+                normalize(embedding);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    /// Pooled embeddings of a batch of inputs, padded to the longest one.
+    fn embed(&self, inputs: &[Vec<u32>]) -> Result<Vec<Vec<f32>>> {
+        let seq_len = inputs.iter().map(Vec::len).max().unwrap_or_default();
+        let mut input_ids = Vec::with_capacity(inputs.len() * seq_len);
+        let mut attention_mask = Vec::with_capacity(inputs.len() * seq_len);
+        for tokens in inputs {
+            input_ids.extend(tokens);
+            input_ids.resize(input_ids.len() + seq_len - tokens.len(), 0);
+            attention_mask.extend((0..seq_len).map(|i| u32::from(i < tokens.len())));
+        }
+        let shape = (inputs.len(), seq_len);
+        let input_ids = Tensor::from_vec(input_ids, shape, &self.device)?;
+        let attention_mask = Tensor::from_vec(attention_mask, shape, &self.device)?;
+        let hidden = self.encoder.forward(&input_ids, &attention_mask)?;
+        let pooled = match self.pooling {
+            Pooling::Cls => hidden.narrow(1, 0, 1)?.squeeze(1)?,
+            Pooling::Mean => {
+                let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(D::Minus1)?;
+                let sum = hidden.to_dtype(DType::F32)?.broadcast_mul(&mask)?.sum(1)?;
+                sum.broadcast_div(&mask.sum(1)?)?
+            }
+        };
+        Ok(pooled.to_dtype(DType::F32)?.to_vec2()?)
+    }
+}
+
+/// Scale `embedding` to unit length.
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0. {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Base64 of the little endian bytes of `embedding`, the way OpenAI encodes embeddings.
+fn base64(embedding: &[f32]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | ((*byte as u32) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+pub(crate) fn init_model(args: EmbeddingModelConfig) -> Result<Embedding> {
+    let device = device(args.cpu)?;
+    let model_id = args.model_id;
+    let (config_filename, tokenizer_filename, weights_filename) = {
+        let config = PathBuf::from(format!("{model_id}/config.json"));
+        let tokenizer = PathBuf::from(format!("{model_id}/tokenizer.json"));
+        let model = PathBuf::from(format!("{model_id}/model.safetensors"));
+        (config, tokenizer, model)
+    };
+
+    let config_json = std::fs::read_to_string(config_filename)?;
+    let config: Value = serde_json::from_str(&config_json)?;
+    let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+    tokenizer
+        .with_padding(None)
+        .with_truncation(None)
+        .map_err(E::msg)?;
+
+    let architecture = args
+        .architecture
+        .unwrap_or_else(|| EmbeddingArchitecture::detect(&config));
+    let vb =
+        unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DType::F32, &device)? };
+    let (encoder, max_input_tokens, hidden_size) = match architecture {
+        EmbeddingArchitecture::Bert => {
+            let config: bert::Config = serde_json::from_str(&config_json)?;
+            let model = bert::BertModel::load(vb, &config)?;
+            let encoder = Encoder::Bert(model);
+            (encoder, config.max_position_embeddings, config.hidden_size)
+        }
+        EmbeddingArchitecture::JinaBert => {
+            let config: jina_bert::Config = serde_json::from_str(&config_json)?;
+            let model = jina_bert::BertModel::new(vb, &config)?;
+            let encoder = Encoder::JinaBert(model);
+            (encoder, config.max_position_embeddings, config.hidden_size)
+        }
+        EmbeddingArchitecture::NomicBert => {
+            let config: nomic_bert::Config = serde_json::from_str(&config_json)?;
+            let model = NomicBertModel::load(vb, &config)?;
+            let encoder = Encoder::NomicBert(model);
+            (encoder, config.n_positions, config.n_embd)
+        }
+    };
+    let embedder = Embedder {
+        encoder: Arc::new(encoder),
+        pooling: args.pooling,
+        normalize: args.normalize,
+        device,
+    };
+    Embedding::spawn(
+        &args.alias,
+        embedder,
+        tokenizer,
+        max_input_tokens,
+        hidden_size,
+        args.workers,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, normalize, EmbeddingArchitecture};
+    use serde_json::json;
+
+    #[test]
+    fn encode_embeddings() {
+        assert_eq!(base64(&[1.0]), "AACAPw==");
+        assert_eq!(base64(&[1.0, -2.0]), "AACAPwAAAMA=");
+        assert_eq!(base64(&[1.0, -2.0, 0.5]), "AACAPwAAAMAAAAA/");
+        let mut embedding = vec![3.0, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, [0.6, 0.8]);
+    }
+
+    #[test]
+    fn detect_architecture() {
+        let detect = EmbeddingArchitecture::detect;
+        assert_eq!(
+            detect(&json!({"model_type": "bert"})),
+            EmbeddingArchitecture::Bert
+        );
+        assert_eq!(
+            detect(&json!({"model_type": "bert", "architectures": ["JinaBertForMaskedLM"]})),
+            EmbeddingArchitecture::JinaBert
+        );
+        assert_eq!(
+            detect(&json!({"model_type": "nomic_bert"})),
+            EmbeddingArchitecture::NomicBert
+        );
+    }
+}
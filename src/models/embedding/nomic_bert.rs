@@ -0,0 +1,213 @@
+//! The nomic-bert encoder of the nomic-embed-text models: a BERT with rotary position embeddings,
+//! SwiGLU feed forward layers and no biases in its attention.
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{
+    embedding, layer_norm, linear_b, rotary_emb, Embedding, LayerNorm, Linear, VarBuilder,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) n_embd: usize,
+    pub(crate) n_head: usize,
+    pub(crate) n_layer: usize,
+    pub(crate) n_inner: Option<usize>,
+    pub(crate) n_positions: usize,
+    #[serde(default = "default_type_vocab_size")]
+    pub(crate) type_vocab_size: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub(crate) layer_norm_epsilon: f64,
+    #[serde(default = "default_rotary_emb_base")]
+    pub(crate) rotary_emb_base: f32,
+    #[serde(default)]
+    pub(crate) qkv_proj_bias: bool,
+    #[serde(default)]
+    pub(crate) mlp_fc1_bias: bool,
+    #[serde(default)]
+    pub(crate) mlp_fc2_bias: bool,
+}
+
+fn default_type_vocab_size() -> usize {
+    2
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-12
+}
+
+fn default_rotary_emb_base() -> f32 {
+    1000.
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    inv_freq: Vec<f32>,
+}
+
+impl RotaryEmbedding {
+    fn new(head_dim: usize, base: f32) -> Self {
+        let inv_freq = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1. / base.powf(i as f32 / head_dim as f32))
+            .collect();
+        Self { inv_freq }
+    }
+
+    fn cos_sin(&self, seq_len: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+        let inv_freq = Tensor::new(self.inv_freq.as_slice(), device)?;
+        let positions = Tensor::arange(0u32, seq_len as u32, device)?.to_dtype(DType::F32)?;
+        let freqs = positions
+            .unsqueeze(1)?
+            .broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+        Ok((freqs.cos()?, freqs.sin()?))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    wqkv: Linear,
+    out_proj: Linear,
+    n_head: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let n_embd = config.n_embd;
+        Ok(Self {
+            wqkv: linear_b(n_embd, 3 * n_embd, config.qkv_proj_bias, vb.pp("Wqkv"))?,
+            out_proj: linear_b(n_embd, n_embd, config.qkv_proj_bias, vb.pp("out_proj"))?,
+            n_head: config.n_head,
+            head_dim: n_embd / config.n_head,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = xs.dims3()?;
+        let qkv = self
+            .wqkv
+            .forward(xs)?
+            .reshape((b_sz, seq_len, 3, self.n_head, self.head_dim))?
+            .permute((2, 0, 3, 1, 4))?;
+        let q = rotary_emb::rope(&qkv.get(0)?.contiguous()?, cos, sin)?;
+        let k = rotary_emb::rope(&qkv.get(1)?.contiguous()?, cos, sin)?;
+        let v = qkv.get(2)?.contiguous()?;
+        let scores = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let probs = candle_nn::ops::softmax_last_dim(&scores.broadcast_add(mask)?)?;
+        let output = probs
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, n_embd))?;
+        self.out_proj.forward(&output)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SwiGlu {
+    fc11: Linear,
+    fc12: Linear,
+    fc2: Linear,
+}
+
+impl SwiGlu {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let n_inner = config.n_inner.unwrap_or(4 * config.n_embd);
+        Ok(Self {
+            fc11: linear_b(config.n_embd, n_inner, config.mlp_fc1_bias, vb.pp("fc11"))?,
+            fc12: linear_b(config.n_embd, n_inner, config.mlp_fc1_bias, vb.pp("fc12"))?,
+            fc2: linear_b(n_inner, config.n_embd, config.mlp_fc2_bias, vb.pp("fc2"))?,
+        })
+    }
+}
+
+impl Module for SwiGlu {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = candle_nn::ops::silu(&self.fc12.forward(xs)?)?;
+        self.fc2.forward(&(self.fc11.forward(xs)? * gate)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    attn: Attention,
+    mlp: SwiGlu,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+}
+
+impl Block {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let eps = config.layer_norm_epsilon;
+        Ok(Self {
+            attn: Attention::load(vb.pp("attn"), config)?,
+            mlp: SwiGlu::load(vb.pp("mlp"), config)?,
+            norm1: layer_norm(config.n_embd, eps, vb.pp("norm1"))?,
+            norm2: layer_norm(config.n_embd, eps, vb.pp("norm2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let xs = self
+            .norm1
+            .forward(&(xs + self.attn.forward(xs, mask, cos, sin)?)?)?;
+        self.norm2.forward(&(&xs + self.mlp.forward(&xs)?)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct NomicBertModel {
+    word_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    emb_ln: LayerNorm,
+    layers: Vec<Block>,
+    rotary: RotaryEmbedding,
+}
+
+impl NomicBertModel {
+    pub(crate) fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let embeddings = vb.pp("embeddings");
+        let layers = (0..config.n_layer)
+            .map(|index| Block::load(vb.pp(format!("encoder.layers.{index}")), config))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            word_embeddings: embedding(
+                config.vocab_size,
+                config.n_embd,
+                embeddings.pp("word_embeddings"),
+            )?,
+            token_type_embeddings: embedding(
+                config.type_vocab_size,
+                config.n_embd,
+                embeddings.pp("token_type_embeddings"),
+            )?,
+            emb_ln: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("emb_ln"))?,
+            layers,
+            rotary: RotaryEmbedding::new(config.n_embd / config.n_head, config.rotary_emb_base),
+        })
+    }
+
+    /// Hidden states of the last layer for a batch of `(batch, seq_len)` token ids, where the
+    /// attention mask is 1 for tokens and 0 for padding.
+    pub(crate) fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let xs = (self.word_embeddings.forward(input_ids)?
+            + self.token_type_embeddings.forward(token_type_ids)?)?;
+        let mut xs = self.emb_ln.forward(&xs)?;
+        // padding gets a large negative bias, so that no token attends to it
+        let mask = ((attention_mask.to_dtype(DType::F32)? - 1.)? * 1e9)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let (cos, sin) = self.rotary.cos_sin(seq_len, input_ids.device())?;
+        for layer in &self.layers {
+            xs = layer.forward(&xs, &mask, &cos, &sin)?;
+        }
+        Ok(xs)
+    }
+}
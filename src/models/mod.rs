@@ -1,15 +1,19 @@
 use crate::configs::Config;
 use crate::models::audio::whisper::Whisper;
 use crate::models::chat::ChatModel;
+use crate::models::embedding::Embedding;
+use crate::types::model::{ModelKind, ModelObject};
+use chrono::Local;
 use std::collections::HashMap;
 
 pub(crate) mod audio;
 pub(crate) mod chat;
 mod device;
+pub(crate) mod embedding;
 
 #[derive(Debug, Clone)]
 pub struct Models {
-    model_map: HashMap<String, Model>,
+    model_map: HashMap<String, LoadedModel>,
 }
 
 impl Models {
@@ -30,7 +34,7 @@ impl Models {
                 chat_config.alias,
                 start.elapsed().as_secs()
             );
-            model_map.insert(alias, Model::Chat(model));
+            model_map.insert(alias, LoadedModel::new(Model::Chat(Box::new(model)), start));
         }
         for (index, whisper_config) in config
             .whisper_configs
@@ -51,26 +55,125 @@ impl Models {
                 whisper_config.alias,
                 start.elapsed().as_secs()
             );
-            model_map.insert(alias, Model::Whisper(model));
+            model_map.insert(alias, LoadedModel::new(Model::Whisper(model), start));
+        }
+        for (index, embedding_config) in config
+            .embedding_configs
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+        {
+            let start = std::time::Instant::now();
+            println!(
+                "{}: init embedding model: {}",
+                index + 1,
+                embedding_config.alias
+            );
+            let alias = embedding_config.alias.clone();
+            let model = embedding::init_model(embedding_config.clone())?;
+            println!(
+                "init embedding model: {} finished in {:2}s",
+                embedding_config.alias,
+                start.elapsed().as_secs()
+            );
+            model_map.insert(
+                alias,
+                LoadedModel::new(Model::Embedding(Box::new(model)), start),
+            );
         }
         Ok(Self { model_map })
     }
+    /// Descriptions of all models, ordered by id.
+    pub(crate) fn list(&self) -> Vec<ModelObject> {
+        let mut models: Vec<_> = self
+            .model_map
+            .iter()
+            .map(|(id, model)| model.describe(id))
+            .collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        models
+    }
+    pub(crate) fn get(&self, alias: &str) -> Option<ModelObject> {
+        self.model_map.get(alias).map(|model| model.describe(alias))
+    }
     pub(crate) fn get_whisper(&self, alias: String) -> Option<&Whisper> {
-        match self.model_map.get(&alias) {
+        match self.model(&alias) {
             Some(Model::Whisper(model)) => Some(model),
             _ => None,
         }
     }
     pub(crate) fn get_chat(&self, alias: String) -> Option<&ChatModel> {
-        match self.model_map.get(&alias) {
+        match self.model(&alias) {
             Some(Model::Chat(model)) => Some(model),
             _ => None,
         }
     }
+    pub(crate) fn get_embedding(&self, alias: String) -> Option<&Embedding> {
+        match self.model(&alias) {
+            Some(Model::Embedding(model)) => Some(model),
+            _ => None,
+        }
+    }
+    fn model(&self, alias: &str) -> Option<&Model> {
+        self.model_map.get(alias).map(|loaded| &loaded.model)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Model {
     Whisper(Whisper),
-    Chat(ChatModel),
+    Chat(Box<ChatModel>),
+    Embedding(Box<Embedding>),
+}
+
+#[derive(Debug, Clone)]
+struct LoadedModel {
+    model: Model,
+    /// Unix timestamp of when loading finished.
+    created: usize,
+    /// Seconds it took to load the model.
+    load_time: f32,
+}
+
+impl LoadedModel {
+    fn new(model: Model, start: std::time::Instant) -> Self {
+        Self {
+            model,
+            created: Local::now().timestamp() as usize,
+            load_time: start.elapsed().as_secs_f32(),
+        }
+    }
+
+    fn describe(&self, id: &str) -> ModelObject {
+        let mut object = ModelObject {
+            id: id.to_string(),
+            object: "model".to_string(),
+            created: self.created,
+            owned_by: "llm_server".to_string(),
+            kind: ModelKind::Chat,
+            chat_format: None,
+            context_length: None,
+            quantization: None,
+            device: String::new(),
+            load_time: self.load_time,
+        };
+        match &self.model {
+            Model::Chat(model) => {
+                object.chat_format = Some(model.chat_format.clone());
+                object.context_length = Some(model.context_length);
+                object.quantization = Some(model.quantization.clone());
+                object.device = model.device.to_string();
+            }
+            Model::Whisper(model) => {
+                object.kind = ModelKind::Whisper;
+                object.device = model.device.to_string();
+            }
+            Model::Embedding(model) => {
+                object.kind = ModelKind::Embedding;
+                object.context_length = Some(model.max_input_tokens);
+                object.device = model.device.to_string();
+            }
+        }
+        object
+    }
 }
@@ -47,9 +47,9 @@ pub struct AssistantMessage {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ToolMessage {
     /// The contents of the tool message.
-    content: String,
+    pub(crate) content: String,
     /// Tool call that this message is responding to.
-    tool_call_id: String,
+    pub(crate) tool_call_id: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -79,43 +79,104 @@ pub enum ToolType {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ChatResponseFormatObject {
     pub(crate) r#type: ChatResponseFormat,
+    /// The schema the output must follow, required if the type is json_schema.
+    #[serde(default)]
+    pub(crate) json_schema: Option<JsonSchemaFormat>,
+}
+
+/// The schema of a json_schema response format.
+///
+/// Other fields such as strict are accepted and ignored, the output always follows the schema.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JsonSchemaFormat {
+    /// The name of the response format.
+    pub(crate) name: String,
+    /// The schema for the response format, described as a JSON Schema object.
+    #[serde(default)]
+    pub(crate) schema: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ChatResponseFormat {
     Text,
+    /// Constrains the model to generate a valid JSON object.
     #[default]
-    Json,
+    #[serde(alias = "json")]
+    JsonObject,
+    /// Constrains the model to generate JSON that validates against the given schema.
+    JsonSchema,
 }
 #[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    /// A single stop sequence.
+    Single(String),
+    /// Up to 4 stop sequences.
+    Multiple(Vec<String>),
+}
+
+impl Stop {
+    pub(crate) fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::Single(stop) => vec![stop],
+            Self::Multiple(stops) => stops,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Tool {
+    /// The type of the tool. Currently, only functions are supported.
+    pub(crate) r#type: ToolType,
     /// The schema of the tool. Currently, only functions are supported.
-    r#type: ToolType,
-    /// The schema of the tool. Currently, only functions are supported.
-    function: FunctionInfo,
+    pub(crate) function: FunctionInfo,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FunctionInfo {
     /// A description of what the function does, used by the model to choose when and how to call the function.
-    description: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) description: Option<String>,
     /// The name of the function to be called. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a maximum length of 64.
-    name: String,
-    /// The parameters the functions accepts, described as a JSON Schema object.
-    parameters: serde_json::Value,
+    pub(crate) name: String,
+    /// The parameters the functions accepts, described as a JSON Schema object. Omitting parameters defines a function with an empty parameter list.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) parameters: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
-#[serde(rename_all = "snake_case")]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
 pub enum ToolChoice {
-    #[default]
+    /// One of none, auto or required.
+    Mode(ToolChoiceMode),
+    /// Forces the model to call the named function.
+    Named(NamedToolChoice),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoiceMode {
+    /// The model will not call any tool and generates a message instead.
     None,
+    /// The model can pick between generating a message or calling one or more tools.
     Auto,
-    // TODO: we need something like this: #[serde(tag = "type", content = "function")]
-    Function {
-        name: String,
-    },
+    /// The model must call one or more tools.
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct NamedToolChoice {
+    /// The type of the tool. Currently, only function is supported.
+    pub(crate) r#type: ToolType,
+    /// The function the model must call.
+    pub(crate) function: ToolChoiceFunction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ToolChoiceFunction {
+    /// The name of the function to call.
+    pub(crate) name: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -126,6 +187,92 @@ pub struct ChatCompletionChoice {
     pub index: usize,
     /// A chat completion message generated by the model.
     pub message: AssistantMessage,
+    /// Log probability information for the choice.
+    pub logprobs: Option<ChatCompletionLogprobs>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChatCompletionChunkChoice {
+    /// The index of the choice in the list of choices.
+    pub index: usize,
+    /// A chat completion delta generated by streamed model responses.
+    pub delta: ChatCompletionDelta,
+    /// Log probability information for the choice.
+    pub logprobs: Option<ChatCompletionLogprobs>,
+    /// The reason the model stopped generating tokens, null until the last chunk of the choice.
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChatCompletionDelta {
+    /// The role of the author of this message, only sent with the first chunk of a choice.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub role: Option<String>,
+    /// The contents of the chunk message.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub content: Option<String>,
+    /// The parts of tool calls generated in this chunk.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ToolCallDelta {
+    /// The index of the tool call among the tool calls of the message.
+    pub index: usize,
+    /// The ID of the tool call, only sent with its first chunk.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<String>,
+    /// The type of the tool, only sent with the first chunk of the call.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub r#type: Option<ToolType>,
+    /// The part of the function call generated in this chunk.
+    pub function: FunctionCallDelta,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FunctionCallDelta {
+    /// The name of the function to call, only sent with the first chunk of the call.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
+    /// The next part of the arguments to call the function with.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamOptions {
+    /// If set, an additional chunk with the token usage of the entire request is streamed before the data: [DONE] message.
+    #[serde(default)]
+    pub(crate) include_usage: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChatCompletionLogprobs {
+    /// A list of message content tokens with log probability information.
+    pub content: Vec<ChatCompletionTokenLogprob>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChatCompletionTokenLogprob {
+    /// The token.
+    pub token: String,
+    /// The log probability of this token.
+    pub logprob: f32,
+    /// The UTF-8 bytes of the token, useful when characters are split across several tokens.
+    pub bytes: Vec<u8>,
+    /// The most likely tokens at this position, up to top_logprobs of them.
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TopLogprob {
+    /// The token.
+    pub token: String,
+    /// The log probability of this token.
+    pub logprob: f32,
+    /// The UTF-8 bytes of the token.
+    pub bytes: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -136,6 +283,15 @@ pub struct ChatCompleteUsage {
     pub prompt_tokens: usize,
     /// Total number of tokens used in the request (prompt + completion).
     pub total_tokens: usize,
+    /// Breakdown of tokens used in the prompt.
+    #[serde(default)]
+    pub prompt_tokens_details: PromptTokensDetails,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PromptTokensDetails {
+    /// Number of prompt tokens served from the prefix cache.
+    pub cached_tokens: usize,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
@@ -146,5 +302,4 @@ pub enum FinishReason {
     Length,
     ContentFilter,
     ToolCalls,
-    Null,
 }
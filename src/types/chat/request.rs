@@ -1,8 +1,10 @@
 use crate::types::chat::completion::{
-    ChatCompletionMessage, ChatResponseFormatObject, Tool, ToolChoice,
+    ChatCompletionMessage, ChatResponseFormatObject, Stop, StreamOptions, Tool, ToolChoice,
+    ToolChoiceMode,
 };
 use derive_builder::Builder;
 use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Deserialize, Builder)]
 pub struct ChatCompletionRequest {
@@ -14,12 +16,27 @@ pub struct ChatCompletionRequest {
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing frequency in the text so far, decreasing the model's likelihood to repeat the same line verbatim.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    frequency_penalty: Option<f32>,
-
-    // Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100. Mathematically, the bias is added to the logits generated by the model prior to sampling. The exact effect will vary per model, but values between -1 and 1 should decrease or increase likelihood of selection; values like -100 or 100 should result in a ban or exclusive selection of the relevant token.
-    // #[builder(default, setter(strip_option))]
-    // #[serde(skip_serializing_if = "Option::is_none")]
-    // logit_bias: Option<f32>,
+    pub(crate) frequency_penalty: Option<f32>,
+    /// Restricts the output to exactly one of the given strings. Not part of the OpenAI API.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) guided_choice: Option<Vec<String>>,
+    /// Restricts the output to a grammar in the GBNF syntax of llama.cpp, starting at its root rule. Not part of the OpenAI API.
+    #[builder(default, setter(strip_option, into))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) guided_grammar: Option<String>,
+    /// Restricts the output to text that matches the regular expression as a whole. Not part of the OpenAI API.
+    #[builder(default, setter(strip_option, into))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) guided_regex: Option<String>,
+    /// Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100. Mathematically, the bias is added to the logits generated by the model prior to sampling. The exact effect will vary per model, but values between -1 and 1 should decrease or increase likelihood of selection; values like -100 or 100 should result in a ban or exclusive selection of the relevant token.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logit_bias: Option<HashMap<String, f32>>,
+    /// Whether to return log probabilities of the output tokens. If true, returns the log probabilities of each output token returned in the content of message.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<bool>,
     /// The maximum number of tokens to generate in the chat completion.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -27,11 +44,19 @@ pub struct ChatCompletionRequest {
     /// How many chat completion choices to generate for each input message. Note that you will be charged based on the number of generated tokens across all the choices. Keep n as 1 to minimize costs.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    n: Option<usize>,
+    pub(crate) n: Option<usize>,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far, increasing the model's likelihood to talk about new topics.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    presence_penalty: Option<f32>,
+    pub(crate) presence_penalty: Option<f32>,
+    /// Penalty for repeating any of the last repeat_last_n tokens, 1.0 disables it. Defaults to the value configured for the model.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) repeat_penalty: Option<f32>,
+    /// Number of most recent tokens the repeat_penalty looks at. Defaults to the value configured for the model.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) repeat_last_n: Option<usize>,
     /// An object specifying the format that the model must output. Setting to { "type": "json_object" } enables JSON mode, which guarantees the message the model generates is valid JSON.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -39,16 +64,19 @@ pub struct ChatCompletionRequest {
     /// This feature is in Beta. If specified, our system will make the best effort to sample deterministically, such that repeated requests with the same seed and parameters should return the same result. Determinism is not guaranteed, and you should refer to the system_fingerprint response parameter to monitor changes in the backend.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    seed: Option<usize>,
+    pub(crate) seed: Option<u64>,
     /// Up to 4 sequences where the API will stop generating further tokens.
-    // TODO: make this as an enum
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    stop: Option<String>,
+    pub(crate) stop: Option<Stop>,
     /// If set, partial message deltas will be sent, like in ChatGPT. Tokens will be sent as data-only server-sent events as they become available, with the stream terminated by a data: [DONE] message.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) stream: Option<bool>,
+    /// Options for streaming response. Only set this when you set stream: true.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stream_options: Option<StreamOptions>,
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the output more random, while lower values like 0.2 will make it more focused and deterministic. We generally recommend altering this or top_p but not both.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -57,16 +85,30 @@ pub struct ChatCompletionRequest {
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) top_p: Option<f32>,
+    /// An integer between 0 and 20 specifying the number of most likely tokens to return at each token position, each with an associated log probability. logprobs must be set to true if this parameter is used.
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_logprobs: Option<usize>,
     /// A list of tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of functions the model may generate JSON inputs for.
     #[builder(default, setter(into))]
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    tools: Option<Vec<Tool>>,
+    pub(crate) tools: Option<Vec<Tool>>,
     /// Controls which (if any) function is called by the model. none means the model will not call a function and instead generates a message. auto means the model can pick between generating a message or calling a function. Specifying a particular function via {"type": "function", "function": {"name": "my_function"}} forces the model to call that function. none is the default when no functions are present. auto is the default if functions are present.
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    tool_choice: Option<ToolChoice>,
+    pub(crate) tool_choice: Option<ToolChoice>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect abuse.
     #[builder(default, setter(strip_option, into))]
     #[serde(skip_serializing_if = "Option::is_none")]
     user: Option<String>,
 }
+
+impl ChatCompletionRequest {
+    /// The tools the model may call, none if tool_choice is none.
+    pub(crate) fn tools(&self) -> &[Tool] {
+        match self.tool_choice {
+            Some(ToolChoice::Mode(ToolChoiceMode::None)) => &[],
+            _ => self.tools.as_deref().unwrap_or_default(),
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use crate::types::chat::completion::{ChatCompleteUsage, ChatCompletionChoice};
+use crate::types::chat::completion::{
+    ChatCompleteUsage, ChatCompletionChoice, ChatCompletionChunkChoice,
+};
 use chrono::Local;
 use serde::{Deserialize, Serialize};
 
@@ -21,18 +23,19 @@ pub struct ChatCompletionResponse {
 }
 
 impl ChatCompletionResponse {
-    pub(crate) fn new(model: String) -> Self {
+    pub(crate) fn new(model: String, system_fingerprint: String) -> Self {
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             choices: Vec::new(),
             created: Local::now().timestamp() as usize,
             model,
-            system_fingerprint: String::new(),
+            system_fingerprint,
             object: "chat.completion".to_string(),
             usage: ChatCompleteUsage {
                 completion_tokens: 0,
                 prompt_tokens: 0,
                 total_tokens: 0,
+                prompt_tokens_details: Default::default(),
             },
         }
     }
@@ -42,22 +45,25 @@ impl ChatCompletionResponse {
 pub struct ChatCompletionResponseChunk {
     /// A unique identifier for the chat completion.
     pub id: String,
-    /// A list of chat completion choices. Can be more than one if n is greater than 1.
-    pub choices: Vec<ChatCompletionChoice>,
+    /// A list of chat completion choices. Can be more than one if n is greater than 1. Empty for the last chunk if stream_options.include_usage is set.
+    pub choices: Vec<ChatCompletionChunkChoice>,
     /// The Unix timestamp (in seconds) of when the chat completion was created.
     pub created: usize,
     /// The model used for the chat completion.
     pub model: String,
     /// This fingerprint represents the backend configuration that the model runs with. Can be used in conjunction with the seed request parameter to understand when backend changes have been made that might impact determinism.
     pub system_fingerprint: String,
-    /// The object type, which is always chat.completion.
+    /// The object type, which is always chat.completion.chunk.
     pub object: String,
+    /// Usage statistics for the entire request, only sent with the last chunk if stream_options.include_usage is set.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub usage: Option<ChatCompleteUsage>,
 }
 
 impl ChatCompletionResponseChunk {
     pub(crate) fn from_response(
         response: &ChatCompletionResponse,
-        choices: Vec<ChatCompletionChoice>,
+        choices: Vec<ChatCompletionChunkChoice>,
     ) -> Self {
         let ChatCompletionResponse {
             id,
@@ -73,6 +79,7 @@ impl ChatCompletionResponseChunk {
             model,
             system_fingerprint,
             object: "chat.completion.chunk".to_string(),
+            usage: None,
         }
     }
 }
@@ -0,0 +1,53 @@
+use crate::types::chat::completion::{Stop, StreamOptions};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionRequest {
+    /// ID of the model to use.
+    pub(crate) model: String,
+    /// The prompt(s) to generate completions for, encoded as a string, array of strings, array of tokens, or array of token arrays.
+    pub(crate) prompt: Prompt,
+    /// Echo back the prompt in addition to the completion.
+    #[serde(default)]
+    pub(crate) echo: bool,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing frequency in the text so far, decreasing the model's likelihood to repeat the same line verbatim.
+    pub(crate) frequency_penalty: Option<f32>,
+    /// Modify the likelihood of specified tokens appearing in the completion. Maps token IDs to a bias value from -100 to 100.
+    pub(crate) logit_bias: Option<HashMap<String, f32>>,
+    /// Include the log probabilities on the logprobs most likely output tokens, as well the chosen tokens. The maximum value for logprobs is 5.
+    pub(crate) logprobs: Option<usize>,
+    /// The maximum number of tokens that can be generated in the completion. Defaults to 16.
+    pub(crate) max_tokens: Option<usize>,
+    /// How many completions to generate for each prompt.
+    pub(crate) n: Option<usize>,
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far, increasing the model's likelihood to talk about new topics.
+    pub(crate) presence_penalty: Option<f32>,
+    /// Penalty for repeating any of the last repeat_last_n tokens, 1.0 disables it. Defaults to the value configured for the model.
+    pub(crate) repeat_penalty: Option<f32>,
+    /// Number of most recent tokens the repeat_penalty looks at. Defaults to the value configured for the model.
+    pub(crate) repeat_last_n: Option<usize>,
+    /// If specified, the same seed and parameters should return the same result.
+    pub(crate) seed: Option<u64>,
+    /// Up to 4 sequences where the API will stop generating further tokens. The returned text will not contain the stop sequence.
+    pub(crate) stop: Option<Stop>,
+    /// Whether to stream back partial progress as data-only server-sent events, terminated by a data: [DONE] message.
+    pub(crate) stream: Option<bool>,
+    /// Options for streaming response. Only set this when you set stream: true.
+    pub(crate) stream_options: Option<StreamOptions>,
+    /// The suffix that comes after a completion of inserted text. Only supported by models with fill-in-the-middle tokens.
+    pub(crate) suffix: Option<String>,
+    /// What sampling temperature to use, between 0 and 2.
+    pub(crate) temperature: Option<f32>,
+    /// An alternative to sampling with temperature, called nucleus sampling.
+    pub(crate) top_p: Option<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    Text(String),
+    Texts(Vec<String>),
+    Tokens(Vec<u32>),
+    TokenLists(Vec<Vec<u32>>),
+}
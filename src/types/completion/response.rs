@@ -0,0 +1,80 @@
+use crate::types::chat::completion::{ChatCompleteUsage, FinishReason};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompletionResponse {
+    /// A unique identifier for the completion.
+    pub id: String,
+    /// The list of completion choices the model generated for the input prompt.
+    pub choices: Vec<CompletionChoice>,
+    /// The Unix timestamp (in seconds) of when the completion was created.
+    pub created: usize,
+    /// The model used for completion.
+    pub model: String,
+    /// This fingerprint represents the backend configuration that the model runs with.
+    pub system_fingerprint: String,
+    /// The object type, which is always text_completion.
+    pub object: String,
+    /// Usage statistics for the completion request. Streamed chunks only carry it in the last chunk, if stream_options.include_usage is set.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub usage: Option<ChatCompleteUsage>,
+}
+
+impl CompletionResponse {
+    pub(crate) fn new(model: String, system_fingerprint: String) -> Self {
+        Self {
+            id: format!("cmpl-{}", uuid::Uuid::new_v4().simple()),
+            choices: Vec::new(),
+            created: Local::now().timestamp() as usize,
+            model,
+            system_fingerprint,
+            object: "text_completion".to_string(),
+            usage: None,
+        }
+    }
+
+    /// A streamed chunk of this completion with the given choices.
+    pub(crate) fn chunk(&self, choices: Vec<CompletionChoice>) -> Self {
+        Self {
+            choices,
+            usage: None,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompletionChoice {
+    /// The generated text, or the part of it in this chunk.
+    pub text: String,
+    /// The index of the choice, prompt by prompt and n choices per prompt.
+    pub index: usize,
+    /// Log probability information for the tokens of the text.
+    pub logprobs: Option<CompletionLogprobs>,
+    /// The reason the model stopped generating tokens, null until the last chunk of a streamed choice.
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CompletionLogprobs {
+    /// The tokens of the text.
+    pub tokens: Vec<String>,
+    /// The log probability of each token, null for the first token of an echoed prompt.
+    pub token_logprobs: Vec<Option<f32>>,
+    /// The most likely tokens at each position with their log probabilities, up to logprobs of them. Null for the first token of an echoed prompt.
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+    /// The character offset of each token in the text of the choice, including the echoed prompt.
+    pub text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    /// Append the log probabilities of the tokens that follow.
+    pub(crate) fn extend(&mut self, other: CompletionLogprobs) {
+        self.tokens.extend(other.tokens);
+        self.token_logprobs.extend(other.token_logprobs);
+        self.top_logprobs.extend(other.top_logprobs);
+        self.text_offset.extend(other.text_offset);
+    }
+}
@@ -0,0 +1,5 @@
+pub(crate) mod request;
+pub(crate) mod response;
+
+pub(crate) use request::{EmbeddingInput, EmbeddingRequest, EncodingFormat};
+pub(crate) use response::{Embedding, EmbeddingResponse, EmbeddingUsage, EmbeddingVector};
@@ -0,0 +1,34 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmbeddingRequest {
+    /// Input text to embed, encoded as a string or array of tokens. To embed multiple inputs in a single request, pass an array of strings or array of token arrays.
+    pub(crate) input: EmbeddingInput,
+    /// ID of the model to use.
+    pub(crate) model: String,
+    /// The format to return the embeddings in. Can be either float or base64.
+    #[serde(default)]
+    pub(crate) encoding_format: EncodingFormat,
+    /// The number of dimensions the resulting output embeddings should have. The embedding is truncated to its first dimensions, which only keeps its meaning for models trained for it.
+    pub(crate) dimensions: Option<usize>,
+    /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect abuse.
+    user: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Text(String),
+    Texts(Vec<String>),
+    Tokens(Vec<u32>),
+    TokenLists(Vec<Vec<u32>>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    /// Little endian f32 values, base64 encoded.
+    Base64,
+}
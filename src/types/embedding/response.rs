@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EmbeddingResponse {
+    /// The object type, which is always list.
+    pub object: String,
+    /// The embeddings of the inputs, in the order of the inputs.
+    pub data: Vec<Embedding>,
+    /// The model used for the embeddings.
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+impl EmbeddingResponse {
+    pub(crate) fn new(model: String, data: Vec<Embedding>, usage: EmbeddingUsage) -> Self {
+        Self {
+            object: "list".to_string(),
+            data,
+            model,
+            usage,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Embedding {
+    /// The object type, which is always embedding.
+    pub object: String,
+    /// The embedding vector, a list of floats or a base64 string depending on encoding_format.
+    pub embedding: EmbeddingVector,
+    /// The index of the embedding in the list of embeddings.
+    pub index: usize,
+}
+
+impl Embedding {
+    pub(crate) fn new(index: usize, embedding: EmbeddingVector) -> Self {
+        Self {
+            object: "embedding".to_string(),
+            embedding,
+            index,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct EmbeddingUsage {
+    /// The number of tokens used by the inputs.
+    pub prompt_tokens: usize,
+    /// The total number of tokens used by the request.
+    pub total_tokens: usize,
+}
@@ -1,2 +1,5 @@
 pub(crate) mod audio;
 pub(crate) mod chat;
+pub(crate) mod completion;
+pub(crate) mod embedding;
+pub(crate) mod model;
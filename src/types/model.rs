@@ -0,0 +1,53 @@
+use crate::models::chat::chat_format::ChatFormat;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelKind {
+    Chat,
+    Whisper,
+    Embedding,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelObject {
+    /// The model identifier, which can be referenced in the API endpoints.
+    pub id: String,
+    /// The object type, which is always model.
+    pub object: String,
+    /// The Unix timestamp (in seconds) when the model was loaded.
+    pub created: usize,
+    /// The organization that owns the model.
+    pub owned_by: String,
+    /// Which endpoints serve the model. Not part of the OpenAI API, like the fields below.
+    pub kind: ModelKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) chat_format: Option<ChatFormat>,
+    /// Maximum number of tokens of the prompt and the completion together, or of an embedding input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<usize>,
+    /// Type that holds most of the weights of a chat model, named like llama.cpp names them:
+    /// q4_0, q4_k and the other quantized types, or f16, bf16 and f32 for unquantized weights.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<String>,
+    /// Device the model runs on, cpu, cuda or metal.
+    pub device: String,
+    /// Seconds it took to load the model.
+    pub load_time: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelList {
+    /// The object type, which is always list.
+    pub object: String,
+    pub data: Vec<ModelObject>,
+}
+
+impl ModelList {
+    pub(crate) fn new(data: Vec<ModelObject>) -> Self {
+        Self {
+            object: "list".to_string(),
+            data,
+        }
+    }
+}